@@ -2,6 +2,8 @@
 // FIXED: Replaced Flipper with Comptroller & Added necessary traits
 
 use odra::host::{HostEnv, NoArgs};
+use odra::prelude::Address;
+use odra::schema::casper_contract_schema::NamedCLType;
 // FIX: Import Addressable agar fungsi .address() bisa dipakai
 use odra::prelude::Addressable; 
 
@@ -15,6 +17,10 @@ use odra_cli::{
 
 // FIX: Import Kontrak Comptroller Anda, BUKAN Flipper
 use my_project::contracts::comptroller::Comptroller;
+use my_project::processor::NovaLending;
+
+/// Fractional digits shown for values; `Display` would print all 18
+const DISPLAY_PLACES: usize = 6;
 
 /// Script untuk deploy Comptroller
 pub struct ComptrollerDeployScript;
@@ -73,6 +79,42 @@ impl ScenarioMetadata for CheckAdminScenario {
     const DESCRIPTION: &'static str = "Checks the admin of the comptroller contract";
 }
 
+/// Scenario: reserve values in the quote currency
+pub struct ReserveValuesScenario;
+
+impl Scenario for ReserveValuesScenario {
+    fn args(&self) -> Vec<CommandArg> {
+        vec![CommandArg::new("reserve", "Reserve address", NamedCLType::Key)]
+    }
+
+    fn run(
+        &self,
+        env: &HostEnv,
+        container: &DeployedContractsContainer,
+        args: Args
+    ) -> Result<(), Error> {
+        let contract = container.contract_ref::<NovaLending>(env)?;
+        let reserve = args.get_single::<Address>("reserve")?;
+
+        println!("🔍 Running Scenario: Reserve Values...");
+        if let Some(value) = contract.get_reserve_available_value(reserve) {
+            println!("💧 Available value: {}", value.format_with(DISPLAY_PLACES));
+        }
+        if let Some(rate) = contract.get_exchange_rate(reserve) {
+            println!("🔁 Exchange rate: {}", rate.format_with(DISPLAY_PLACES));
+        }
+        let bad_debt = contract.get_reserve_bad_debt(reserve);
+        println!("⚠️ Bad debt: {}", bad_debt.format_with(DISPLAY_PLACES));
+
+        Ok(())
+    }
+}
+
+impl ScenarioMetadata for ReserveValuesScenario {
+    const NAME: &'static str = "reserve-values";
+    const DESCRIPTION: &'static str = "Prints a reserve's available value, exchange rate and bad debt";
+}
+
 /// Main function
 pub fn main() {
     OdraCli::new()
@@ -81,8 +123,10 @@ pub fn main() {
         .deploy(ComptrollerDeployScript) 
         // Daftarkan Kontrak Comptroller
         .contract::<Comptroller>() 
+        .contract::<NovaLending>()
         // Daftarkan Skenario Test
         .scenario(CheckAdminScenario) 
+        .scenario(ReserveValuesScenario)
         .build()
         .run();
 }
\ No newline at end of file
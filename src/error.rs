@@ -74,6 +74,28 @@ pub enum LendingError {
     ExceededSlippage = 45,
     //MathOverflow = 46,
     InsufficientCollateral = 47,
+    LoanNotMatured = 48,
+    TimelockNotElapsed = 49,
+
+    // 50
+    LiquidationGracePeriod = 50,
+    BorrowCooldown = 51,
+    BorrowingDisabled = 52,
+    ObligationNotEmpty = 53,
+    ReserveLimitReached = 54,
+    MarketPaused = 55,
+    ReserveInvariantViolated = 56,
+    MathOverflowAdd = 57,
+    MathOverflowSub = 58,
+    MathOverflowMul = 59,
+
+    // 60
+    MathOverflowDiv = 60,
+    MathOverflowPow = 61,
+    FlashLoanDisabled = 62,
+    SelfLiquidation = 63,
+    CollateralValueZero = 64,
+    OracleConcentrationLimit = 65,
 }
 
 impl LendingError {
@@ -100,7 +122,7 @@ impl LendingError {
             LendingError::TokenMintToFailed => "Token mint to failed",
             LendingError::TokenBurnFailed => "Token burn failed",
             LendingError::InsufficientLiquidity => "Insufficient liquidity available",
-            LendingError::ReserveCollateralDisabled => "Input reserve has collateral disabled",
+            LendingError::ReserveCollateralDisabled => "Input reserve has collateral disabled, or all deposited collateral has a loan-to-value of zero",
             LendingError::ReserveStale => "Reserve state needs to be refreshed",
             LendingError::WithdrawTooSmall => "Withdraw amount too small",
             LendingError::WithdrawTooLarge => "Withdraw amount too large",
@@ -114,7 +136,7 @@ impl LendingError {
             LendingError::InvalidObligationOwner => "Obligation owner is invalid",
             LendingError::ObligationDepositsEmpty => "Obligation deposits are empty",
             LendingError::ObligationBorrowsEmpty => "Obligation borrows are empty",
-            LendingError::ObligationDepositsZero => "Obligation deposits have zero value",
+            LendingError::ObligationDepositsZero => "Obligation has no deposits",
             LendingError::ObligationBorrowsZero => "Obligation borrows have zero value",
             LendingError::InvalidObligationCollateral => "Invalid obligation collateral",
             LendingError::InvalidObligationLiquidity => "Invalid obligation liquidity",
@@ -126,9 +148,45 @@ impl LendingError {
             LendingError::NotEnoughLiquidityAfterFlashLoan => "Not enough liquidity after flash loan",
             LendingError::ExceededSlippage  => "Amount smaller than desired slippage limit",
             LendingError::InsufficientCollateral => "kolekteral abis",
+            LendingError::LoanNotMatured => "Loan has no maturity or has not matured yet",
+            LendingError::TimelockNotElapsed => "Config change must wait out the timelock",
+            LendingError::LiquidationGracePeriod => "Obligation is still within the liquidation grace period",
+            LendingError::BorrowCooldown => "Cannot borrow this soon after depositing collateral",
+            LendingError::BorrowingDisabled => "Borrowing is disabled for this reserve",
+            LendingError::ObligationNotEmpty => "Obligation still has deposits or borrows",
+            LendingError::ReserveLimitReached => "Lending market has reached its reserve limit",
+            LendingError::MarketPaused => "Lending market is paused",
+            LendingError::ReserveInvariantViolated => "Reserve accounting invariant violated",
+            LendingError::MathOverflowAdd => "Math overflow in addition",
+            LendingError::MathOverflowSub => "Math underflow in subtraction",
+            LendingError::MathOverflowMul => "Math overflow in multiplication",
+            LendingError::MathOverflowDiv => "Math overflow or division by zero",
+            LendingError::MathOverflowPow => "Math overflow in exponentiation",
+            LendingError::FlashLoanDisabled => "Flash loans are disabled for this reserve",
+            LendingError::SelfLiquidation => "Borrowers cannot liquidate their own obligation",
+            LendingError::CollateralValueZero => "Obligation collateral is priced at zero, check the oracle",
+            LendingError::OracleConcentrationLimit => "Borrow would back too much of the market with one oracle",
             //LendingError::MathOverflow =>"mate",
         }
     }
+
+    /// Which arithmetic operation overflowed, if this is a contextual overflow
+    pub fn math_context(&self) -> Option<crate::math::common::MathContext> {
+        use crate::math::common::MathContext;
+        match self {
+            LendingError::MathOverflowAdd => Some(MathContext::Add),
+            LendingError::MathOverflowSub => Some(MathContext::Sub),
+            LendingError::MathOverflowMul => Some(MathContext::Mul),
+            LendingError::MathOverflowDiv => Some(MathContext::Div),
+            LendingError::MathOverflowPow => Some(MathContext::Pow),
+            _ => None,
+        }
+    }
+
+    /// True for both the plain and contextual overflow variants
+    pub fn is_math_overflow(&self) -> bool {
+        *self == LendingError::MathOverflow || self.math_context().is_some()
+    }
 }
 
 impl core::fmt::Display for LendingError {
@@ -14,6 +14,29 @@ pub const HALF_WAD: u64 = 500_000_000_000_000_000;
 /// Scale for percentages
 pub const PERCENT_SCALER: u64 = 10_000_000_000_000_000;
 
+/// Arithmetic operation that overflowed, recovered from the error variant
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MathContext {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+impl MathContext {
+    /// Overflow error carrying this context
+    pub fn overflow(self) -> LendingError {
+        match self {
+            MathContext::Add => LendingError::MathOverflowAdd,
+            MathContext::Sub => LendingError::MathOverflowSub,
+            MathContext::Mul => LendingError::MathOverflowMul,
+            MathContext::Div => LendingError::MathOverflowDiv,
+            MathContext::Pow => LendingError::MathOverflowPow,
+        }
+    }
+}
+
 /// Try to subtract, return an error on underflow
 pub trait TrySub: Sized {
     /// Subtract
@@ -44,3 +67,78 @@ pub trait TryMul<RHS>: Sized {
 //   the Odra/Casper error handling used across migrated modules.
 // - Keep the numeric constants (`WAD`, `PERCENT_SCALER`) as `u64` to remain
 //   compatible with other migrated files which cast to `u128` when needed.
+
+/// `a * b / c` rounded down, exact even when `a * b` overflows `U256`.
+/// Fails if `c` is zero or the quotient itself doesn't fit.
+pub fn mul_div(a: U256, b: U256, c: U256) -> Result<U256, LendingError> {
+    if c.is_zero() {
+        return Err(MathContext::Div.overflow());
+    }
+    if let Some(product) = a.checked_mul(b) {
+        return Ok(product / c);
+    }
+
+    let (hi, lo) = full_mul(a, b);
+    if hi >= c {
+        return Err(MathContext::Mul.overflow());
+    }
+
+    // Shift-subtract long division of the 512-bit product; the remainder
+    // stays below `c`, so a bit carried out of the shift means it's >= c
+    let mut remainder = hi;
+    let mut quotient = U256::zero();
+    for bit in (0..256).rev() {
+        let carry = remainder.bit(255);
+        remainder = remainder << 1;
+        if lo.bit(bit) {
+            remainder = remainder | U256::one();
+        }
+        if carry || remainder >= c {
+            remainder = remainder.overflowing_sub(c).0;
+            quotient = quotient | (U256::one() << bit);
+        }
+    }
+    Ok(quotient)
+}
+
+/// 512-bit product of `a` and `b` as (high, low) words
+fn full_mul(a: U256, b: U256) -> (U256, U256) {
+    let mask = (U256::one() << 128) - U256::one();
+    let (a_lo, a_hi) = (a & mask, a >> 128);
+    let (b_lo, b_hi) = (b & mask, b >> 128);
+
+    let (mid, mid_carry) = (a_lo * b_hi).overflowing_add(a_hi * b_lo);
+    let (lo, lo_carry) = (a_lo * b_lo).overflowing_add(mid << 128);
+
+    let mut hi = a_hi * b_hi + (mid >> 128);
+    if lo_carry {
+        hi = hi + U256::one();
+    }
+    if mid_carry {
+        hi = hi + (U256::one() << 128);
+    }
+    (hi, lo)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mul_div_without_overflow() {
+        assert_eq!(mul_div(U256::from(6u64), U256::from(7u64), U256::from(4u64)), Ok(U256::from(10u64)));
+        assert_eq!(mul_div(U256::one(), U256::one(), U256::zero()), Err(LendingError::MathOverflowDiv));
+    }
+
+    #[test]
+    fn test_mul_div_with_overflowing_product() {
+        let a = U256::max_value() / 3;
+        let b = U256::exp10(30);
+        assert!(a.checked_mul(b).is_none());
+
+        assert_eq!(mul_div(a, b, U256::exp10(30)), Ok(a));
+        assert_eq!(mul_div(a, b, U256::exp10(31)), Ok(a / 10));
+        assert_eq!(mul_div(U256::max_value(), U256::max_value(), U256::max_value()), Ok(U256::max_value()));
+        assert_eq!(mul_div(a, b, U256::one()), Err(LendingError::MathOverflowMul));
+    }
+}
@@ -6,11 +6,12 @@
 #![allow(missing_docs)]
 
 use {
-    crate::{error::LendingError, math::{common::*}},
+    crate::{error::LendingError, math::{common::*, TryDiv, TryMul}},
     odra::casper_types::U256,
     core::fmt,
     alloc::{
-        string::ToString,
+        format,
+        string::{String, ToString},
         vec,
     }
 };
@@ -67,6 +68,33 @@ impl Decimal {
         Self(U256::from(percent as u64 * PERCENT_SCALER)) 
     }
 
+    /// Given percentage (0-100) of this value
+    pub fn percent_of(&self, percent: u8) -> Result<Self, LendingError> {
+        self.try_mul(Self::from_percent(percent))
+    }
+
+    /// `numerator / denominator` without overflowing on the scaled
+    /// intermediate product
+    pub fn from_fraction(numerator: U256, denominator: U256) -> Result<Self, LendingError> {
+        Ok(Self(mul_div(numerator, Self::wad(), denominator)?))
+    }
+
+    /// Multiply, rounding half up instead of truncating toward zero
+    pub fn try_mul_round(self, rhs: Self) -> Result<Self, LendingError> {
+        Ok(Self(
+            self.0
+                .checked_mul(rhs.0)
+                .and_then(|product| product.checked_add(Self::half_wad()))
+                .ok_or(MathContext::Mul.overflow())?
+                / Self::wad()
+        ))
+    }
+
+    /// Given basis points (1/100th of a percent) of this value
+    pub fn bps_of(&self, bps: u64) -> Result<Self, LendingError> {
+        self.try_mul(bps)?.try_div(10_000u64)
+    }
+
     /// Return raw scaled value as u128 (assumes value fits into u128)
     #[allow(clippy::wrong_self_convention)]
     pub fn to_scaled_val(&self) -> u128 { 
@@ -78,13 +106,51 @@ impl Decimal {
         Self(U256::from(scaled_val)) 
     }
 
+    /// Underlying scaled value, without the `u128` narrowing of `to_scaled_val`
+    pub fn raw(&self) -> U256 {
+        self.0
+    }
+
+    /// Convert to a rate without narrowing the scaled value through `u128`
+    pub fn to_rate(&self) -> crate::math::Rate {
+        crate::math::Rate(self.0)
+    }
+
+    /// Compare against a rate; both are WAD-scaled so the raw values line up
+    pub fn cmp_rate(&self, rate: &crate::math::Rate) -> core::cmp::Ordering {
+        self.0.cmp(&rate.0)
+    }
+
+    /// Format rounded (half up) to `places` fractional digits, without
+    /// trailing zeros. `Display` keeps the full 18 digits.
+    pub fn format_with(&self, places: usize) -> String {
+        let places = places.min(SCALE);
+        let unit = U256::exp10(SCALE - places);
+        let rounded = self.0.saturating_add(unit / 2) / unit;
+
+        let mut digits = rounded.to_string();
+        if places == 0 {
+            return digits;
+        }
+        if digits.len() <= places {
+            digits.insert_str(0, &"0".repeat(places + 1 - digits.len()));
+        }
+        let (integer, fraction) = digits.split_at(digits.len() - places);
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.is_empty() {
+            integer.to_string()
+        } else {
+            format!("{}.{}", integer, fraction)
+        }
+    }
+
     /// Round scaled decimal to u64
     pub fn try_round_u64(&self) -> Result<u64, LendingError> {
         let rounded_val = Self::half_wad()
             .checked_add(self.0)
-            .ok_or(LendingError::MathOverflow)?
+            .ok_or(MathContext::Add.overflow())?
             .checked_div(Self::wad())
-            .ok_or(LendingError::MathOverflow)?;
+            .ok_or(MathContext::Div.overflow())?;
         
         if rounded_val > U256::from(u64::MAX) {
             return Err(LendingError::MathOverflow);
@@ -94,13 +160,7 @@ impl Decimal {
 
     /// Ceiling scaled decimal to u64
     pub fn try_ceil_u64(&self) -> Result<u64, LendingError> {
-        let ceil_val = Self::wad()
-            .checked_sub(U256::from(1u64))
-            .ok_or(LendingError::MathOverflow)?
-            .checked_add(self.0)
-            .ok_or(LendingError::MathOverflow)?
-            .checked_div(Self::wad())
-            .ok_or(LendingError::MathOverflow)?;
+        let ceil_val = self.try_ceil_u256()?;
         
         if ceil_val > U256::from(u64::MAX) {
             return Err(LendingError::MathOverflow);
@@ -108,9 +168,42 @@ impl Decimal {
         Ok(ceil_val.as_u64())
     }
 
+    /// Create decimal from an integer, erroring where `From<u64>` would
+    /// silently produce zero
+    pub fn try_from_u64(val: u64) -> Result<Self, LendingError> {
+        Self::try_from_u256(U256::from(val))
+    }
+
+    /// Create decimal from an integer, erroring where `From<u128>` would
+    /// silently produce zero
+    pub fn try_from_u128(val: u128) -> Result<Self, LendingError> {
+        Self::try_from_u256(U256::from(val))
+    }
+
+    /// Create decimal from a full-width integer amount
+    pub fn try_from_u256(val: U256) -> Result<Self, LendingError> {
+        Ok(Self(Self::wad().checked_mul(val).ok_or(MathContext::Mul.overflow())?))
+    }
+
+    /// Floor scaled decimal to U256, without the u64 cap
+    pub fn try_floor_u256(&self) -> Result<U256, LendingError> {
+        self.0.checked_div(Self::wad()).ok_or(MathContext::Div.overflow())
+    }
+
+    /// Ceiling scaled decimal to U256, without the u64 cap
+    pub fn try_ceil_u256(&self) -> Result<U256, LendingError> {
+        Self::wad()
+            .checked_sub(U256::from(1u64))
+            .ok_or(MathContext::Sub.overflow())?
+            .checked_add(self.0)
+            .ok_or(MathContext::Add.overflow())?
+            .checked_div(Self::wad())
+            .ok_or(MathContext::Div.overflow())
+    }
+
     /// Floor scaled decimal to u64
     pub fn try_floor_u64(&self) -> Result<u64, LendingError> {
-        let floor_val = self.0.checked_div(Self::wad()).ok_or(LendingError::MathOverflow)?;
+        let floor_val = self.0.checked_div(Self::wad()).ok_or(MathContext::Div.overflow())?;
         
         if floor_val > U256::from(u64::MAX) {
             return Err(LendingError::MathOverflow);
@@ -132,6 +225,8 @@ impl fmt::Display for Decimal {
     }
 }
 
+// The `From` conversions below map an overflowing value to zero; use the
+// `try_from_*` constructors wherever the input is user-controlled.
 impl From<u64> for Decimal { 
     fn from(val: u64) -> Self { 
         Self(Self::wad().checked_mul(U256::from(val)).unwrap_or(U256::zero())) 
@@ -145,43 +240,44 @@ impl From<u128> for Decimal {
 }
 
 // NEW: Add conversion from Rate to Decimal
+//
+// Same as `Rate::to_decimal()`: both are WAD-scaled, so the full U256 value
+// carries over.
 impl From<crate::math::Rate> for Decimal {
     fn from(rate: crate::math::Rate) -> Self {
-        Self::from_scaled_val(rate.to_scaled_val())
+        rate.to_decimal()
     }
 }
 
 // NEW: Implement division with Rate
 impl crate::math::TryDiv<crate::math::Rate> for Decimal {
     fn try_div(self, rhs: crate::math::Rate) -> Result<Self, LendingError> {
-        let rhs_decimal = Decimal::from(rhs);
-        self.try_div(rhs_decimal)
+        self.try_div(rhs.to_decimal())
     }
 }
 
 // NEW: Implement multiplication with Rate
 impl crate::math::TryMul<crate::math::Rate> for Decimal {
     fn try_mul(self, rhs: crate::math::Rate) -> Result<Self, LendingError> {
-        let rhs_decimal = Decimal::from(rhs);
-        self.try_mul(rhs_decimal)
+        self.try_mul(rhs.to_decimal())
     }
 }
 
 impl crate::math::TryAdd for Decimal {
     fn try_add(self, rhs: Self) -> Result<Self, LendingError> {
-        Ok(Self(self.0.checked_add(rhs.0).ok_or(LendingError::MathOverflow)?))
+        Ok(Self(self.0.checked_add(rhs.0).ok_or(MathContext::Add.overflow())?))
     }
 }
 
 impl crate::math::TrySub for Decimal {
     fn try_sub(self, rhs: Self) -> Result<Self, LendingError> {
-        Ok(Self(self.0.checked_sub(rhs.0).ok_or(LendingError::MathOverflow)?))
+        Ok(Self(self.0.checked_sub(rhs.0).ok_or(MathContext::Sub.overflow())?))
     }
 }
 
 impl crate::math::TryDiv<u64> for Decimal {
     fn try_div(self, rhs: u64) -> Result<Self, LendingError> {
-        Ok(Self(self.0.checked_div(U256::from(rhs)).ok_or(LendingError::MathOverflow)?))
+        Ok(Self(self.0.checked_div(U256::from(rhs)).ok_or(MathContext::Div.overflow())?))
     }
 }
 
@@ -190,16 +286,16 @@ impl crate::math::TryDiv<Decimal> for Decimal {
         Ok(Self(
             self.0
                 .checked_mul(Self::wad())
-                .ok_or(LendingError::MathOverflow)?
+                .ok_or(MathContext::Div.overflow())?
                 .checked_div(rhs.0)
-                .ok_or(LendingError::MathOverflow)?
+                .ok_or(MathContext::Div.overflow())?
         ))
     }
 }
 
 impl crate::math::TryMul<u64> for Decimal {
     fn try_mul(self, rhs: u64) -> Result<Self, LendingError> {
-        Ok(Self(self.0.checked_mul(U256::from(rhs)).ok_or(LendingError::MathOverflow)?))
+        Ok(Self(self.0.checked_mul(U256::from(rhs)).ok_or(MathContext::Mul.overflow())?))
     }
 }
 
@@ -208,19 +304,142 @@ impl crate::math::TryMul<Decimal> for Decimal {
         Ok(Self(
             self.0
                 .checked_mul(rhs.0)
-                .ok_or(LendingError::MathOverflow)?
+                .ok_or(MathContext::Mul.overflow())?
                 .checked_div(Self::wad())
-                .ok_or(LendingError::MathOverflow)?
+                .ok_or(MathContext::Mul.overflow())?
         ))
     }
 }
 
 #[cfg(test)]
 mod test { 
-    use super::*; 
+    use super::*;
+    use crate::math::TrySub; 
     
     #[test] 
     fn test_scaler() { 
         assert_eq!(U256::from(WAD), Decimal::wad()); 
     } 
-}
\ No newline at end of file
+
+    #[test]
+    fn test_to_rate_preserves_value_above_u128() {
+        let big = U256::from(u128::MAX) + U256::from(1u64);
+        assert_eq!(Decimal(big).to_rate().0, big);
+    }
+
+    #[test]
+    fn test_from_decimal_keeps_value_above_u128() {
+        let big = U256::from(u128::MAX) + U256::from(1u64);
+        assert_eq!(crate::math::Rate::from(Decimal(big)).raw(), big);
+        assert_eq!(Decimal::from(crate::math::Rate(big)), Decimal(big));
+    }
+
+    #[test]
+    fn test_percent_of() {
+        assert_eq!(Decimal::from(100u64).percent_of(5).unwrap(), Decimal::from(5u64));
+        assert_eq!(Decimal::from(100u64).percent_of(100).unwrap(), Decimal::from(100u64));
+        assert_eq!(Decimal::from(100u64).percent_of(0).unwrap(), Decimal::zero());
+    }
+
+    #[test]
+    fn test_bps_of() {
+        assert_eq!(Decimal::from(100u64).bps_of(500).unwrap(), Decimal::from(5u64));
+        assert_eq!(Decimal::from(100u64).bps_of(10_000).unwrap(), Decimal::from(100u64));
+        assert_eq!(Decimal::from(100u64).bps_of(1).unwrap(), Decimal::from_percent(1));
+    }
+
+    #[test]
+    fn test_format_with_rounds() {
+        let value = Decimal::from_scaled_val(1_234_560_000_000_000_000);
+        assert_eq!(value.format_with(2), "1.23");
+        assert_eq!(value.format_with(4), "1.2346");
+        assert_eq!(value.format_with(0), "1");
+        assert_eq!(Decimal::from_scaled_val(4_000_000_000_000_000).format_with(2), "0");
+    }
+
+    #[test]
+    fn test_format_with_trims_trailing_zeros() {
+        assert_eq!(Decimal::from_percent(150).format_with(4), "1.5");
+        assert_eq!(Decimal::from(2u64).format_with(2), "2");
+        assert_eq!(Decimal::from_percent(5).format_with(18), "0.05");
+    }
+
+    #[test]
+    fn test_cmp_rate() {
+        let half = Decimal::one().try_div(2u64).unwrap();
+        assert_eq!(half.cmp_rate(&crate::math::Rate::from_percent(50)), core::cmp::Ordering::Equal);
+        assert_eq!(half.cmp_rate(&crate::math::Rate::from_percent(49)), core::cmp::Ordering::Greater);
+        assert_eq!(half.cmp_rate(&crate::math::Rate::from_percent(51)), core::cmp::Ordering::Less);
+        assert_eq!(crate::math::Rate::from_percent(50).to_decimal(), half);
+    }
+
+    #[test]
+    fn test_u256_round_trip_above_u64() {
+        let amount = U256::from(u64::MAX) + U256::from(1_000u64);
+        let decimal = Decimal::try_from_u256(amount).unwrap();
+        assert_eq!(decimal.try_floor_u256().unwrap(), amount);
+        assert_eq!(decimal.try_floor_u64(), Err(LendingError::MathOverflow));
+        assert_eq!(Decimal::try_from_u256(U256::max_value()), Err(LendingError::MathOverflowMul));
+    }
+
+    #[test]
+    fn test_try_from_errors_instead_of_zeroing() {
+        // u128::MAX * WAD still fits in U256, so overflow needs a wider input
+        assert_eq!(Decimal::try_from_u128(u128::MAX).unwrap().try_floor_u256().unwrap(), U256::from(u128::MAX));
+        assert_eq!(Decimal::try_from_u64(7).unwrap(), Decimal::from(7u64));
+
+        let overflowing = U256::max_value() / U256::from(WAD) + U256::one();
+        assert_eq!(Decimal::try_from_u256(overflowing), Err(LendingError::MathOverflowMul));
+    }
+
+    #[test]
+    fn test_raw_keeps_high_bits() {
+        let value = U256::from(u128::MAX) * U256::from(4u64);
+        let decimal = Decimal(value);
+        assert_eq!(decimal.raw(), value);
+        // Beyond what `to_scaled_val` can represent
+        assert!(decimal.raw() > U256::from(u128::MAX));
+    }
+
+    #[test]
+    fn test_overflow_carries_math_context() {
+        let big = Decimal(U256::max_value());
+        let err = big.try_mul(Decimal::from(2u64)).unwrap_err();
+        assert_eq!(err, LendingError::MathOverflowMul);
+        assert_eq!(err.math_context(), Some(MathContext::Mul));
+        assert!(err.is_math_overflow());
+
+        let err = Decimal::zero().try_sub(Decimal::one()).unwrap_err();
+        assert_eq!(err.math_context(), Some(MathContext::Sub));
+        assert_eq!(Decimal::one().try_div(0u64), Err(LendingError::MathOverflowDiv));
+        assert_eq!(LendingError::MathOverflow.math_context(), None);
+    }
+
+    #[test]
+    fn test_mul_round_removes_truncation_drift() {
+        let factor = Decimal(U256::from(1_000_000_001_234_567_891u64));
+        let mut truncated = Decimal::one();
+        let mut rounded = Decimal::one();
+        // Reference carried at 36 digits so its own truncation is negligible
+        let mut reference = U256::exp10(36);
+        for _ in 0..1_000 {
+            truncated = truncated.try_mul(factor).unwrap();
+            rounded = rounded.try_mul_round(factor).unwrap();
+            reference = reference * factor.0 / U256::exp10(18);
+        }
+
+        let reference = reference / U256::exp10(18);
+        let drift = |value: Decimal| if value.0 > reference { value.0 - reference } else { reference - value.0 };
+        assert!(truncated.0 < reference);
+        assert!(drift(truncated) > U256::from(250u64));
+        assert!(drift(rounded) < U256::from(50u64));
+    }
+
+    #[test]
+    fn test_from_fraction() {
+        assert_eq!(Decimal::from_fraction(U256::from(3u64), U256::from(2u64)), Ok(Decimal::from_percent(150)));
+        // numerator * WAD overflows, but the ratio fits
+        let big = U256::max_value() / 2;
+        assert_eq!(Decimal::from_fraction(big, big), Ok(Decimal::one()));
+    }
+}
@@ -1,9 +1,13 @@
 pub mod common;
 pub mod decimal;
 pub mod rate;
+pub mod signed_decimal;
+pub mod token_amount;
 
 pub use decimal::Decimal;
 pub use rate::Rate;
+pub use signed_decimal::SignedDecimal;
+pub use token_amount::TokenAmount;
 
 pub trait TryAdd: Sized {
     fn try_add(self, rhs: Self) -> Result<Self, crate::error::LendingError>;
@@ -63,11 +63,33 @@ impl Rate {
         self.0.as_u128()
     }
 
+    /// Underlying scaled value, without the `u128` narrowing of `to_scaled_val`
+    pub fn raw(&self) -> U256 {
+        self.0
+    }
+
     /// Create rate from scaled value
     pub fn from_scaled_val(scaled_val: u128) -> Self { 
         Self(U256::from(scaled_val)) 
     }
 
+    /// Convert to a decimal without narrowing the scaled value through `u128`.
+    /// Both types are WAD-scaled, so this is the lossless path for comparing
+    /// a rate against a decimal threshold.
+    pub fn to_decimal(&self) -> crate::math::Decimal {
+        crate::math::Decimal(self.0)
+    }
+
+    /// Smaller of two rates
+    pub fn min(self, other: Self) -> Self {
+        if other < self { other } else { self }
+    }
+
+    /// Larger of two rates
+    pub fn max(self, other: Self) -> Self {
+        if other > self { other } else { self }
+    }
+
     /// Calculate power (for compound interest)
     pub fn try_pow(&self, exponent: u64) -> Result<Self, LendingError> {
         if exponent == 0 {
@@ -80,9 +102,38 @@ impl Rate {
 
         while exp > 0 {
             if exp % 2 == 1 {
-                result = result.try_mul(base)?;
+                result = result.try_mul(base).map_err(|_| MathContext::Pow.overflow())?;
             }
-            base = base.try_mul(base)?;
+            base = base.try_mul(base).map_err(|_| MathContext::Pow.overflow())?;
+            exp /= 2;
+        }
+
+        Ok(result)
+    }
+
+    /// Multiply, rounding half up instead of truncating
+    pub fn try_mul_round(self, rhs: Self) -> Result<Self, LendingError> {
+        Ok(Self(
+            self.0
+                .checked_mul(rhs.0)
+                .and_then(|product| product.checked_add(U256::from(HALF_WAD)))
+                .ok_or(MathContext::Mul.overflow())?
+                / Self::wad()
+        ))
+    }
+
+    /// `try_pow` with each step rounded half up, so long compounding runs
+    /// don't drift downward
+    pub fn try_pow_round(&self, exponent: u64) -> Result<Self, LendingError> {
+        let mut result = Self::one();
+        let mut base = *self;
+        let mut exp = exponent;
+
+        while exp > 0 {
+            if exp % 2 == 1 {
+                result = result.try_mul_round(base).map_err(|_| MathContext::Pow.overflow())?;
+            }
+            base = base.try_mul_round(base).map_err(|_| MathContext::Pow.overflow())?;
             exp /= 2;
         }
 
@@ -110,27 +161,30 @@ impl From<u64> for Rate {
 }
 
 // NEW: Add conversion from Decimal to Rate
+//
+// Same as `Decimal::to_rate()`: both are WAD-scaled, so the full U256 value
+// carries over.
 impl From<crate::math::Decimal> for Rate {
     fn from(decimal: crate::math::Decimal) -> Self {
-        Self::from_scaled_val(decimal.to_scaled_val())
+        decimal.to_rate()
     }
 }
 
 impl crate::math::TryAdd for Rate {
     fn try_add(self, rhs: Self) -> Result<Self, LendingError> {
-        Ok(Self(self.0.checked_add(rhs.0).ok_or(LendingError::MathOverflow)?))
+        Ok(Self(self.0.checked_add(rhs.0).ok_or(MathContext::Add.overflow())?))
     }
 }
 
 impl crate::math::TrySub for Rate {
     fn try_sub(self, rhs: Self) -> Result<Self, LendingError> {
-        Ok(Self(self.0.checked_sub(rhs.0).ok_or(LendingError::MathOverflow)?))
+        Ok(Self(self.0.checked_sub(rhs.0).ok_or(MathContext::Sub.overflow())?))
     }
 }
 
 impl crate::math::TryDiv<u64> for Rate {
     fn try_div(self, rhs: u64) -> Result<Self, LendingError> {
-        Ok(Self(self.0.checked_div(U256::from(rhs)).ok_or(LendingError::MathOverflow)?))
+        Ok(Self(self.0.checked_div(U256::from(rhs)).ok_or(MathContext::Div.overflow())?))
     }
 }
 
@@ -139,16 +193,16 @@ impl crate::math::TryDiv<Rate> for Rate {
         Ok(Self(
             self.0
                 .checked_mul(Self::wad())
-                .ok_or(LendingError::MathOverflow)?
+                .ok_or(MathContext::Div.overflow())?
                 .checked_div(rhs.0)
-                .ok_or(LendingError::MathOverflow)?
+                .ok_or(MathContext::Div.overflow())?
         ))
     }
 }
 
 impl crate::math::TryMul<u64> for Rate {
     fn try_mul(self, rhs: u64) -> Result<Self, LendingError> {
-        Ok(Self(self.0.checked_mul(U256::from(rhs)).ok_or(LendingError::MathOverflow)?))
+        Ok(Self(self.0.checked_mul(U256::from(rhs)).ok_or(MathContext::Mul.overflow())?))
     }
 }
 
@@ -157,9 +211,9 @@ impl crate::math::TryMul<Rate> for Rate {
         Ok(Self(
             self.0
                 .checked_mul(rhs.0)
-                .ok_or(LendingError::MathOverflow)?
+                .ok_or(MathContext::Mul.overflow())?
                 .checked_div(Self::wad())
-                .ok_or(LendingError::MathOverflow)?
+                .ok_or(MathContext::Mul.overflow())?
         ))
     }
 }
@@ -180,4 +234,39 @@ mod test {
         let squared = rate.try_pow(2).unwrap();
         assert!(squared.0 < rate.0); // Logic: 0.1 * 0.1 = 0.01 (lebih kecil)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_to_decimal_preserves_value_above_u128() {
+        let big = U256::from(u128::MAX) + U256::from(1u64);
+        assert_eq!(Rate(big).to_decimal().0, big);
+    }
+
+    #[test]
+    fn test_rate_min_max() {
+        let low = Rate::from_percent(10);
+        let high = Rate::from_percent(50);
+        assert_eq!(low.min(high), low);
+        assert_eq!(high.min(low), low);
+        assert_eq!(low.max(high), high);
+        assert_eq!(high.max(low), high);
+    }
+
+    #[test]
+    fn test_raw_keeps_high_bits() {
+        let value = U256::from(u128::MAX) + U256::one();
+        assert_eq!(Rate(value).raw(), value);
+    }
+
+    #[test]
+    fn test_pow_overflow_context() {
+        let rate = Rate(U256::max_value() / 2);
+        assert_eq!(rate.try_pow(3), Err(LendingError::MathOverflowPow));
+    }
+
+    #[test]
+    fn test_pow_round_matches_pow_on_exact_values() {
+        let rate = Rate::from_percent(50);
+        assert_eq!(rate.try_pow_round(3).unwrap(), rate.try_pow(3).unwrap());
+        assert_eq!(rate.try_pow_round(0).unwrap(), Rate::one());
+    }
+}
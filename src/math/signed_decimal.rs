@@ -0,0 +1,112 @@
+//! Signed counterpart to `Decimal` for values like net rates that can go
+//! below zero
+
+use {
+    crate::{error::LendingError, math::{Decimal, TryDiv}},
+    odra::casper_types::{bytesrepr::{Error, FromBytes, ToBytes}, CLType, CLTyped, U256},
+};
+
+/// A sign and a WAD-scaled magnitude. Zero is never negative, so equal
+/// values compare equal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SignedDecimal {
+    negative: bool,
+    magnitude: Decimal,
+}
+
+impl ToBytes for SignedDecimal {
+    fn to_bytes(&self) -> Result<alloc::vec::Vec<u8>, Error> {
+        (self.negative, self.magnitude.raw()).to_bytes()
+    }
+
+    fn serialized_length(&self) -> usize {
+        (self.negative, self.magnitude.raw()).serialized_length()
+    }
+}
+
+impl FromBytes for SignedDecimal {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let ((negative, magnitude), remainder) = <(bool, U256)>::from_bytes(bytes)?;
+        Ok((Self::new(negative, Decimal(magnitude)), remainder))
+    }
+}
+
+impl CLTyped for SignedDecimal {
+    fn cl_type() -> CLType {
+        <(bool, U256)>::cl_type()
+    }
+}
+
+impl SignedDecimal {
+    fn new(negative: bool, magnitude: Decimal) -> Self {
+        Self {
+            negative: negative && magnitude != Decimal::zero(),
+            magnitude,
+        }
+    }
+
+    pub fn zero() -> Self {
+        Self::default()
+    }
+
+    pub fn positive(magnitude: Decimal) -> Self {
+        Self::new(false, magnitude)
+    }
+
+    pub fn negative(magnitude: Decimal) -> Self {
+        Self::new(true, magnitude)
+    }
+
+    /// `a - b`, which can't overflow once signed
+    pub fn difference(a: Decimal, b: Decimal) -> Self {
+        if a >= b {
+            Self::positive(Decimal(a.raw() - b.raw()))
+        } else {
+            Self::negative(Decimal(b.raw() - a.raw()))
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn magnitude(&self) -> Decimal {
+        self.magnitude
+    }
+
+    /// Divide by an unsigned value, keeping the sign
+    pub fn try_div(self, rhs: Decimal) -> Result<Self, LendingError> {
+        Ok(Self::new(self.negative, self.magnitude.try_div(rhs)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_difference_sign() {
+        let five = Decimal::from(5u64);
+        let three = Decimal::from(3u64);
+
+        assert_eq!(SignedDecimal::difference(five, three), SignedDecimal::positive(Decimal::from(2u64)));
+        assert_eq!(SignedDecimal::difference(three, five), SignedDecimal::negative(Decimal::from(2u64)));
+        assert!(SignedDecimal::difference(three, five).is_negative());
+        assert_eq!(SignedDecimal::difference(five, five), SignedDecimal::zero());
+        assert!(!SignedDecimal::negative(Decimal::zero()).is_negative());
+    }
+
+    #[test]
+    fn test_div_keeps_sign() {
+        let value = SignedDecimal::negative(Decimal::from(6u64)).try_div(Decimal::from(3u64)).unwrap();
+        assert_eq!(value, SignedDecimal::negative(Decimal::from(2u64)));
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let value = SignedDecimal::negative(Decimal::from(7u64));
+        let bytes = value.to_bytes().unwrap();
+        assert_eq!(bytes.len(), value.serialized_length());
+        assert_eq!(SignedDecimal::from_bytes(&bytes).unwrap(), (value, &[][..]));
+    }
+}
@@ -0,0 +1,109 @@
+//! Raw token amount in the token's smallest unit
+
+use {
+    crate::{error::LendingError, math::{common::{MathContext, SCALE}, Decimal}},
+    odra::casper_types::U256,
+};
+
+/// Raw token amount, as held on-chain. Kept distinct from the WAD-scaled
+/// `Decimal` so the two can't be passed for one another.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd, Eq, Ord)]
+pub struct TokenAmount(pub U256);
+
+impl odra::casper_types::bytesrepr::ToBytes for TokenAmount {
+    fn to_bytes(&self) -> Result<alloc::vec::Vec<u8>, odra::casper_types::bytesrepr::Error> {
+        self.0.to_bytes()
+    }
+
+    fn serialized_length(&self) -> usize {
+        self.0.serialized_length()
+    }
+}
+
+impl odra::casper_types::bytesrepr::FromBytes for TokenAmount {
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), odra::casper_types::bytesrepr::Error> {
+        let (value, remainder) = U256::from_bytes(bytes)?;
+        Ok((TokenAmount(value), remainder))
+    }
+}
+
+impl odra::casper_types::CLTyped for TokenAmount {
+    fn cl_type() -> odra::casper_types::CLType {
+        odra::casper_types::CLType::U256
+    }
+}
+
+impl TokenAmount {
+    pub fn zero() -> Self {
+        Self(U256::zero())
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// Amount in whole tokens for a token with `decimals` decimals.
+    /// Exact for up to 18 decimals; more than that can't be represented.
+    pub fn to_decimal(&self, decimals: u8) -> Result<Decimal, LendingError> {
+        let scale = Self::wad_per_unit(decimals)?;
+        Ok(Decimal(self.0.checked_mul(scale).ok_or(MathContext::Mul.overflow())?))
+    }
+
+    /// Raw amount for `decimal` whole tokens, rounded down to the smallest unit
+    pub fn from_decimal(decimal: Decimal, decimals: u8) -> Result<Self, LendingError> {
+        let scale = Self::wad_per_unit(decimals)?;
+        Ok(Self(decimal.0.checked_div(scale).ok_or(MathContext::Div.overflow())?))
+    }
+
+    /// WAD units per smallest token unit
+    fn wad_per_unit(decimals: u8) -> Result<U256, LendingError> {
+        let exponent = (SCALE as u32)
+            .checked_sub(decimals as u32)
+            .ok_or(MathContext::Sub.overflow())?;
+        Ok(U256::from(10u64).pow(U256::from(exponent)))
+    }
+}
+
+impl From<U256> for TokenAmount {
+    fn from(val: U256) -> Self {
+        Self(val)
+    }
+}
+
+impl From<u64> for TokenAmount {
+    fn from(val: u64) -> Self {
+        Self(U256::from(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_across_decimals() {
+        let amount = TokenAmount::from(123_456_789u64);
+        for decimals in [0u8, 6, 9, 18] {
+            let decimal = amount.to_decimal(decimals).unwrap();
+            assert_eq!(TokenAmount::from_decimal(decimal, decimals).unwrap(), amount);
+        }
+    }
+
+    #[test]
+    fn test_to_decimal_scales_by_decimals() {
+        let amount = TokenAmount::from(1_500_000u64);
+        assert_eq!(amount.to_decimal(6).unwrap(), Decimal::from_scaled_val(1_500_000_000_000_000_000));
+        assert_eq!(amount.to_decimal(0).unwrap(), Decimal::from(1_500_000u64));
+    }
+
+    #[test]
+    fn test_from_decimal_rounds_down() {
+        let decimal = Decimal::from_scaled_val(1_999_999_999_999_999_999);
+        assert_eq!(TokenAmount::from_decimal(decimal, 6).unwrap(), TokenAmount::from(1_999_999u64));
+    }
+
+    #[test]
+    fn test_more_than_18_decimals_rejected() {
+        assert_eq!(TokenAmount::from(1u64).to_decimal(19), Err(LendingError::MathOverflowSub));
+    }
+}
@@ -11,10 +11,38 @@ use crate::math::{TryAdd, TrySub, TryMul, TryDiv};
 
 use crate::error::LendingError;
 use crate::math::{
-    common::{TryAdd, TryDiv, TryMul, TrySub},
-    Decimal, Rate
+    common::{mul_div, TryAdd, TryDiv, TryMul, TrySub, SCALE},
+    Decimal, Rate, SignedDecimal, TokenAmount
 };
 
+/// Default number of slots before reserves and obligations need a refresh.
+/// Slots are block times in milliseconds, so this is one minute.
+pub const DEFAULT_STALE_AFTER_SLOTS: u64 = 60_000;
+
+/// Milliseconds per year, the unit of block times and of every slot count
+/// in this module. `state::SLOTS_PER_YEAR` counts seconds.
+pub const MS_PER_YEAR: u64 = 31_536_000_000;
+
+/// Default cap on reserves per market, keeping per-reserve loops bounded
+pub const DEFAULT_MAX_RESERVES: u64 = 32;
+/// Layout version of newly stored obligations
+pub const OBLIGATION_VERSION: u8 = 2;
+
+/// Largest raw price scale an oracle can be configured with
+pub const MAX_PRICE_DECIMALS: u8 = 36;
+
+/// Most deposit fee tiers a reserve can configure
+pub const MAX_FEE_TIERS: usize = 4;
+
+/// Role bits granted through `grant_role`; the owner holds all of them.
+/// Keepers run maintenance such as `recompute_obligation`
+pub const ROLE_KEEPER: u8 = 1;
+/// Pausers can halt and resume the market
+pub const ROLE_PAUSER: u8 = 1 << 1;
+/// Risk admins tune market parameters, reserve configs and oracles
+pub const ROLE_RISK_ADMIN: u8 = 1 << 2;
+const ALL_ROLES: u8 = ROLE_KEEPER | ROLE_PAUSER | ROLE_RISK_ADMIN;
+
 #[odra::module]
 pub struct NovaLending {
     // Lending Market State
@@ -25,12 +53,49 @@ pub struct NovaLending {
     
     // Reserves and Obligations storage
     pub reserves: Mapping<Address, Reserve>,
-    pub obligations: Mapping<Address, Obligation>,
+    pub reserve_keys: List<Address>,
+    pub underlying_to_reserve: Mapping<Address, Address>,
+    pub obligations: Mapping<Address, Option<Obligation>>,
+    pub obligation_owners: List<Address>,
+    pub obligation_owner_index: Mapping<Address, Option<u32>>,
+    /// Obligations still stored in the v1 layout, awaiting `migrate_obligation`
+    pub legacy_obligations: Mapping<Address, Option<ObligationV1>>,
     
     // Additional state variables
     pub bump_seed: Var<u8>,
     pub last_update_slot: Var<u64>,
     pub reserve_count: Var<u64>,
+    pub stale_after_slots: Var<u64>,
+    /// More lenient price age tolerated by liquidations
+    pub liquidation_stale_after_slots: Var<u64>,
+    pub config_timelock_slots: Var<u64>,
+    pub pending_reserve_configs: Mapping<Address, Option<PendingReserveConfig>>,
+    pub liquidation_grace_slots: Var<u64>,
+    pub borrow_cooldown_slots: Var<u64>,
+    pub max_reserves: Var<u64>,
+    /// Max percent of market TVL that borrows backed by any one oracle's
+    /// collateral may reach; 0 leaves it uncapped
+    pub max_oracle_exposure: Var<u8>,
+    /// Borrowed value backed by each oracle's collateral, the sum of every
+    /// obligation's entry in `obligation_oracle_exposure`
+    pub oracle_exposures: Mapping<Address, Decimal>,
+    /// Each obligation's (oracle, borrowed value) shares of `oracle_exposures`
+    pub obligation_oracle_exposure: Mapping<Address, Vec<(Address, Decimal)>>,
+    pub paused: Var<bool>,
+    /// Raw feed values pushed by the owner per oracle, quoted ahead of the
+    /// mock feed and scaled by each reserve's `price_decimals`
+    pub oracle_prices: Mapping<Address, Option<U256>>,
+    /// Debt left on obligations whose collateral was seized in full, per
+    /// borrow reserve
+    pub bad_debt: Mapping<Address, Decimal>,
+    /// Borrowers whose unbacked debt makes up `bad_debt`, per borrow reserve
+    pub bad_debt_borrowers: Mapping<Address, Vec<Address>>,
+    /// Bad debt written off by `socialize_bad_debt` so far, per reserve
+    pub socialized_bad_debt: Mapping<Address, Decimal>,
+    /// Collateral minted to the protocol by `compound_reserve_fees`, per reserve
+    pub protocol_collateral: Mapping<Address, U256>,
+    /// `ROLE_*` bits held by each account
+    pub roles: Mapping<Address, u8>,
 }
 
 #[odra::module]
@@ -56,6 +121,8 @@ impl NovaLending {
         self.bump_seed.set(bump);
         self.last_update_slot.set(0u64);
         self.reserve_count.set(0u64);
+        self.stale_after_slots.set(DEFAULT_STALE_AFTER_SLOTS);
+        self.max_reserves.set(DEFAULT_MAX_RESERVES);
     }
 
     // ===========================================================================
@@ -74,6 +141,136 @@ impl NovaLending {
         Ok(())
     }
 
+    /// Adds the `ROLE_*` bits in `role` to `account`
+    pub fn grant_role(&mut self, account: Address, role: u8) -> Result<(), LendingError> {
+        let caller = self.env().caller();
+        let current_owner = self.owner.get().unwrap();
+        
+        if caller != current_owner {
+            return Err(LendingError::InvalidMarketOwner);
+        }
+
+        if role == 0 || role & !ALL_ROLES != 0 {
+            return Err(LendingError::InvalidConfig);
+        }
+
+        let roles = self.roles.get(&account).unwrap_or(0) | role;
+        self.roles.set(&account, roles);
+        self.env().emit_event(RolesUpdated {
+            account,
+            roles,
+            slot: self.env().get_block_time(),
+        });
+        Ok(())
+    }
+
+    /// Removes the `ROLE_*` bits in `role` from `account`
+    pub fn revoke_role(&mut self, account: Address, role: u8) -> Result<(), LendingError> {
+        let caller = self.env().caller();
+        let current_owner = self.owner.get().unwrap();
+        
+        if caller != current_owner {
+            return Err(LendingError::InvalidMarketOwner);
+        }
+
+        if role == 0 || role & !ALL_ROLES != 0 {
+            return Err(LendingError::InvalidConfig);
+        }
+
+        let roles = self.roles.get(&account).unwrap_or(0) & !role;
+        self.roles.set(&account, roles);
+        self.env().emit_event(RolesUpdated {
+            account,
+            roles,
+            slot: self.env().get_block_time(),
+        });
+        Ok(())
+    }
+
+    /// Whether `account` holds every bit in `role`; always true for the owner
+    pub fn has_role(&self, account: Address, role: u8) -> bool {
+        self.owner.get() == Some(account)
+            || self.roles.get(&account).unwrap_or(0) & role == role
+    }
+
+    pub fn set_stale_after_slots(&mut self, slots: u64) -> Result<(), LendingError> {
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        // Zero would mark everything stale immediately after a refresh
+        if slots == 0 {
+            return Err(LendingError::InvalidConfig);
+        }
+
+        self.stale_after_slots.set(slots);
+        Ok(())
+    }
+
+    /// Price age liquidations tolerate, so a marginally stale price can't
+    /// block them while new borrows still need a fresh one. Can't be stricter
+    /// than `stale_after_slots`.
+    pub fn set_liquidation_stale_after_slots(&mut self, slots: u64) -> Result<(), LendingError> {
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        if slots < self.get_stale_after_slots() {
+            return Err(LendingError::InvalidConfig);
+        }
+
+        self.liquidation_stale_after_slots.set(slots);
+        Ok(())
+    }
+
+    /// Slots an obligation must stay unhealthy before it can be liquidated
+    pub fn set_liquidation_grace_slots(&mut self, slots: u64) -> Result<(), LendingError> {
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        self.liquidation_grace_slots.set(slots);
+        Ok(())
+    }
+
+    /// Slots after a collateral deposit before the obligation can borrow;
+    /// zero disables the cooldown
+    pub fn set_borrow_cooldown_slots(&mut self, slots: u64) -> Result<(), LendingError> {
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        self.borrow_cooldown_slots.set(slots);
+        Ok(())
+    }
+
+    /// Halts deposits, redemptions and new borrows. Repayments, liquidations
+    /// and `emergency_redeem` stay available.
+    pub fn set_paused(&mut self, paused: bool) -> Result<(), LendingError> {
+        self.check_role(ROLE_PAUSER)?;
+
+        self.paused.set(paused);
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.get().unwrap_or(false)
+    }
+
+    /// Caps how many reserves `init_reserve` may create. Lowering it below
+    /// the current count only blocks new reserves.
+    pub fn set_max_reserves(&mut self, max_reserves: u64) -> Result<(), LendingError> {
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        self.max_reserves.set(max_reserves);
+        Ok(())
+    }
+
+    /// Caps borrows backed by a single oracle's collateral at `percent` of
+    /// market TVL, checked on every borrow. Zero removes the cap.
+    pub fn set_max_oracle_exposure(&mut self, percent: u8) -> Result<(), LendingError> {
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        if percent > 100 {
+            return Err(LendingError::InvalidConfig);
+        }
+
+        self.max_oracle_exposure.set(percent);
+        Ok(())
+    }
+
     // ===========================================================================
     // RESERVE OPERATIONS
     // ===========================================================================
@@ -81,13 +278,33 @@ impl NovaLending {
     pub fn init_reserve(
         &mut self,
         liquidity_amount: U256,
-        config: ReserveConfig
-    ) -> Result<(), LendingError> {
+        config: ReserveConfig,
+        underlying: Option<Address>,
+        mint_decimals: u8,
+        initial_exchange_rate: Option<Decimal>
+    ) -> Result<Address, LendingError> {
         if liquidity_amount == U256::zero() {
             return Err(LendingError::InvalidAmount);
         }
 
+        // Liquidity per unit of collateral; below 1.0 the collateral supply
+        // would outgrow the liquidity backing it
+        let initial_exchange_rate = initial_exchange_rate.unwrap_or_else(Decimal::one);
+        if initial_exchange_rate < Decimal::one() {
+            return Err(LendingError::InvalidConfig);
+        }
+        let initial_collateral_supply = Decimal::try_from_u256(liquidity_amount)?
+            .try_div(initial_exchange_rate)?
+            .try_floor_u256()?;
+        if initial_collateral_supply.is_zero() {
+            return Err(LendingError::InvalidConfig);
+        }
+
         config.validate()?;
+        // Market values scale by 10^decimals within 18-decimal precision
+        if mint_decimals > 18 {
+            return Err(LendingError::InvalidConfig);
+        }
 
         let caller = self.env().caller();
         let current_owner = self.owner.get().unwrap();
@@ -96,15 +313,26 @@ impl NovaLending {
             return Err(LendingError::InvalidMarketOwner);
         }
 
+        if self.get_reserve_count() >= self.get_max_reserves() {
+            return Err(LendingError::ReserveLimitReached);
+        }
+
+        // Two reserves for the same asset would split liquidations between them
+        if let Some(underlying) = underlying {
+            if self.underlying_to_reserve.get(&underlying).is_some() {
+                return Err(LendingError::AlreadyInitialized);
+            }
+        }
+
         let clock = self.env().get_block_time();
-        let market_price = self.get_oracle_price()?;
+        let market_price = self.get_oracle_price(self.oracle_program_id.get().unwrap(), SCALE as u8)?;
 
         let reserve = Reserve::new(InitReserveParams {
             current_slot: clock,
             lending_market: self.env().self_address(),
             liquidity: ReserveLiquidity::new(NewReserveLiquidityParams {
-                mint_pubkey: self.generate_temp_address(),
-                mint_decimals: 9,
+                mint_pubkey: underlying.unwrap_or_else(|| self.generate_temp_address()),
+                mint_decimals,
                 supply_pubkey: self.generate_temp_address(),
                 fee_receiver: caller,
                 oracle_pubkey: self.oracle_program_id.get().unwrap(),
@@ -112,34 +340,75 @@ impl NovaLending {
                 available_amount: liquidity_amount,
                 borrowed_amount_wads: Decimal::zero(),
                 cumulative_borrow_rate_wads: Decimal::one(),
+                underlying,
             }),
             collateral: ReserveCollateral::new(NewReserveCollateralParams {
                 mint_pubkey: self.generate_temp_address(),
                 supply_pubkey: self.generate_temp_address(),
-                mint_total_supply: U256::zero(),
+                // Seeded against the initial liquidity (1:1 unless a peg is
+                // given) so the exchange rate is never derived from an empty
+                // collateral supply
+                mint_total_supply: initial_collateral_supply,
             }),
             config,
         });
 
         let reserve_key = self.generate_reserve_key();
         self.reserves.set(&reserve_key, reserve);
+        self.reserve_keys.push(reserve_key);
+        if let Some(underlying) = underlying {
+            self.underlying_to_reserve.set(&underlying, reserve_key);
+        }
         
         // Increment reserve count
         let count = self.reserve_count.get().unwrap_or(0);
         self.reserve_count.set(count + 1);
 
-        Ok(())
+        Ok(reserve_key)
     }
 
     pub fn refresh_reserve(&mut self, reserve_key: Address) -> Result<(), LendingError> {
         let mut reserve = self.reserves.get(&reserve_key)
             .ok_or(LendingError::InvalidAccountInput)?;
+        // Another market's reserve may follow different oracle semantics
+        reserve.ensure_market(self.env().self_address())?;
         
         let clock = self.env().get_block_time();
-        reserve.liquidity.market_price = self.get_oracle_price()?;
+        reserve.liquidity.market_price = self.get_oracle_price(
+            reserve.liquidity.oracle_pubkey,
+            reserve.liquidity.price_decimals
+        )?;
         
         reserve.accrue_interest(clock)?;
         reserve.last_update.update_slot(clock);
+
+        self.env().emit_event(ReserveRefreshed {
+            reserve: reserve_key,
+            cumulative_borrow_rate_wads: reserve.liquidity.cumulative_borrow_rate_wads,
+            available_amount: reserve.liquidity.available_amount,
+            borrowed_amount_wads: reserve.liquidity.borrowed_amount_wads,
+            market_price: reserve.liquidity.market_price,
+            slot: clock,
+        });
+        
+        self.reserves.set(&reserve_key, reserve);
+        Ok(())
+    }
+
+    /// Refreshes only the market price, leaving interest accrual to the next
+    /// `refresh_reserve`
+    pub fn refresh_reserve_price(&mut self, reserve_key: Address) -> Result<(), LendingError> {
+        let mut reserve = self.reserves.get(&reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+        // Another market's reserve may follow different oracle semantics
+        reserve.ensure_market(self.env().self_address())?;
+        
+        let clock = self.env().get_block_time();
+        reserve.liquidity.market_price = self.get_oracle_price(
+            reserve.liquidity.oracle_pubkey,
+            reserve.liquidity.price_decimals
+        )?;
+        reserve.last_update.update_slot(clock);
         
         self.reserves.set(&reserve_key, reserve);
         Ok(())
@@ -148,9 +417,11 @@ impl NovaLending {
     pub fn deposit_reserve_liquidity(
         &mut self,
         reserve_key: Address,
-        liquidity_amount: U256
-    ) -> Result<U256, LendingError> {
-        if liquidity_amount == U256::zero() {
+        liquidity_amount: TokenAmount
+    ) -> Result<TokenAmount, LendingError> {
+        self.check_not_paused()?;
+
+        if liquidity_amount.is_zero() {
             return Err(LendingError::InvalidAmount);
         }
 
@@ -158,7 +429,8 @@ impl NovaLending {
             .ok_or(LendingError::InvalidAccountInput)?;
         
         let clock = self.env().get_block_time();
-        if reserve.last_update.is_stale(clock) {
+        let stale_after = self.get_stale_after_slots();
+        if reserve.last_update.is_stale(clock, stale_after) {
             return Err(LendingError::ReserveStale);
         }
 
@@ -168,16 +440,36 @@ impl NovaLending {
         self.reserves.set(&reserve_key, reserve);
         
         // In Odra, token transfers happen through CEP-18
-        self.transfer_tokens(liquidity_amount)?;
+        self.transfer_tokens(liquidity_amount.0)?;
         
         Ok(collateral_amount)
     }
 
+    /// Deposit into several reserves in one call, returning the collateral
+    /// minted for each in order. Any failing deposit reverts the whole batch;
+    /// since a deposit marks its reserve stale, a reserve can appear only once.
+    pub fn deposit_reserve_liquidity_batch(
+        &mut self,
+        deposits: Vec<(Address, U256)>
+    ) -> Result<Vec<U256>, LendingError> {
+        if deposits.is_empty() {
+            return Err(LendingError::InvalidAmount);
+        }
+
+        let mut minted = Vec::with_capacity(deposits.len());
+        for (reserve_key, liquidity_amount) in deposits {
+            minted.push(self.deposit_reserve_liquidity(reserve_key, TokenAmount(liquidity_amount))?.0);
+        }
+        Ok(minted)
+    }
+
     pub fn redeem_reserve_collateral(
         &mut self,
         reserve_key: Address,
         collateral_amount: U256
     ) -> Result<U256, LendingError> {
+        self.check_not_paused()?;
+
         if collateral_amount == U256::zero() {
             return Err(LendingError::InvalidAmount);
         }
@@ -186,7 +478,8 @@ impl NovaLending {
             .ok_or(LendingError::InvalidAccountInput)?;
         
         let clock = self.env().get_block_time();
-        if reserve.last_update.is_stale(clock) {
+        let stale_after = self.get_stale_after_slots();
+        if reserve.last_update.is_stale(clock, stale_after) {
             return Err(LendingError::ReserveStale);
         }
 
@@ -201,6 +494,38 @@ impl NovaLending {
         Ok(liquidity_amount)
     }
 
+    /// Redeems collateral while the market is paused. Skips the freshness
+    /// check, since the exchange rate needs no price, but only pays out of
+    /// available liquidity. Outside a pause the reserve must be fresh.
+    pub fn emergency_redeem(
+        &mut self,
+        reserve_key: Address,
+        collateral_amount: U256
+    ) -> Result<U256, LendingError> {
+        if collateral_amount == U256::zero() {
+            return Err(LendingError::InvalidAmount);
+        }
+
+        let mut reserve = self.reserves.get(&reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+
+        if !self.is_paused() {
+            let clock = self.env().get_block_time();
+            if reserve.last_update.is_stale(clock, self.get_stale_after_slots()) {
+                return Err(LendingError::ReserveStale);
+            }
+        }
+
+        let liquidity_amount = reserve.redeem_collateral(collateral_amount)?;
+        reserve.last_update.mark_stale();
+        
+        self.reserves.set(&reserve_key, reserve);
+        
+        self.transfer_tokens_to_user(liquidity_amount)?;
+        
+        Ok(liquidity_amount)
+    }
+
     // ===========================================================================
     // OBLIGATION OPERATIONS
     // ===========================================================================
@@ -208,7 +533,7 @@ impl NovaLending {
     pub fn init_obligation(&mut self) -> Result<(), LendingError> {
         let caller = self.env().caller();
         
-        if self.obligations.get(&caller).is_some() {
+        if self.obligations.get(&caller).flatten().is_some() {
             return Err(LendingError::AlreadyInitialized);
         }
 
@@ -221,20 +546,59 @@ impl NovaLending {
             borrows: vec![],
         });
 
-        self.obligations.set(&caller, obligation);
+        self.obligations.set(&caller, Some(obligation));
+        self.add_obligation_owner(caller);
+        Ok(())
+    }
+
+    /// Moves `user`'s obligation from the v1 layout into the current one,
+    /// defaulting the fields v1 lacked
+    pub fn migrate_obligation(&mut self, user: Address) -> Result<(), LendingError> {
+        // Already on the current layout, whether created or migrated
+        if self.obligations.get(&user).flatten().is_some() {
+            return Err(LendingError::AlreadyInitialized);
+        }
+        let legacy = self.legacy_obligations.get(&user).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+
+        self.obligations.set(&user, Some(legacy.upgrade()));
+        self.legacy_obligations.set(&user, None);
+        self.add_obligation_owner(user);
+        Ok(())
+    }
+
+    /// Clears the caller's obligation once every deposit has been withdrawn
+    /// and every borrow repaid. Zeroed position entries count as empty.
+    pub fn close_obligation(&mut self) -> Result<(), LendingError> {
+        let caller = self.env().caller();
+        let obligation = self.obligations.get(&caller).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+
+        let has_deposits = obligation.deposits.iter()
+            .any(|collateral| !collateral.deposited_amount.is_zero());
+        let has_borrows = obligation.borrows.iter()
+            .any(|liquidity| liquidity.borrowed_amount_wads != Decimal::zero());
+        if has_deposits || has_borrows {
+            return Err(LendingError::ObligationNotEmpty);
+        }
+
+        self.sync_oracle_exposure(caller, Vec::new())?;
+        self.obligations.set(&caller, None);
+        self.remove_obligation_owner(caller);
         Ok(())
     }
 
     pub fn refresh_obligation(&mut self, user_address: Address) -> Result<(), LendingError> {
-        let mut obligation = self.obligations.get(&user_address)
+        let mut obligation = self.obligations.get(&user_address).flatten()
             .ok_or(LendingError::InvalidObligationOwner)?;
 
         let clock = self.env().get_block_time();
+        let stale_after = self.get_stale_after_slots();
         
         let mut deposited_value = Decimal::zero();
         let mut borrowed_value = Decimal::zero();
-        let mut allowed_borrow_value = Decimal::zero();
         let mut unhealthy_borrow_value = Decimal::zero();
+        let mut borrow_capacities = Vec::with_capacity(obligation.deposits.len());
 
         // Refresh deposits
         for collateral in obligation.deposits.iter_mut() {
@@ -242,7 +606,7 @@ impl NovaLending {
             let reserve = self.reserves.get(&reserve_key)
                 .ok_or(LendingError::InvalidAccountInput)?;
 
-            if reserve.last_update.is_stale(clock) {
+            if reserve.last_update.is_stale(clock, stale_after) {
                 return Err(LendingError::ReserveStale);
             }
 
@@ -258,9 +622,10 @@ impl NovaLending {
             let liquidation_threshold_rate = Rate::from_percent(reserve.config.liquidation_threshold);
 
             deposited_value = deposited_value.try_add(market_value)?;
-            allowed_borrow_value = allowed_borrow_value.try_add(market_value.try_mul(loan_to_value_rate)?)?;
+            borrow_capacities.push((market_value.try_mul(loan_to_value_rate)?, reserve.config.collateral_weight_cap));
             unhealthy_borrow_value = unhealthy_borrow_value.try_add(market_value.try_mul(liquidation_threshold_rate)?)?;
         }
+        let allowed_borrow_value = capped_borrow_value(&borrow_capacities)?;
 
         // Refresh borrows
         for liquidity in obligation.borrows.iter_mut() {
@@ -268,11 +633,11 @@ impl NovaLending {
             let reserve = self.reserves.get(&reserve_key)
                 .ok_or(LendingError::InvalidAccountInput)?;
 
-            if reserve.last_update.is_stale(clock) {
+            if reserve.last_update.is_stale(clock, stale_after) {
                 return Err(LendingError::ReserveStale);
             }
 
-            liquidity.accrue_interest(reserve.liquidity.cumulative_borrow_rate_wads)?;
+            liquidity.accrue_interest(reserve.liquidity.cumulative_borrow_rate_wads, clock)?;
 
             let market_value = self.calculate_market_value(
                 liquidity.borrowed_amount_wads.try_floor_u64()?,
@@ -288,30 +653,183 @@ impl NovaLending {
         obligation.borrowed_value = borrowed_value;
         obligation.allowed_borrow_value = allowed_borrow_value;
         obligation.unhealthy_borrow_value = unhealthy_borrow_value;
+        obligation.mark_unhealthy_since(clock);
         obligation.last_update.update_slot(clock);
         
-        self.obligations.set(&user_address, obligation);
+        let contributions = self.oracle_contributions(&obligation, obligation.borrowed_value)?;
+        self.sync_oracle_exposure(user_address, contributions)?;
+        self.obligations.set(&user_address, Some(obligation));
+        Ok(())
+    }
+
+    /// Refreshes each user's obligation, skipping those that touch a stale
+    /// reserve. Returns the users that were refreshed.
+    pub fn refresh_obligations(&mut self, users: Vec<Address>) -> Result<Vec<Address>, LendingError> {
+        let mut refreshed = Vec::with_capacity(users.len());
+        for user in users {
+            match self.refresh_obligation(user) {
+                Ok(()) => refreshed.push(user),
+                Err(LendingError::ReserveStale) => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(refreshed)
+    }
+
+    /// Owner-only repair for an obligation whose cached aggregates may be
+    /// inconsistent. Positions in reserves that no longer exist are dropped
+    /// and reported via `ObligationPositionDropped`; remaining reserves must
+    /// be fresh.
+    pub fn recompute_obligation(&mut self, user_address: Address) -> Result<(), LendingError> {
+        self.check_role(ROLE_KEEPER)?;
+
+        let mut obligation = self.obligations.get(&user_address).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+
+        let clock = self.env().get_block_time();
+        let stale_after = self.get_stale_after_slots();
+        let position_reserves = obligation.deposits.iter()
+            .map(|collateral| collateral.deposit_reserve)
+            .chain(obligation.borrows.iter().map(|liquidity| liquidity.borrow_reserve));
+        for reserve_key in position_reserves {
+            if let Some(reserve) = self.reserves.get(&reserve_key) {
+                if reserve.last_update.is_stale(clock, stale_after) {
+                    return Err(LendingError::ReserveStale);
+                }
+            }
+        }
+
+        let dropped = obligation.recompute_aggregates(clock, |key| self.reserves.get(key))?;
+        obligation.mark_unhealthy_since(clock);
+        obligation.last_update.update_slot(clock);
+        let contributions = self.oracle_contributions(&obligation, obligation.borrowed_value)?;
+        self.sync_oracle_exposure(user_address, contributions)?;
+        self.obligations.set(&user_address, Some(obligation));
+
+        for reserve in dropped {
+            self.env().emit_event(ObligationPositionDropped {
+                obligation_owner: user_address,
+                reserve,
+                slot: clock,
+            });
+        }
+        Ok(())
+    }
+
+    /// Recomputes only the positions backed by `reserve_keys` and adjusts the
+    /// obligation's aggregates by the change in their values, then marks the
+    /// obligation fresh.
+    ///
+    /// The given reserves must be fresh. Positions in other reserves keep the
+    /// values from the last refresh, so this is only sound when those reserves
+    /// have not moved since then; use `refresh_obligation` otherwise.
+    pub fn refresh_obligation_partial(
+        &mut self,
+        user_address: Address,
+        reserve_keys: Vec<Address>
+    ) -> Result<(), LendingError> {
+        let mut obligation = self.obligations.get(&user_address).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+
+        let clock = self.env().get_block_time();
+        let stale_after = self.get_stale_after_slots();
+
+        let mut deposited_value = obligation.deposited_value;
+        let mut borrowed_value = obligation.borrowed_value;
+        let mut unhealthy_borrow_value = obligation.unhealthy_borrow_value;
+
+        for collateral in obligation.deposits.iter_mut() {
+            if !reserve_keys.contains(&collateral.deposit_reserve) {
+                continue;
+            }
+            let reserve = self.reserves.get(&collateral.deposit_reserve)
+                .ok_or(LendingError::InvalidAccountInput)?;
+
+            if reserve.last_update.is_stale(clock, stale_after) {
+                return Err(LendingError::ReserveStale);
+            }
+
+            let market_value = self.calculate_market_value(
+                collateral.deposited_amount,
+                reserve.liquidity.market_price,
+                reserve.liquidity.mint_decimals
+            )?;
+
+            let liquidation_threshold_rate = Rate::from_percent(reserve.config.liquidation_threshold);
+
+            deposited_value = deposited_value
+                .try_sub(collateral.market_value)?
+                .try_add(market_value)?;
+            unhealthy_borrow_value = unhealthy_borrow_value
+                .try_sub(collateral.market_value.try_mul(liquidation_threshold_rate)?)?
+                .try_add(market_value.try_mul(liquidation_threshold_rate)?)?;
+
+            collateral.market_value = market_value;
+        }
+
+        for liquidity in obligation.borrows.iter_mut() {
+            if !reserve_keys.contains(&liquidity.borrow_reserve) {
+                continue;
+            }
+            let reserve = self.reserves.get(&liquidity.borrow_reserve)
+                .ok_or(LendingError::InvalidAccountInput)?;
+
+            if reserve.last_update.is_stale(clock, stale_after) {
+                return Err(LendingError::ReserveStale);
+            }
+
+            liquidity.accrue_interest(reserve.liquidity.cumulative_borrow_rate_wads, clock)?;
+
+            let market_value = self.calculate_market_value(
+                liquidity.borrowed_amount_wads.try_floor_u64()?.into(),
+                reserve.liquidity.market_price,
+                reserve.liquidity.mint_decimals
+            )?;
+
+            borrowed_value = borrowed_value
+                .try_sub(liquidity.market_value)?
+                .try_add(market_value)?;
+            liquidity.market_value = market_value;
+        }
+
+        obligation.deposited_value = deposited_value;
+        obligation.borrowed_value = borrowed_value;
+        // Caps depend on every deposit's share, so recompute over all of them
+        obligation.allowed_borrow_value = self.allowed_borrow_value(&obligation)?;
+        obligation.unhealthy_borrow_value = unhealthy_borrow_value;
+        obligation.mark_unhealthy_since(clock);
+        obligation.last_update.update_slot(clock);
+
+        let contributions = self.oracle_contributions(&obligation, obligation.borrowed_value)?;
+        self.sync_oracle_exposure(user_address, contributions)?;
+        self.obligations.set(&user_address, Some(obligation));
         Ok(())
     }
 
+    /// Deposits collateral and returns the resulting health factor, with the
+    /// deposited position revalued in place; `Decimal(U256::MAX)` when
+    /// nothing is borrowed. Other positions keep their last refreshed values.
     pub fn deposit_obligation_collateral(
         &mut self,
         reserve_key: Address,
         collateral_amount: U256
-    ) -> Result<(), LendingError> {
+    ) -> Result<Decimal, LendingError> {
+        self.check_not_paused()?;
+
         if collateral_amount == U256::zero() {
             return Err(LendingError::InvalidAmount);
         }
 
         let caller = self.env().caller();
-        let mut obligation = self.obligations.get(&caller)
+        let mut obligation = self.obligations.get(&caller).flatten()
             .ok_or(LendingError::InvalidObligationOwner)?;
             
         let mut reserve = self.reserves.get(&reserve_key)
             .ok_or(LendingError::InvalidAccountInput)?;
 
         let clock = self.env().get_block_time();
-        if reserve.last_update.is_stale(clock) {
+        let stale_after = self.get_stale_after_slots();
+        if reserve.last_update.is_stale(clock, stale_after) {
             return Err(LendingError::ReserveStale);
         }
 
@@ -319,17 +837,31 @@ impl NovaLending {
             return Err(LendingError::ReserveCollateralDisabled);
         }
 
+        let added_value = self.calculate_market_value(
+            collateral_amount,
+            reserve.liquidity.market_price,
+            reserve.liquidity.mint_decimals
+        )?;
         let collateral = obligation.find_or_add_collateral_to_deposits(reserve_key)?;
         collateral.deposit(collateral_amount)?;
+        collateral.market_value = collateral.market_value.try_add(added_value)?;
+
+        obligation.deposited_value = obligation.deposited_value.try_add(added_value)?;
+        obligation.allowed_borrow_value = self.allowed_borrow_value(&obligation)?;
+        obligation.unhealthy_borrow_value = obligation.unhealthy_borrow_value
+            .try_add(added_value.try_mul(Rate::from_percent(reserve.config.liquidation_threshold))?)?;
+        let health_factor = obligation.health_factor()?
+            .unwrap_or(Decimal(U256::max_value()));
             
+        obligation.last_deposit_slot = clock;
         obligation.last_update.mark_stale();
         
-        self.obligations.set(&caller, obligation);
+        self.obligations.set(&caller, Some(obligation));
         self.reserves.set(&reserve_key, reserve);
         
         self.transfer_tokens(collateral_amount)?;
         
-        Ok(())
+        Ok(health_factor)
     }
 
     pub fn withdraw_obligation_collateral(
@@ -337,19 +869,22 @@ impl NovaLending {
         reserve_key: Address,
         collateral_amount: U256
     ) -> Result<(), LendingError> {
+        self.check_not_paused()?;
+
         if collateral_amount == U256::zero() {
             return Err(LendingError::InvalidAmount);
         }
 
         let caller = self.env().caller();
-        let mut obligation = self.obligations.get(&caller)
+        let mut obligation = self.obligations.get(&caller).flatten()
             .ok_or(LendingError::InvalidObligationOwner)?;
             
         let reserve = self.reserves.get(&reserve_key)
             .ok_or(LendingError::InvalidAccountInput)?;
 
         let clock = self.env().get_block_time();
-        if reserve.last_update.is_stale(clock) || obligation.last_update.is_stale(clock) {
+        let stale_after = self.get_stale_after_slots();
+        if reserve.last_update.is_stale(clock, stale_after) || obligation.last_update.is_stale(clock, stale_after) {
             return Err(LendingError::ReserveStale);
         }
 
@@ -366,80 +901,299 @@ impl NovaLending {
             }
         } else {
             // Complex withdrawal logic with borrows
-            self.calculate_withdraw_amount(&obligation, &reserve, &collateral, collateral_amount)?
+            self.calculate_withdraw_amount(&obligation, &collateral, collateral_amount)?
         };
 
         obligation.withdraw(withdraw_amount, collateral_index)?;
         obligation.last_update.mark_stale();
         
-        self.obligations.set(&caller, obligation);
+        self.obligations.set(&caller, Some(obligation));
         self.transfer_tokens_to_user(withdraw_amount)?;
         
         Ok(())
     }
 
-    // ===========================================================================
-    // BORROW AND REPAY OPERATIONS
-    // ===========================================================================
-    
-    pub fn borrow_obligation_liquidity(
+    /// Withdraws collateral, first redeeming as much of it as needed to repay
+    /// the obligation's debt in the same reserve so it stays within its
+    /// borrow limit. The redeemed liquidity goes back into the reserve, so
+    /// the collateral and debt must share a reserve.
+    pub fn withdraw_with_auto_repay(
         &mut self,
-        reserve_key: Address,
-        liquidity_amount: U256,
-        slippage_limit: U256
+        withdraw_reserve_key: Address,
+        repay_reserve_key: Address,
+        collateral_amount: U256
     ) -> Result<(), LendingError> {
-        if liquidity_amount == U256::zero() {
+        self.check_not_paused()?;
+
+        if collateral_amount == U256::zero() {
             return Err(LendingError::InvalidAmount);
         }
 
         let caller = self.env().caller();
-        let mut obligation = self.obligations.get(&caller)
+        let mut obligation = self.obligations.get(&caller).flatten()
             .ok_or(LendingError::InvalidObligationOwner)?;
-            
-        let mut reserve = self.reserves.get(&reserve_key)
+        let mut withdraw_reserve = self.reserves.get(&withdraw_reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+        let repay_reserve = self.reserves.get(&repay_reserve_key)
             .ok_or(LendingError::InvalidAccountInput)?;
 
         let clock = self.env().get_block_time();
-        if reserve.last_update.is_stale(clock) || obligation.last_update.is_stale(clock) {
+        let stale_after = self.get_stale_after_slots();
+        if withdraw_reserve.last_update.is_stale(clock, stale_after) ||
+           repay_reserve.last_update.is_stale(clock, stale_after) ||
+           obligation.last_update.is_stale(clock, stale_after) {
             return Err(LendingError::ReserveStale);
         }
 
-        if obligation.deposits.is_empty() {
-            return Err(LendingError::ObligationDepositsEmpty);
+        let (collateral, collateral_index) = obligation.find_collateral_in_deposits(withdraw_reserve_key)?;
+        if collateral.deposited_amount == U256::zero() {
+            return Err(LendingError::ObligationCollateralEmpty);
         }
 
-        let remaining_borrow_value = obligation.remaining_borrow_value()?;
-        if remaining_borrow_value == Decimal::zero() {
-            return Err(LendingError::BorrowTooLarge);
+        let withdraw_amount = collateral_amount.min(collateral.deposited_amount);
+        let withdraw_value = collateral.market_value
+            .try_mul(Decimal::try_from_u256(withdraw_amount)?)?
+            .try_div(Decimal::try_from_u256(collateral.deposited_amount)?)?;
+
+        let lost_borrow_value = withdraw_value
+            .try_mul(Rate::from_percent(withdraw_reserve.config.loan_to_value_ratio))?;
+        let allowed_after = if lost_borrow_value >= obligation.allowed_borrow_value {
+            Decimal::zero()
+        } else {
+            obligation.allowed_borrow_value.try_sub(lost_borrow_value)?
+        };
+
+        let mut repay_collateral = U256::zero();
+        let mut repaid_value = Decimal::zero();
+        let mut leftover_liquidity = U256::zero();
+        if obligation.borrowed_value > allowed_after {
+            let repay_value = obligation.borrowed_value.try_sub(allowed_after)?;
+            let (liquidity, liquidity_index) = obligation.find_liquidity_in_borrows(repay_reserve_key)?;
+            if repay_value > withdraw_value || repay_value > liquidity.market_value {
+                return Err(LendingError::WithdrawTooLarge);
+            }
+
+            repay_collateral = Decimal::try_from_u256(withdraw_amount)?
+                .try_mul(repay_value)?
+                .try_div(withdraw_value)?
+                .try_ceil_u256()?
+                .min(withdraw_amount);
+
+            // Redeem the seized collateral, then swap its liquidity into the
+            // repay reserve's token at the oracle prices
+            let redeemed = withdraw_reserve.redeem_collateral(repay_collateral)?;
+            withdraw_reserve.last_update.mark_stale();
+            let withdraw_price = withdraw_reserve.liquidity.market_price;
+            let withdraw_decimals = withdraw_reserve.liquidity.mint_decimals;
+            self.reserves.set(&withdraw_reserve_key, withdraw_reserve);
+
+            // Reloaded so a same-reserve repay sees the redemption
+            let mut repay_reserve = self.reserves.get(&repay_reserve_key)
+                .ok_or(LendingError::InvalidAccountInput)?;
+            let price = repay_reserve.liquidity.market_price;
+            let decimals = repay_reserve.liquidity.mint_decimals;
+            let swapped = if withdraw_reserve_key == repay_reserve_key {
+                redeemed
+            } else {
+                let redeemed_value = self.calculate_market_value(redeemed, withdraw_price, withdraw_decimals)?;
+                token_amount(redeemed_value, price, decimals)?.try_floor_u256()?
+            };
+            let CalculateRepayResult {
+                settle_amount,
+                repay_amount,
+            } = repay_reserve.calculate_repay(swapped, liquidity.borrowed_amount_wads)?;
+            if repay_amount == U256::zero() {
+                return Err(LendingError::RepayTooSmall);
+            }
+
+            repay_reserve.liquidity.repay_borrow(repay_amount, settle_amount, &liquidity)?;
+            repay_reserve.last_update.mark_stale();
+            obligation.repay(settle_amount, liquidity_index)?;
+            self.reserves.set(&repay_reserve_key, repay_reserve);
+
+            repaid_value = self.calculate_market_value(repay_amount, price, decimals)?;
+            // Whatever the debt didn't take goes back in the withdrawn token
+            let used = if withdraw_reserve_key == repay_reserve_key {
+                repay_amount
+            } else {
+                token_amount(repaid_value, withdraw_price, withdraw_decimals)?
+                    .try_ceil_u256()?
+                    .min(redeemed)
+            };
+            leftover_liquidity = redeemed - used;
+        }
+
+        obligation.withdraw(withdraw_amount, collateral_index)?;
+        obligation.last_update.mark_stale();
+
+        self.sync_repaid_exposure(caller, &obligation, repaid_value)?;
+        self.obligations.set(&caller, Some(obligation));
+        self.transfer_tokens_to_user(withdraw_amount - repay_collateral)?;
+        if !leftover_liquidity.is_zero() {
+            self.transfer_tokens_to_user(leftover_liquidity)?;
+        }
+
+        Ok(())
+    }
+
+    // ===========================================================================
+    // BORROW AND REPAY OPERATIONS
+    // ===========================================================================
+    
+    pub fn borrow_obligation_liquidity(
+        &mut self,
+        reserve_key: Address,
+        liquidity_amount: U256,
+        slippage_limit: U256,
+        maturity_slot: u64,
+        rate_mode: RateMode,
+        host_fee_receiver: Option<Address>,
+        min_health_factor: Decimal
+    ) -> Result<(), LendingError> {
+        self.check_not_paused()?;
+
+        if liquidity_amount == U256::zero() {
+            return Err(LendingError::InvalidAmount);
+        }
+
+        let caller = self.env().caller();
+        let mut obligation = self.obligations.get(&caller).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+            
+        let mut reserve = self.reserves.get(&reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+        if !reserve.config.borrow_enabled {
+            return Err(LendingError::BorrowingDisabled);
+        }
+
+        let clock = self.env().get_block_time();
+        let stale_after = self.get_stale_after_slots();
+        if reserve.last_update.is_stale(clock, stale_after) || obligation.last_update.is_stale(clock, stale_after) {
+            return Err(LendingError::ReserveStale);
+        }
+
+        if obligation.deposits.is_empty() {
+            return Err(LendingError::ObligationDepositsEmpty);
+        }
+
+        // Keeps a deposit and a borrow against it out of the same block
+        let cooldown = self.borrow_cooldown_slots.get().unwrap_or(0);
+        if clock < obligation.last_deposit_slot.saturating_add(cooldown) {
+            return Err(LendingError::BorrowCooldown);
+        }
+
+        // A zero maturity is a perpetual loan
+        if maturity_slot != 0 && maturity_slot <= clock {
+            return Err(LendingError::InvalidConfig);
+        }
+
+        let remaining_borrow_value = obligation.remaining_borrow_value()?;
+        if remaining_borrow_value == Decimal::zero() {
+            // Deposits made before their reserve's LTV was set to zero count
+            // for nothing; say so rather than call the borrow too large
+            let collateral_disabled = obligation.deposits.iter().all(|collateral| {
+                self.reserves.get(&collateral.deposit_reserve)
+                    .map_or(false, |reserve| reserve.config.loan_to_value_ratio == 0)
+            });
+            if collateral_disabled {
+                return Err(LendingError::ReserveCollateralDisabled);
+            }
+            return Err(LendingError::BorrowTooLarge);
         }
 
+        // Bring the cumulative rate up to this slot before a new position
+        // snapshots it, so a later same-slot refresh charges it nothing
+        reserve.accrue_interest(clock)?;
+
         let CalculateBorrowResult {
             borrow_amount,
             receive_amount,
             borrow_fee: _,
-            host_fee: _,
+            host_fee,
         } = reserve.calculate_borrow(liquidity_amount, remaining_borrow_value)?;
 
         if receive_amount == U256::zero() {
             return Err(LendingError::BorrowTooSmall);
         }
 
-        if liquidity_amount == U256::max_value() && receive_amount < slippage_limit {
+        let max_borrow_per_tx = reserve.config.max_borrow_per_tx;
+        if !max_borrow_per_tx.is_zero() && borrow_amount.try_floor_u256()? > max_borrow_per_tx {
+            return Err(LendingError::BorrowTooLarge);
+        }
+
+        let added_value = self.calculate_market_value(
+            borrow_amount.try_floor_u256()?,
+            reserve.liquidity.market_price,
+            reserve.liquidity.mint_decimals
+        )?;
+
+        // The borrower's own margin on top of the protocol's; zero skips it
+        if min_health_factor > Decimal::zero() {
+            let borrowed_value = obligation.borrowed_value.try_add(added_value)?;
+            if obligation.unhealthy_borrow_value.try_div(borrowed_value)? < min_health_factor {
+                return Err(LendingError::BorrowTooLarge);
+            }
+        }
+
+        // Fees are taken out of precise borrows too, so guard every path
+        if receive_amount < slippage_limit {
             return Err(LendingError::ExceededSlippage);
         }
 
+        let contributions = self.oracle_contributions(&obligation, obligation.borrowed_value.try_add(added_value)?)?;
+        self.check_oracle_exposure(caller, &contributions)?;
+
         reserve.liquidity.borrow(borrow_amount)?;
         reserve.last_update.mark_stale();
+
+        // Without an integrator to pay, the host fee stays in the reserve
+        // as protocol fees
+        if host_fee_receiver.is_none() && !host_fee.is_zero() {
+            reserve.liquidity.deposit(host_fee)?;
+            reserve.liquidity.accumulated_protocol_fees_wads = reserve.liquidity.accumulated_protocol_fees_wads
+                .try_add(Decimal::try_from_u256(host_fee)?)?;
+        }
         
         let liquidity = obligation.find_or_add_liquidity_to_borrows(reserve_key)?;
-        liquidity.borrow(borrow_amount.try_floor_u64()?)?;
+        // The term and rate mode are fixed when the position opens; later
+        // borrows keep them
+        if liquidity.borrowed_amount_wads == Decimal::zero() {
+            liquidity.maturity_slot = maturity_slot;
+            liquidity.rate_mode = rate_mode;
+            liquidity.stable_borrow_rate = match liquidity.rate_mode {
+                RateMode::Stable => reserve.current_borrow_rate()?,
+                RateMode::Variable => Rate::zero(),
+            };
+            liquidity.cumulative_borrow_rate_wads = reserve.liquidity.cumulative_borrow_rate_wads;
+            liquidity.last_accrual_slot = clock;
+        } else {
+            // Settle what the position owes so far, so the new principal
+            // starts from this slot's snapshot
+            liquidity.accrue_interest(reserve.liquidity.cumulative_borrow_rate_wads, clock)?;
+        }
+        if matches!(liquidity.rate_mode, RateMode::Stable) {
+            reserve.liquidity.add_stable_borrow(borrow_amount, liquidity.stable_borrow_rate)?;
+        }
+        liquidity.borrow(borrow_amount.try_floor_u64()?.into())?;
         obligation.last_update.mark_stale();
         
+        self.sync_oracle_exposure(caller, contributions)?;
         self.reserves.set(&reserve_key, reserve);
-        self.obligations.set(&caller, obligation);
+        self.obligations.set(&caller, Some(obligation));
         
         // Distribute borrowed amount minus fees
         self.transfer_tokens_to_user(receive_amount)?;
+        if let Some(receiver) = host_fee_receiver {
+            if !host_fee.is_zero() {
+                self.transfer_tokens_to(receiver, host_fee)?;
+                self.env().emit_event(HostFeePaid {
+                    reserve: reserve_key,
+                    receiver,
+                    amount: host_fee,
+                    slot: clock,
+                });
+            }
+        }
         
         Ok(())
     }
@@ -454,14 +1208,15 @@ impl NovaLending {
         }
 
         let caller = self.env().caller();
-        let mut obligation = self.obligations.get(&caller)
+        let mut obligation = self.obligations.get(&caller).flatten()
             .ok_or(LendingError::InvalidObligationOwner)?;
             
         let mut reserve = self.reserves.get(&reserve_key)
             .ok_or(LendingError::InvalidAccountInput)?;
 
         let clock = self.env().get_block_time();
-        if reserve.last_update.is_stale(clock) || obligation.last_update.is_stale(clock) {
+        let stale_after = self.get_stale_after_slots();
+        if reserve.last_update.is_stale(clock, stale_after) || obligation.last_update.is_stale(clock, stale_after) {
             return Err(LendingError::ReserveStale);
         }
 
@@ -479,20 +1234,51 @@ impl NovaLending {
             return Err(LendingError::RepayTooSmall);
         }
 
-        reserve.liquidity.repay(repay_amount, settle_amount)?;
+        reserve.liquidity.repay_borrow(repay_amount, settle_amount, &liquidity)?;
         reserve.last_update.mark_stale();
         
         obligation.repay(settle_amount, liquidity_index)?;
         obligation.last_update.mark_stale();
         
+        let repaid_value = self.calculate_market_value(
+            repay_amount,
+            reserve.liquidity.market_price,
+            reserve.liquidity.mint_decimals
+        )?;
+        self.sync_repaid_exposure(caller, &obligation, repaid_value)?;
         self.reserves.set(&reserve_key, reserve);
-        self.obligations.set(&caller, obligation);
+        self.obligations.set(&caller, Some(obligation));
         
         self.transfer_tokens(repay_amount)?;
         
         Ok(())
     }
 
+    /// Repay `percent` (1-100) of the caller's current debt in a reserve;
+    /// 100 settles it in full
+    pub fn repay_obligation_liquidity_pct(
+        &mut self,
+        reserve_key: Address,
+        percent: u8
+    ) -> Result<(), LendingError> {
+        if percent == 0 || percent > 100 {
+            return Err(LendingError::InvalidAmount);
+        }
+
+        let caller = self.env().caller();
+        let obligation = self.obligations.get(&caller).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+        let (liquidity, _) = obligation.find_liquidity_in_borrows(reserve_key)?;
+
+        let liquidity_amount = if percent == 100 {
+            U256::max_value()
+        } else {
+            liquidity.borrowed_amount_wads.percent_of(percent)?.try_floor_u256()?
+        };
+
+        self.repay_obligation_liquidity(reserve_key, liquidity_amount)
+    }
+
     // ===========================================================================
     // LIQUIDATION OPERATIONS
     // ===========================================================================
@@ -503,65 +1289,146 @@ impl NovaLending {
         repay_reserve_key: Address,
         withdraw_reserve_key: Address,
         liquidity_amount: U256
+    ) -> Result<(), LendingError> {
+        self.liquidate(borrower, repay_reserve_key, withdraw_reserve_key, liquidity_amount, false)
+    }
+
+    /// Liquidates a fixed-term borrow past its maturity slot, regardless of
+    /// the obligation's health.
+    pub fn liquidate_matured(
+        &mut self,
+        borrower: Address,
+        repay_reserve_key: Address,
+        withdraw_reserve_key: Address,
+        liquidity_amount: U256
+    ) -> Result<(), LendingError> {
+        self.liquidate(borrower, repay_reserve_key, withdraw_reserve_key, liquidity_amount, true)
+    }
+
+    /// Liquidates against every collateral at once, seizing the same fraction
+    /// of each position so the seized value follows each one's share of the
+    /// deposited value. All deposit reserves must be fresh.
+    pub fn liquidate_obligation_proportional(
+        &mut self,
+        borrower: Address,
+        repay_reserve_key: Address,
+        liquidity_amount: U256
     ) -> Result<(), LendingError> {
         if liquidity_amount == U256::zero() {
             return Err(LendingError::InvalidAmount);
         }
+        // The bonus would come out of the protocol for the borrower's benefit
+        if self.env().caller() == borrower {
+            return Err(LendingError::SelfLiquidation);
+        }
 
-        let mut obligation = self.obligations.get(&borrower)
+        let mut obligation = self.obligations.get(&borrower).flatten()
             .ok_or(LendingError::InvalidObligationOwner)?;
-            
         let mut repay_reserve = self.reserves.get(&repay_reserve_key)
             .ok_or(LendingError::InvalidAccountInput)?;
-        let mut withdraw_reserve = self.reserves.get(&withdraw_reserve_key)
-            .ok_or(LendingError::InvalidAccountInput)?;
 
         let clock = self.env().get_block_time();
-        if repay_reserve.last_update.is_stale(clock) || 
-           withdraw_reserve.last_update.is_stale(clock) || 
-           obligation.last_update.is_stale(clock) {
+        let stale_after = self.get_liquidation_stale_after_slots();
+        if repay_reserve.last_update.is_stale(clock, stale_after) ||
+           obligation.last_update.is_stale(clock, stale_after) {
             return Err(LendingError::ReserveStale);
         }
+        let mut reserves_updated = repay_reserve.last_update.slot;
+        for collateral in obligation.deposits.iter() {
+            let deposit_reserve = self.reserves.get(&collateral.deposit_reserve)
+                .ok_or(LendingError::InvalidAccountInput)?;
+            if deposit_reserve.last_update.is_stale(clock, stale_after) {
+                return Err(LendingError::ReserveStale);
+            }
+            reserves_updated = reserves_updated.max(deposit_reserve.last_update.slot);
+        }
+        if !obligation.is_refreshed_since(reserves_updated) {
+            return Err(LendingError::ObligationStale);
+        }
 
-        if obligation.borrowed_value < obligation.unhealthy_borrow_value {
-            return Err(LendingError::ObligationHealthy);
+        self.check_liquidatable(&obligation, clock)?;
+        if obligation.deposited_value == Decimal::zero() {
+            return Err(LendingError::InvalidAccountInput);
         }
 
         let (liquidity, liquidity_index) = obligation.find_liquidity_in_borrows(repay_reserve_key)?;
-        let (collateral, collateral_index) = obligation.find_collateral_in_deposits(withdraw_reserve_key)?;
 
-        let CalculateLiquidationResult {
-            settle_amount,
-            repay_amount,
-            withdraw_amount,
-        } = withdraw_reserve.calculate_liquidation(
-            liquidity_amount,
-            &obligation,
-            &liquidity,
-            &collateral,
-        )?;
+        // The close factor caps value, so price the offered tokens first
+        let price = repay_reserve.liquidity.market_price;
+        let decimals = repay_reserve.liquidity.mint_decimals;
+        let max_repay = obligation.borrowed_value.try_sub(obligation.unhealthy_borrow_value)?;
+        let repay_value = self.calculate_market_value(liquidity_amount, price, decimals)?
+            .min(max_repay)
+            .min(liquidity.market_value);
+        let settle_amount = token_amount(repay_value, price, decimals)?
+            .min(Decimal::try_from_u256(liquidity_amount)?)
+            .min(liquidity.borrowed_amount_wads);
+        let repay_amount = settle_amount.try_floor_u256()?;
+        let bonus = repay_reserve.liquidation_bonus_rate(&obligation)?;
+        let withdraw_value = repay_value.try_add(repay_value.try_mul(bonus)?)?;
+        let withdraw_pct = withdraw_value.try_div(obligation.deposited_value)?;
+
+        let mut total_withdrawn = U256::zero();
+        for index in 0..obligation.deposits.len() {
+            let deposited_amount = obligation.deposits[index].deposited_amount;
+            // Flooring and capping keeps every position non-negative
+            let withdraw_amount = withdraw_pct
+                .try_mul(Decimal::try_from_u256(deposited_amount)?)?
+                .try_floor_u256()?
+                .min(deposited_amount);
+            obligation.withdraw(withdraw_amount, index)?;
+            total_withdrawn = total_withdrawn.try_add(withdraw_amount)?;
+        }
 
-        if repay_amount == U256::zero() || withdraw_amount == U256::zero() {
+        if repay_amount == U256::zero() || total_withdrawn == U256::zero() {
             return Err(LendingError::LiquidationTooSmall);
         }
 
-        repay_reserve.liquidity.repay(repay_amount, settle_amount)?;
+        repay_reserve.liquidity.repay_borrow(repay_amount, settle_amount, &liquidity)?;
         repay_reserve.last_update.mark_stale();
-        
+
         obligation.repay(settle_amount, liquidity_index)?;
-        obligation.withdraw(withdraw_amount, collateral_index)?;
         obligation.last_update.mark_stale();
-        
+
+        // With the last collateral seized, nothing backs what is still owed
+        if obligation.deposits.iter().all(|deposit| deposit.deposited_amount.is_zero()) {
+            for liquidity in obligation.borrows.iter() {
+                self.record_bad_debt(borrower, liquidity.borrow_reserve, liquidity.borrowed_amount_wads)?;
+            }
+        }
+
+        let repaid_value = self.calculate_market_value(
+            repay_amount,
+            repay_reserve.liquidity.market_price,
+            repay_reserve.liquidity.mint_decimals
+        )?;
+        self.sync_repaid_exposure(borrower, &obligation, repaid_value)?;
         self.reserves.set(&repay_reserve_key, repay_reserve);
-        self.reserves.set(&withdraw_reserve_key, withdraw_reserve);
-        self.obligations.set(&borrower, obligation);
-        
-        // Transfer logic for liquidation
-        self.handle_liquidation_transfers(repay_amount, withdraw_amount)?;
-        
+        self.obligations.set(&borrower, Some(obligation));
+
+        self.handle_liquidation_transfers(repay_amount, total_withdrawn)?;
+
         Ok(())
     }
 
+    /// Refreshes both reserves and the obligation, then liquidates in the same
+    /// call. Any other reserve the obligation touches must already be fresh.
+    pub fn refresh_and_liquidate(
+        &mut self,
+        borrower: Address,
+        repay_reserve_key: Address,
+        withdraw_reserve_key: Address,
+        liquidity_amount: U256
+    ) -> Result<(), LendingError> {
+        self.refresh_reserve(repay_reserve_key)?;
+        if withdraw_reserve_key != repay_reserve_key {
+            self.refresh_reserve(withdraw_reserve_key)?;
+        }
+        self.refresh_obligation(borrower)?;
+
+        self.liquidate(borrower, repay_reserve_key, withdraw_reserve_key, liquidity_amount, false)
+    }
+
     // ===========================================================================
     // FLASH LOAN OPERATIONS
     // ===========================================================================
@@ -571,12 +1438,21 @@ impl NovaLending {
         reserve_key: Address,
         amount: U256
     ) -> Result<(), LendingError> {
+        self.check_not_paused()?;
+
         if amount == U256::zero() {
             return Err(LendingError::InvalidAmount);
         }
 
         let mut reserve = self.reserves.get(&reserve_key)
             .ok_or(LendingError::InvalidAccountInput)?;
+        if !reserve.config.flash_loan_enabled {
+            return Err(LendingError::FlashLoanDisabled);
+        }
+
+        // Existing borrows are settled up to now before the reserve lends more
+        let clock = self.env().get_block_time();
+        reserve.accrue_interest(clock)?;
 
         let flash_loan_amount = if amount == U256::max_value() {
             reserve.liquidity.available_amount
@@ -584,26 +1460,157 @@ impl NovaLending {
             amount
         };
 
+        // Keep some liquidity in the reserve while the loan is out
+        let max_utilization = reserve.config.flash_loan_max_utilization;
+        if max_utilization != 0 {
+            let max_amount = Decimal::try_from_u256(reserve.liquidity.available_amount)?
+                .percent_of(max_utilization)?
+                .try_floor_u256()?;
+            if flash_loan_amount > max_amount {
+                return Err(LendingError::NotEnoughLiquidityAfterFlashLoan);
+            }
+        }
+
         let (origination_fee, host_fee) = reserve.config.fees
-            .calculate_flash_loan_fees(Decimal::from(flash_loan_amount.as_u128()))?;
+            .calculate_flash_loan_fees(Decimal::try_from_u256(flash_loan_amount)?)?;
 
         let returned_amount_required = flash_loan_amount
-            .checked_add(origination_fee.try_floor_u64()?)
+            .checked_add(origination_fee.try_floor_u256()?)
             .ok_or(LendingError::MathOverflow)?;
 
-        reserve.liquidity.borrow(Decimal::from(flash_loan_amount.as_u128()))?;
+        reserve.liquidity.borrow(Decimal::try_from_u256(flash_loan_amount)?)?;
         self.reserves.set(&reserve_key, reserve);
         
         // Execute flash loan logic
         self.execute_flash_loan(flash_loan_amount, returned_amount_required)?;
         
+        // The loan's execution may have moved the reserve on; settle it again
+        // before taking the repayment
         let mut reserve = self.reserves.get(&reserve_key).unwrap();
-        reserve.liquidity.repay(flash_loan_amount, Decimal::from(flash_loan_amount.as_u128()))?;
+        reserve.accrue_interest(self.env().get_block_time())?;
+        reserve.liquidity.repay(flash_loan_amount, Decimal::try_from_u256(flash_loan_amount)?)?;
         self.reserves.set(&reserve_key, reserve);
         
         // Handle fees
-        self.distribute_flash_loan_fees(origination_fee.try_floor_u64()?, host_fee.try_floor_u64()?)?;
+        self.distribute_flash_loan_fees(origination_fee.try_floor_u256()?, host_fee.try_floor_u256()?)?;
+        
+        Ok(())
+    }
+
+    /// Sends `to` whatever `token` balance the contract holds beyond its
+    /// reserve's `available_amount`, which already includes protocol fees.
+    /// Returns the amount swept.
+    pub fn sweep_untracked(&mut self, token: Address, to: Address) -> Result<U256, LendingError> {
+        let caller = self.env().caller();
+        let current_owner = self.owner.get().unwrap();
+        
+        if caller != current_owner {
+            return Err(LendingError::InvalidMarketOwner);
+        }
+
+        let tracked = match self.underlying_to_reserve.get(&token) {
+            Some(reserve_key) => self.reserves.get(&reserve_key)
+                .ok_or(LendingError::InvalidAccountInput)?
+                .liquidity
+                .available_amount,
+            None => U256::zero(),
+        };
+
+        let mut token_ref = Cep18TokenContractRef::new(self.env(), token);
+        let balance = token_ref.balance_of(&self.env().self_address());
+        let surplus = balance.saturating_sub(tracked);
+        if surplus.is_zero() {
+            return Err(LendingError::InvalidAmount);
+        }
+
+        token_ref.transfer(&to, &surplus);
+        self.env().emit_event(UntrackedTokensSwept {
+            token,
+            to,
+            amount: surplus,
+            slot: self.env().get_block_time(),
+        });
+        Ok(surplus)
+    }
+
+    /// Reinvests the reserve's accrued protocol fees as protocol-owned
+    /// liquidity, minting the matching collateral to the protocol. Returns
+    /// the collateral minted.
+    pub fn compound_reserve_fees(&mut self, reserve_key: Address) -> Result<U256, LendingError> {
+        let caller = self.env().caller();
+        let current_owner = self.owner.get().unwrap();
+        
+        if caller != current_owner {
+            return Err(LendingError::InvalidMarketOwner);
+        }
+
+        let mut reserve = self.reserves.get(&reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+        if reserve.liquidity.accumulated_protocol_fees_wads == Decimal::zero() {
+            return Err(LendingError::InvalidAmount);
+        }
+
+        let collateral_amount = reserve.compound_protocol_fees()?;
+        self.reserves.set(&reserve_key, reserve);
+
+        let owned = self.get_protocol_collateral(reserve_key).try_add(collateral_amount)?;
+        self.protocol_collateral.set(&reserve_key, owned);
+        Ok(collateral_amount)
+    }
+
+    /// Writes the reserve's recorded bad debt off its borrows and off the
+    /// obligations that owe it, lowering the exchange rate so suppliers
+    /// absorb the loss, and clears the record.
+    pub fn socialize_bad_debt(&mut self, reserve_key: Address) -> Result<(), LendingError> {
+        let caller = self.env().caller();
+        let current_owner = self.owner.get().unwrap();
         
+        if caller != current_owner {
+            return Err(LendingError::InvalidMarketOwner);
+        }
+
+        let bad_debt = self.get_reserve_bad_debt(reserve_key);
+        if bad_debt == Decimal::zero() {
+            return Err(LendingError::InvalidAmount);
+        }
+
+        let mut reserve = self.reserves.get(&reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+
+        // Clear what the obligations still owe, so a later repay or
+        // liquidation can't settle against debt the reserve no longer holds
+        let borrowed_before = reserve.liquidity.borrowed_amount_wads;
+        for borrower in self.bad_debt_borrowers.get(&reserve_key).unwrap_or_default() {
+            let Some(mut obligation) = self.obligations.get(&borrower).flatten() else {
+                continue;
+            };
+            let Ok((liquidity, index)) = obligation.find_liquidity_in_borrows(reserve_key) else {
+                continue;
+            };
+            obligation.repay(liquidity.borrowed_amount_wads, index)?;
+            obligation.last_update.mark_stale();
+            // Nothing backs what is left, so it counts against no oracle
+            self.sync_oracle_exposure(borrower, Vec::new())?;
+            self.obligations.set(&borrower, Some(obligation));
+            let owed = liquidity.borrowed_amount_wads.min(reserve.liquidity.borrowed_amount_wads);
+            reserve.liquidity.repay_borrow(U256::zero(), owed, &liquidity)?;
+        }
+
+        let written_off = borrowed_before.try_sub(reserve.liquidity.borrowed_amount_wads)?;
+        reserve.last_update.mark_stale();
+
+        self.reserves.set(&reserve_key, reserve);
+        self.bad_debt.set(&reserve_key, Decimal::zero());
+        self.bad_debt_borrowers.set(&reserve_key, Vec::new());
+        self.socialized_bad_debt.set(
+            &reserve_key,
+            self.socialized_bad_debt.get(&reserve_key).unwrap_or_else(Decimal::zero).try_add(written_off)?
+        );
+        self.env().emit_event(BadDebtSocialized {
+            reserve: reserve_key,
+            amount: written_off,
+            slot: self.env().get_block_time(),
+        });
         Ok(())
     }
 
@@ -618,6 +1625,17 @@ impl NovaLending {
     ) -> Result<(), LendingError> {
         new_config.validate()?;
 
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        // With a timelock set, changes must go through propose/apply
+        if self.config_timelock_slots.get().unwrap_or(0) > 0 {
+            return Err(LendingError::TimelockNotElapsed);
+        }
+
+        self.apply_config(reserve_key, new_config)
+    }
+
+    pub fn set_config_timelock_slots(&mut self, slots: u64) -> Result<(), LendingError> {
         let caller = self.env().caller();
         let current_owner = self.owner.get().unwrap();
         
@@ -625,18 +1643,52 @@ impl NovaLending {
             return Err(LendingError::InvalidMarketOwner);
         }
 
-        let mut reserve = self.reserves.get(&reserve_key)
+        self.config_timelock_slots.set(slots);
+        Ok(())
+    }
+
+    /// Stages a config change that `apply_reserve_config` activates once the
+    /// timelock has elapsed. A new proposal replaces any pending one.
+    pub fn propose_reserve_config(
+        &mut self,
+        reserve_key: Address,
+        new_config: ReserveConfig
+    ) -> Result<(), LendingError> {
+        new_config.validate()?;
+
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        let reserve = self.reserves.get(&reserve_key)
             .ok_or(LendingError::InvalidAccountInput)?;
+        reserve.ensure_market(self.env().self_address())?;
 
-        // Validate reserve belongs to this lending market
-        if reserve.lending_market != self.env().self_address() {
-            return Err(LendingError::InvalidAccountInput);
+        self.pending_reserve_configs.set(&reserve_key, Some(PendingReserveConfig {
+            config: new_config,
+            proposed_slot: self.env().get_block_time(),
+        }));
+        Ok(())
+    }
+
+    pub fn apply_reserve_config(&mut self, reserve_key: Address) -> Result<(), LendingError> {
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        let pending = self.pending_reserve_configs.get(&reserve_key)
+            .flatten()
+            .ok_or(LendingError::InvalidAccountInput)?;
+
+        let timelock = self.config_timelock_slots.get().unwrap_or(0);
+        let clock = self.env().get_block_time();
+        if clock < pending.proposed_slot.saturating_add(timelock) {
+            return Err(LendingError::TimelockNotElapsed);
         }
 
-        reserve.config = new_config;
-        self.reserves.set(&reserve_key, reserve);
-        
-        Ok(())
+        pending.config.validate()?;
+        self.pending_reserve_configs.set(&reserve_key, None);
+        self.apply_config(reserve_key, pending.config)
+    }
+
+    pub fn get_pending_reserve_config(&self, reserve_key: Address) -> Option<PendingReserveConfig> {
+        self.pending_reserve_configs.get(&reserve_key).flatten()
     }
 
     // ===========================================================================
@@ -668,9 +1720,13 @@ impl NovaLending {
         Address::from_bytes(&hash).unwrap()
     }
     
-    fn get_oracle_price(&self) -> Result<Decimal, LendingError> {
+    /// Normalizes the feed's raw value to a WAD price, whatever its scale
+    fn get_oracle_price(&self, oracle: Address, price_decimals: u8) -> Result<Decimal, LendingError> {
+        if let Some(raw_price) = self.oracle_prices.get(&oracle).flatten() {
+            return Decimal::from_fraction(raw_price, U256::from(10u64).pow(U256::from(price_decimals)));
+        }
         // Simplified oracle price fetch
-        // In production, you would call an oracle contract
+        // In production, you would call the `oracle` contract
         Ok(Decimal::from(1_000_000_000u64)) // Mock price
     }
     
@@ -683,38 +1739,51 @@ impl NovaLending {
         // Simplified token transfer to user - in production use CEP-18
         Ok(())
     }
-    
+
+    fn transfer_tokens_to(&self, _recipient: Address, _amount: U256) -> Result<(), LendingError> {
+        // Simplified token transfer to a third party - in production use CEP-18
+        Ok(())
+    }
+    
     fn calculate_market_value(
         &self, 
         amount: U256, 
         price: Decimal, 
         decimals: u8
     ) -> Result<Decimal, LendingError> {
-        let decimals_factor = 10u64
-            .checked_pow(decimals as u32)
-            .ok_or(LendingError::MathOverflow)?;
-            
-        let amount_decimal = Decimal::from(amount.as_u128());
-        amount_decimal
-            .try_mul(price)?
-            .try_div(Decimal::from(decimals_factor))
+        market_value(amount, price, decimals)
+    }
+    
+    /// Inverse of `calculate_market_value`: token amount worth `value`
+    fn calculate_token_amount(
+        &self,
+        value: Decimal,
+        price: Decimal,
+        decimals: u8
+    ) -> Result<U256, LendingError> {
+        let amount = token_amount(value, price, decimals)?.try_floor_u64()?;
+        Ok(amount.into())
     }
     
     fn calculate_withdraw_amount(
         &self,
         obligation: &Obligation,
-        reserve: &Reserve,
         collateral: &Collateral,
         collateral_amount: U256
     ) -> Result<U256, LendingError> {
         // Complex withdrawal calculation when user has borrows
         if obligation.deposited_value == Decimal::zero() {
+            // Collateral still held but refreshed to no value means the
+            // oracle priced it at zero
+            if obligation.deposits.iter().any(|c| !c.deposited_amount.is_zero()) {
+                return Err(LendingError::CollateralValueZero);
+            }
             return Err(LendingError::ObligationDepositsZero);
         }
 
-        let max_withdraw_value = obligation.max_withdraw_value(Rate::from_percent(
-            reserve.config.loan_to_value_ratio
-        ))?;
+        // Blended across every collateral, not just the one being withdrawn
+        let loan_to_value = obligation.weighted_avg_ltv(|key| self.reserves.get(key))?;
+        let max_withdraw_value = obligation.max_withdraw_value(loan_to_value)?;
 
         if max_withdraw_value == Decimal::zero() {
             return Err(LendingError::WithdrawTooLarge);
@@ -724,14 +1793,14 @@ impl NovaLending {
             let withdraw_value = max_withdraw_value.min(collateral.market_value);
             let withdraw_pct = withdraw_value.try_div(collateral.market_value)?;
             (withdraw_pct
-                .try_mul(Decimal::from(collateral.deposited_amount.as_u128()))?
+                .try_mul(Decimal::try_from_u256(collateral.deposited_amount)?)?
                 .try_floor_u64()?)
             .min(collateral.deposited_amount.as_u64())
             .into()
         } else {
             let withdraw_amount = collateral_amount.min(collateral.deposited_amount);
-            let withdraw_pct = Decimal::from(withdraw_amount.as_u128())
-                .try_div(Decimal::from(collateral.deposited_amount.as_u128()))?;
+            let withdraw_pct = Decimal::try_from_u256(withdraw_amount)?
+                .try_div(Decimal::try_from_u256(collateral.deposited_amount)?)?;
             let withdraw_value = collateral.market_value.try_mul(withdraw_pct)?;
             if withdraw_value > max_withdraw_value {
                 return Err(LendingError::WithdrawTooLarge);
@@ -746,6 +1815,279 @@ impl NovaLending {
         Ok(withdraw_amount)
     }
     
+    fn liquidate(
+        &mut self,
+        borrower: Address,
+        repay_reserve_key: Address,
+        withdraw_reserve_key: Address,
+        liquidity_amount: U256,
+        matured: bool
+    ) -> Result<(), LendingError> {
+        if liquidity_amount == U256::zero() {
+            return Err(LendingError::InvalidAmount);
+        }
+        // The bonus would come out of the protocol for the borrower's benefit
+        if self.env().caller() == borrower {
+            return Err(LendingError::SelfLiquidation);
+        }
+
+        let mut obligation = self.obligations.get(&borrower).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+            
+        let mut repay_reserve = self.reserves.get(&repay_reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+        let mut withdraw_reserve = self.reserves.get(&withdraw_reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+
+        let clock = self.env().get_block_time();
+        let stale_after = self.get_liquidation_stale_after_slots();
+        if repay_reserve.last_update.is_stale(clock, stale_after) || 
+           withdraw_reserve.last_update.is_stale(clock, stale_after) || 
+           obligation.last_update.is_stale(clock, stale_after) {
+            return Err(LendingError::ReserveStale);
+        }
+        // The cached values are only as current as the last refresh
+        let reserves_updated = repay_reserve.last_update.slot.max(withdraw_reserve.last_update.slot);
+        if !obligation.is_refreshed_since(reserves_updated) {
+            return Err(LendingError::ObligationStale);
+        }
+
+        let (liquidity, liquidity_index) = obligation.find_liquidity_in_borrows(repay_reserve_key)?;
+        let (collateral, collateral_index) = obligation.find_collateral_in_deposits(withdraw_reserve_key)?;
+
+        let CalculateLiquidationResult {
+            settle_amount,
+            repay_amount,
+            withdraw_amount,
+        } = if matured {
+            if liquidity.maturity_slot == 0 || clock <= liquidity.maturity_slot {
+                return Err(LendingError::LoanNotMatured);
+            }
+            withdraw_reserve.calculate_matured_liquidation(liquidity_amount, &liquidity, &collateral)?
+        } else {
+            self.check_liquidatable(&obligation, clock)?;
+            withdraw_reserve.calculate_liquidation(
+                liquidity_amount,
+                &obligation,
+                &liquidity,
+                &collateral,
+            )?
+        };
+
+        if repay_amount == U256::zero() || withdraw_amount == U256::zero() {
+            return Err(LendingError::LiquidationTooSmall);
+        }
+
+        repay_reserve.liquidity.repay_borrow(repay_amount, settle_amount, &liquidity)?;
+        repay_reserve.last_update.mark_stale();
+        
+        obligation.repay(settle_amount, liquidity_index)?;
+        obligation.withdraw(withdraw_amount, collateral_index)?;
+        obligation.last_update.mark_stale();
+
+        // With the last collateral seized, nothing backs what is still owed
+        if withdraw_amount == collateral.deposited_amount
+            && obligation.deposits.iter().all(|deposit| deposit.deposited_amount.is_zero()) {
+            for liquidity in obligation.borrows.iter() {
+                self.record_bad_debt(borrower, liquidity.borrow_reserve, liquidity.borrowed_amount_wads)?;
+            }
+        }
+        
+        let repaid_value = self.calculate_market_value(
+            repay_amount,
+            repay_reserve.liquidity.market_price,
+            repay_reserve.liquidity.mint_decimals
+        )?;
+        self.sync_repaid_exposure(borrower, &obligation, repaid_value)?;
+        self.reserves.set(&repay_reserve_key, repay_reserve);
+        self.reserves.set(&withdraw_reserve_key, withdraw_reserve);
+        self.obligations.set(&borrower, Some(obligation));
+        
+        // Transfer logic for liquidation
+        self.handle_liquidation_transfers(repay_amount, withdraw_amount)?;
+        
+        Ok(())
+    }
+    
+    fn record_bad_debt(&mut self, borrower: Address, reserve_key: Address, amount: Decimal) -> Result<(), LendingError> {
+        if amount == Decimal::zero() {
+            return Ok(());
+        }
+        let total = self.get_reserve_bad_debt(reserve_key).try_add(amount)?;
+        self.bad_debt.set(&reserve_key, total);
+        let mut borrowers = self.bad_debt_borrowers.get(&reserve_key).unwrap_or_default();
+        if !borrowers.contains(&borrower) {
+            borrowers.push(borrower);
+            self.bad_debt_borrowers.set(&reserve_key, borrowers);
+        }
+        self.env().emit_event(BadDebtRecorded {
+            reserve: reserve_key,
+            obligation_owner: borrower,
+            amount,
+            slot: self.env().get_block_time(),
+        });
+        Ok(())
+    }
+
+    fn add_obligation_owner(&mut self, owner: Address) {
+        if self.obligation_owner_index.get(&owner).flatten().is_none() {
+            self.obligation_owner_index.set(&owner, Some(self.obligation_owners.len()));
+            self.obligation_owners.push(owner);
+        }
+    }
+
+    /// Swap-removes `owner` from `obligation_owners`, keeping the index in step
+    fn remove_obligation_owner(&mut self, owner: Address) {
+        let Some(index) = self.obligation_owner_index.get(&owner).flatten() else {
+            return;
+        };
+        if let Some(last) = self.obligation_owners.pop() {
+            if last != owner {
+                self.obligation_owners.replace(index, last);
+                self.obligation_owner_index.set(&last, Some(index));
+            }
+        }
+        self.obligation_owner_index.set(&owner, None);
+    }
+
+    /// Borrow capacity of the obligation's deposits at their cached values,
+    /// with each reserve's `collateral_weight_cap` applied
+    fn allowed_borrow_value(&self, obligation: &Obligation) -> Result<Decimal, LendingError> {
+        let mut borrow_capacities = Vec::with_capacity(obligation.deposits.len());
+        for collateral in obligation.deposits.iter() {
+            let reserve = self.reserves.get(&collateral.deposit_reserve)
+                .ok_or(LendingError::InvalidAccountInput)?;
+            borrow_capacities.push((
+                collateral.market_value.try_mul(Rate::from_percent(reserve.config.loan_to_value_ratio))?,
+                reserve.config.collateral_weight_cap,
+            ));
+        }
+        capped_borrow_value(&borrow_capacities)
+    }
+
+    /// Fails unless the caller is the owner or holds `role`
+    fn check_role(&self, role: u8) -> Result<(), LendingError> {
+        if !self.has_role(self.env().caller(), role) {
+            return Err(LendingError::InvalidMarketOwner);
+        }
+        Ok(())
+    }
+
+    /// `borrowed_value` split over the oracles pricing the obligation's
+    /// remaining deposits, by each oracle's share of the deposited value
+    fn oracle_contributions(
+        &self,
+        obligation: &Obligation,
+        borrowed_value: Decimal
+    ) -> Result<Vec<(Address, Decimal)>, LendingError> {
+        let mut contributions: Vec<(Address, Decimal)> = Vec::new();
+        if obligation.deposited_value == Decimal::zero() || borrowed_value == Decimal::zero() {
+            return Ok(contributions);
+        }
+        for collateral in obligation.deposits.iter() {
+            if collateral.deposited_amount.is_zero() {
+                continue;
+            }
+            let Some(reserve) = self.reserves.get(&collateral.deposit_reserve) else {
+                continue;
+            };
+            let oracle = reserve.liquidity.oracle_pubkey;
+            match contributions.iter_mut().find(|(known, _)| *known == oracle) {
+                Some((_, value)) => *value = value.try_add(collateral.market_value)?,
+                None => contributions.push((oracle, collateral.market_value)),
+            }
+        }
+        for (_, value) in contributions.iter_mut() {
+            *value = borrowed_value.try_mul(value.try_div(obligation.deposited_value)?)?;
+        }
+        Ok(contributions)
+    }
+
+    /// Rejects `owner`'s new `contributions` if they push any oracle past
+    /// `max_oracle_exposure` percent of market TVL
+    fn check_oracle_exposure(&self, owner: Address, contributions: &[(Address, Decimal)]) -> Result<(), LendingError> {
+        let max_exposure = self.get_max_oracle_exposure();
+        if max_exposure == 0 {
+            return Ok(());
+        }
+
+        let limit = self.market_overview()?.total_value_locked.percent_of(max_exposure)?;
+        let previous = self.obligation_oracle_exposure.get(&owner).unwrap_or_default();
+        for (oracle, value) in contributions.iter() {
+            let own = previous.iter()
+                .find(|(known, _)| known == oracle)
+                .map_or(Decimal::zero(), |(_, own)| *own);
+            let exposure = self.oracle_exposure(*oracle).try_sub(own)?.try_add(*value)?;
+            if exposure > limit {
+                return Err(LendingError::OracleConcentrationLimit);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces `owner`'s shares of `oracle_exposures` with `contributions`
+    fn sync_oracle_exposure(&mut self, owner: Address, contributions: Vec<(Address, Decimal)>) -> Result<(), LendingError> {
+        for (oracle, value) in self.obligation_oracle_exposure.get(&owner).unwrap_or_default() {
+            let exposure = self.oracle_exposure(oracle).try_sub(value)?;
+            self.oracle_exposures.set(&oracle, exposure);
+        }
+        for (oracle, value) in contributions.iter() {
+            let exposure = self.oracle_exposure(*oracle).try_add(*value)?;
+            self.oracle_exposures.set(oracle, exposure);
+        }
+        self.obligation_oracle_exposure.set(&owner, contributions);
+        Ok(())
+    }
+
+    /// Shrinks `owner`'s exposure after a repay or liquidation settles
+    /// `repaid_value` of the debt the last refresh valued
+    fn sync_repaid_exposure(&mut self, owner: Address, obligation: &Obligation, repaid_value: Decimal) -> Result<(), LendingError> {
+        let borrowed_value = obligation.borrowed_value.try_sub(repaid_value).unwrap_or_else(|_| Decimal::zero());
+        let contributions = self.oracle_contributions(obligation, borrowed_value)?;
+        self.sync_oracle_exposure(owner, contributions)
+    }
+
+    fn check_not_paused(&self) -> Result<(), LendingError> {
+        if self.is_paused() {
+            return Err(LendingError::MarketPaused);
+        }
+        Ok(())
+    }
+
+    fn check_liquidatable(&self, obligation: &Obligation, clock: u64) -> Result<(), LendingError> {
+        if obligation.borrowed_value < obligation.unhealthy_borrow_value {
+            return Err(LendingError::ObligationHealthy);
+        }
+        // Ride out single-refresh oracle blips before liquidating
+        let grace = self.liquidation_grace_slots.get().unwrap_or(0);
+        let unhealthy_for = obligation.first_unhealthy_slot
+            .map(|slot| clock.saturating_sub(slot))
+            .unwrap_or(0);
+        if unhealthy_for < grace {
+            return Err(LendingError::LiquidationGracePeriod);
+        }
+        Ok(())
+    }
+
+    fn apply_config(&mut self, reserve_key: Address, new_config: ReserveConfig) -> Result<(), LendingError> {
+        let mut reserve = self.reserves.get(&reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+
+        // Validate reserve belongs to this lending market
+        reserve.ensure_market(self.env().self_address())?;
+
+        let old_config = core::mem::replace(&mut reserve.config, new_config.clone());
+        self.reserves.set(&reserve_key, reserve);
+
+        self.env().emit_event(ReserveConfigUpdated {
+            reserve: reserve_key,
+            old_config,
+            new_config,
+            slot: self.env().get_block_time(),
+        });
+        Ok(())
+    }
+    
     fn handle_liquidation_transfers(
         &self,
         _repay_amount: U256,
@@ -780,580 +2122,4491 @@ impl NovaLending {
     pub fn get_reserve(&self, reserve_key: Address) -> Option<Reserve> {
         self.reserves.get(&reserve_key)
     }
-    
-    pub fn get_obligation(&self, user_address: Address) -> Option<Obligation> {
-        self.obligations.get(&user_address)
+
+    /// Reserve collateral owned by the protocol through compounded fees
+    pub fn get_protocol_collateral(&self, reserve_key: Address) -> U256 {
+        self.protocol_collateral.get(&reserve_key).unwrap_or_default()
     }
-    
-    pub fn get_owner(&self) -> Option<Address> {
-        self.owner.get()
+
+    /// Unrecoverable debt the reserve has absorbed from liquidations
+    pub fn get_reserve_bad_debt(&self, reserve_key: Address) -> Decimal {
+        self.bad_debt.get(&reserve_key).unwrap_or_else(Decimal::zero)
     }
-    
-    pub fn get_reserve_count(&self) -> u64 {
-        self.reserve_count.get().unwrap_or(0)
+
+    /// Available liquidity valued in the quote currency at the last
+    /// refreshed price
+    pub fn get_reserve_available_value(&self, reserve_key: Address) -> Option<Decimal> {
+        let reserve = self.reserves.get(&reserve_key)?;
+        self.calculate_market_value(
+            reserve.liquidity.available_amount,
+            reserve.liquidity.market_price,
+            reserve.liquidity.mint_decimals
+        ).ok()
     }
-}
 
-// ===========================================================================
-// SUPPORTING STRUCTS AND IMPLEMENTATIONS
-// ===========================================================================
+    pub fn get_exchange_rate(&self, reserve_key: Address) -> Option<Decimal> {
+        self.reserves.get(&reserve_key)?
+            .collateral_exchange_rate()
+            .ok()
+    }
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct Reserve {
-    pub lending_market: Address,
-    pub liquidity: ReserveLiquidity,
-    pub collateral: ReserveCollateral,
-    pub config: ReserveConfig,
-    pub last_update: LastUpdate,
-}
+    pub fn collateral_to_liquidity(&self, reserve_key: Address, collateral_amount: U256) -> Option<U256> {
+        self.reserves.get(&reserve_key)?
+            .collateral_to_liquidity(collateral_amount)
+            .ok()
+    }
 
-impl Reserve {
-    pub fn new(params: InitReserveParams) -> Self {
-        Self {
-            lending_market: params.lending_market,
-            liquidity: params.liquidity,
-            collateral: params.collateral,
-            config: params.config,
-            last_update: LastUpdate {
-                slot: params.current_slot,
-                stale: false,
-            },
-        }
+    pub fn liquidity_to_collateral(&self, reserve_key: Address, liquidity_amount: U256) -> Option<U256> {
+        self.reserves.get(&reserve_key)?
+            .liquidity_to_collateral(liquidity_amount)
+            .ok()
     }
-    
-    pub fn deposit_liquidity(&mut self, amount: U256) -> Result<U256, LendingError> {
-        let exchange_rate = self.collateral_exchange_rate()?;
-        let collateral_amount = exchange_rate
-            .try_mul(Decimal::from(amount.as_u128()))?
-            .try_floor_u64()?;
 
-        self.liquidity.deposit(amount)?;
-        self.collateral.mint(collateral_amount.into())?;
+    pub fn set_reserve_fee_receiver(
+        &mut self,
+        reserve_key: Address,
+        new_receiver: Address
+    ) -> Result<(), LendingError> {
+        let caller = self.env().caller();
+        let current_owner = self.owner.get().unwrap();
+        
+        if caller != current_owner {
+            return Err(LendingError::InvalidMarketOwner);
+        }
+
+        if new_receiver == zero_address() {
+            return Err(LendingError::InvalidAccountInput);
+        }
+
+        let mut reserve = self.reserves.get(&reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+        reserve.ensure_market(self.env().self_address())?;
+
+        let old_receiver = core::mem::replace(&mut reserve.liquidity.fee_receiver, new_receiver);
+        self.reserves.set(&reserve_key, reserve);
 
-        Ok(collateral_amount.into())
+        self.env().emit_event(ReserveFeeReceiverUpdated {
+            reserve: reserve_key,
+            old_receiver,
+            new_receiver,
+            slot: self.env().get_block_time(),
+        });
+        Ok(())
     }
-    
-    pub fn redeem_collateral(&mut self, amount: U256) -> Result<U256, LendingError> {
-        let exchange_rate = self.collateral_exchange_rate()?;
-        let liquidity_amount = Decimal::from(amount.as_u128())
-            .try_div(exchange_rate)?
-            .try_floor_u64()?;
 
-        if liquidity_amount > self.liquidity.available_amount.as_u64() {
-            return Err(LendingError::InsufficientLiquidity);
+    pub fn verify_reserve(&self, reserve_key: Address) -> Result<(), LendingError> {
+        let written_off = self.socialized_bad_debt.get(&reserve_key).unwrap_or_else(Decimal::zero);
+        self.reserves.get(&reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?
+            .check_invariants(written_off)
+    }
+
+    /// Points a reserve at a replacement price feed whose raw prices carry
+    /// `price_decimals` decimals (18 for WAD feeds, 8 for Chainlink-style).
+    /// The reserve is marked stale so nothing uses the old price until the
+    /// next refresh.
+    pub fn set_reserve_oracle(
+        &mut self,
+        reserve_key: Address,
+        new_oracle: Address,
+        price_decimals: u8
+    ) -> Result<(), LendingError> {
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        if new_oracle == zero_address() || price_decimals > MAX_PRICE_DECIMALS {
+            return Err(LendingError::InvalidOracleConfig);
         }
 
-        self.collateral.burn(amount)?;
-        self.liquidity.withdraw(liquidity_amount.into())?;
+        let mut reserve = self.reserves.get(&reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+        reserve.ensure_market(self.env().self_address())?;
 
-        Ok(liquidity_amount.into())
+        reserve.liquidity.oracle_pubkey = new_oracle;
+        reserve.liquidity.price_decimals = price_decimals;
+        reserve.last_update.mark_stale();
+        self.reserves.set(&reserve_key, reserve);
+        Ok(())
     }
-    
-    pub fn accrue_interest(&mut self, _slot: u64) -> Result<(), LendingError> {
-        // Simplified interest accrual
-        // In production, implement compound interest calculation
+
+    /// Pushes a raw feed value for `oracle`, used by reserves quoting it
+    /// from their next refresh
+    pub fn set_oracle_price(&mut self, oracle: Address, raw_price: U256) -> Result<(), LendingError> {
+        self.check_role(ROLE_RISK_ADMIN)?;
+
+        if raw_price.is_zero() {
+            return Err(LendingError::InvalidOracleConfig);
+        }
+
+        self.oracle_prices.set(&oracle, Some(raw_price));
         Ok(())
     }
-    
-    pub fn calculate_borrow(
-        &self,
-        amount: U256,
-        remaining: Decimal
-    ) -> Result<CalculateBorrowResult, LendingError> {
-        let remaining_u64 = remaining.try_floor_u64()?;
-        let borrow_amount: U256 = if amount == U256::max_value() {
-            remaining_u64.into()
-        } else {
-            U256::min(amount, remaining_u64.into())
-        };
 
-        let borrow_fee = borrow_amount / 100; // 1% borrow fee
-        let host_fee = borrow_fee / 10; // 10% of borrow fee to host
-        let receive_amount = borrow_amount - borrow_fee;
+    pub fn get_reserve_config(&self, reserve_key: Address) -> Option<ReserveConfig> {
+        self.reserves.get(&reserve_key).map(|reserve| reserve.config)
+    }
 
-        Ok(CalculateBorrowResult {
-            borrow_amount: Decimal::from(borrow_amount.as_u128()),
-            receive_amount,
-            borrow_fee,
-            host_fee
-        })
+    pub fn get_reserve_fee_receiver(&self, reserve_key: Address) -> Option<Address> {
+        self.reserves.get(&reserve_key).map(|reserve| reserve.liquidity.fee_receiver)
     }
     
-    pub fn calculate_repay(
-        &self,
-        amount: U256,
-        borrowed: Decimal
-    ) -> Result<CalculateRepayResult, LendingError> {
-        let borrowed_u256 = borrowed.try_floor_u64()?;
-        let repay_amount: U256 = if amount == U256::max_value() {
-            borrowed_u256.into()
-        } else {
-            U256::min(amount, borrowed_u256.into())
-        };
+    pub fn get_reserve_key_by_underlying(&self, underlying: Address) -> Option<Address> {
+        self.underlying_to_reserve.get(&underlying)
+    }
+    
+    pub fn get_obligation(&self, user_address: Address) -> Option<Obligation> {
+        self.obligations.get(&user_address).flatten()
+    }
 
-        let settle_amount = Decimal::from(repay_amount.as_u128());
+    /// Deposits as (reserve, deposited amount, market value)
+    pub fn get_obligation_deposits(&self, user_address: Address) -> Vec<(Address, U256, Decimal)> {
+        self.obligations.get(&user_address).flatten()
+            .map(|obligation| obligation.deposits
+                .iter()
+                .map(|c| (c.deposit_reserve, c.deposited_amount, c.market_value))
+                .collect())
+            .unwrap_or_default()
+    }
 
-        Ok(CalculateRepayResult {
-            settle_amount,
-            repay_amount
-        })
+    /// Borrows as (reserve, borrowed amount in wads, market value)
+    pub fn get_obligation_borrows(&self, user_address: Address) -> Vec<(Address, Decimal, Decimal)> {
+        self.obligations.get(&user_address).flatten()
+            .map(|obligation| obligation.borrows
+                .iter()
+                .map(|l| (l.borrow_reserve, l.borrowed_amount_wads, l.market_value))
+                .collect())
+            .unwrap_or_default()
     }
-    
-    pub fn calculate_liquidation(
-        &self,
-        amount: U256,
-        obligation: &Obligation,
-        liquidity: &Liquidity,
-        collateral: &Collateral,
-    ) -> Result<CalculateLiquidationResult, LendingError> {
-        // Simplified liquidation calculation
-        let max_repay = obligation.borrowed_value.try_sub(obligation.unhealthy_borrow_value)?;
-        let repay_value = Decimal::from(amount.as_u128()).min(max_repay);
-        
-        let liquidation_premium = Rate::from_percent(105); // 5% liquidation premium
-        let withdraw_value = repay_value.try_mul(liquidation_premium)?;
-        
-        let repay_amount = repay_value.try_floor_u64()?;
-        let withdraw_amount = withdraw_value.try_div(collateral.market_value)?.try_floor_u64()?;
 
-        Ok(CalculateLiquidationResult {
-            settle_amount: repay_value,
-            repay_amount: repay_amount.into(),
-            withdraw_amount: withdraw_amount.into()
-        })
+    /// Reserves the user deposits in or borrows from, each once, deposits first
+    pub fn user_active_reserves(&self, user_address: Address) -> Vec<Address> {
+        let Some(obligation) = self.obligations.get(&user_address).flatten() else {
+            return Vec::new();
+        };
+        let mut reserves = Vec::with_capacity(obligation.deposits.len() + obligation.borrows.len());
+        let keys = obligation.deposits.iter().map(|c| c.deposit_reserve)
+            .chain(obligation.borrows.iter().map(|l| l.borrow_reserve));
+        for key in keys {
+            if !reserves.contains(&key) {
+                reserves.push(key);
+            }
+        }
+        reserves
     }
     
-    fn collateral_exchange_rate(&self) -> Result<Decimal, LendingError> {
-        if self.collateral.mint_total_supply.is_zero() {
-            return Ok(Decimal::one());
+    pub fn get_owner(&self) -> Option<Address> {
+        self.owner.get()
+    }
+    
+    pub fn get_obligation_count(&self) -> u32 {
+        self.obligation_owners.len()
+    }
+
+    /// Owners of open obligations, `limit` at a time starting at `offset`,
+    /// with the total number of owners
+    pub fn get_obligation_owners(&self, offset: u32, limit: u32) -> (Vec<Address>, u32) {
+        let total = self.obligation_owners.len();
+        let end = offset.saturating_add(limit).min(total);
+        let page = (offset..end)
+            .filter_map(|index| self.obligation_owners.get(index))
+            .collect();
+        (page, total)
+    }
+
+    pub fn get_reserve_count(&self) -> u64 {
+        self.reserve_count.get().unwrap_or(0)
+    }
+
+    /// Largest amount `user` can borrow from `borrow_reserve`, bounded by both
+    /// their remaining borrow value (as of the last refresh) and the reserve's
+    /// available liquidity.
+    pub fn max_borrowable(&self, user: Address, borrow_reserve: Address) -> Result<U256, LendingError> {
+        let obligation = self.obligations.get(&user).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+        let reserve = self.reserves.get(&borrow_reserve)
+            .ok_or(LendingError::InvalidAccountInput)?;
+
+        let collateral_limit = self.calculate_token_amount(
+            obligation.remaining_borrow_value()?,
+            reserve.liquidity.market_price,
+            reserve.liquidity.mint_decimals
+        )?;
+
+        Ok(collateral_limit.min(reserve.liquidity.available_amount))
+    }
+
+    /// Borrow rates of the obligation's reserves weighted by the market value
+    /// of each borrow (as of the last refresh). Zero without borrows.
+    pub fn obligation_blended_borrow_rate(&self, user: Address) -> Result<Rate, LendingError> {
+        let obligation = self.obligations.get(&user).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+
+        let mut total_value = Decimal::zero();
+        let mut weighted_rate = Decimal::zero();
+        for liquidity in obligation.borrows.iter() {
+            let reserve = self.reserves.get(&liquidity.borrow_reserve)
+                .ok_or(LendingError::InvalidAccountInput)?;
+            let borrow_rate = reserve.current_borrow_rate()?;
+
+            total_value = total_value.try_add(liquidity.market_value)?;
+            weighted_rate = weighted_rate.try_add(liquidity.market_value.try_mul(borrow_rate)?)?;
         }
-        
-        Decimal::from(self.liquidity.total_supply().as_u128())
-            .try_div(Decimal::from(self.collateral.mint_total_supply.as_u128()))
+
+        if total_value == Decimal::zero() {
+            return Ok(Rate::zero());
+        }
+        Ok(weighted_rate.try_div(total_value)?.to_rate())
+    }
+
+    /// Supply interest earned on deposits less interest paid on borrows, per
+    /// year, over the deposited value (all as of the last refresh). Stable
+    /// borrows pay their locked rate. Zero without deposits.
+    pub fn obligation_net_apy(&self, user: Address) -> Result<SignedDecimal, LendingError> {
+        let obligation = self.obligations.get(&user).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+        if obligation.deposited_value == Decimal::zero() {
+            return Ok(SignedDecimal::zero());
+        }
+
+        let mut earned = Decimal::zero();
+        for collateral in obligation.deposits.iter() {
+            let reserve = self.reserves.get(&collateral.deposit_reserve)
+                .ok_or(LendingError::InvalidAccountInput)?;
+            earned = earned.try_add(collateral.market_value.try_mul(reserve.current_supply_rate()?)?)?;
+        }
+
+        let mut paid = Decimal::zero();
+        for liquidity in obligation.borrows.iter() {
+            let borrow_rate = match liquidity.rate_mode {
+                RateMode::Stable => liquidity.stable_borrow_rate,
+                RateMode::Variable => self.reserves.get(&liquidity.borrow_reserve)
+                    .ok_or(LendingError::InvalidAccountInput)?
+                    .current_borrow_rate()?,
+            };
+            paid = paid.try_add(liquidity.market_value.try_mul(borrow_rate)?)?;
+        }
+
+        SignedDecimal::difference(earned, paid).try_div(obligation.deposited_value)
+    }
+
+    /// Interest the user's borrow from `reserve_key` has built up since it
+    /// was last settled, as a refresh of the reserve and obligation now
+    /// would add it
+    pub fn pending_interest(&self, user: Address, reserve_key: Address) -> Result<Decimal, LendingError> {
+        let obligation = self.obligations.get(&user).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+        let reserve = self.reserves.get(&reserve_key)
+            .ok_or(LendingError::InvalidAccountInput)?;
+        let (mut liquidity, _) = obligation.find_liquidity_in_borrows(reserve_key)?;
+
+        let clock = self.env().get_block_time();
+        let previous_borrowed = liquidity.borrowed_amount_wads;
+        liquidity.accrue_interest(reserve.projected_cumulative_borrow_rate(clock)?, clock)?;
+        liquidity.borrowed_amount_wads.try_sub(previous_borrowed)
+    }
+
+    /// Reserve keys in creation order, `limit` at a time starting at
+    /// `offset`, with the total number of reserves
+    pub fn get_all_reserves(&self, offset: u32, limit: u32) -> (Vec<Address>, u32) {
+        let total = self.reserve_keys.len();
+        let end = offset.saturating_add(limit).min(total);
+        let page = (offset..end)
+            .filter_map(|index| self.reserve_keys.get(index))
+            .collect();
+        (page, total)
+    }
+
+    /// Market-wide counts and totals in one call. Values are in the quote
+    /// currency at each reserve's last refreshed price; locked value is the
+    /// liquidity owed to suppliers.
+    pub fn market_overview(&self) -> Result<MarketOverview, LendingError> {
+        let mut total_value_locked = Decimal::zero();
+        let mut total_borrowed = Decimal::zero();
+        for index in 0..self.reserve_keys.len() {
+            let Some(reserve) = self.reserve_keys.get(index).and_then(|key| self.reserves.get(&key)) else {
+                continue;
+            };
+            let liquidity = &reserve.liquidity;
+            total_value_locked = total_value_locked.try_add(
+                market_value(liquidity.total_supply(), liquidity.market_price, liquidity.mint_decimals)?
+            )?;
+            total_borrowed = total_borrowed.try_add(market_value(
+                liquidity.borrowed_amount_wads.try_floor_u256()?,
+                liquidity.market_price,
+                liquidity.mint_decimals
+            )?)?;
+        }
+
+        Ok(MarketOverview {
+            reserve_count: self.get_reserve_count(),
+            obligation_count: self.get_obligation_count(),
+            total_value_locked,
+            total_borrowed,
+            total_protocol_fees: self.total_protocol_fees()?,
+        })
+    }
+
+    /// Borrowed value backed by collateral that `oracle` prices. Each
+    /// obligation's debt is split over its deposits by value, as of its last
+    /// refresh, borrow, repay or liquidation.
+    pub fn oracle_exposure(&self, oracle: Address) -> Decimal {
+        self.oracle_exposures.get(&oracle).unwrap_or_else(Decimal::zero)
+    }
+
+    /// Protocol fees accrued across every reserve, valued in the quote
+    /// currency at each reserve's last refreshed price
+    pub fn total_protocol_fees(&self) -> Result<Decimal, LendingError> {
+        let mut total = Decimal::zero();
+        for index in 0..self.reserve_keys.len() {
+            let Some(reserve) = self.reserve_keys.get(index).and_then(|key| self.reserves.get(&key)) else {
+                continue;
+            };
+            let fees_value = market_value_wads(
+                reserve.liquidity.accumulated_protocol_fees_wads,
+                reserve.liquidity.market_price,
+                reserve.liquidity.mint_decimals
+            )?;
+            total = total.try_add(fees_value)?;
+        }
+        Ok(total)
+    }
+
+    /// Health factor as of the last refresh; None without borrows
+    pub fn get_obligation_health(&self, user: Address) -> Option<Decimal> {
+        self.obligations.get(&user).flatten()?
+            .health_factor()
+            .ok()
+            .flatten()
+    }
+
+    /// (tokens repaid, collateral received including the bonus) for
+    /// liquidating `amount` of the borrower's debt, computed as
+    /// `liquidate_obligation` would from the last refresh's prices but
+    /// without its health or freshness checks. Lets bots rank opportunities.
+    pub fn estimate_liquidation_reward(
+        &self,
+        borrower: Address,
+        repay_reserve_key: Address,
+        withdraw_reserve_key: Address,
+        amount: U256
+    ) -> Option<(U256, U256)> {
+        let obligation = self.obligations.get(&borrower).flatten()?;
+        let withdraw_reserve = self.reserves.get(&withdraw_reserve_key)?;
+        let (liquidity, _) = obligation.find_liquidity_in_borrows(repay_reserve_key).ok()?;
+        let (collateral, _) = obligation.find_collateral_in_deposits(withdraw_reserve_key).ok()?;
+
+        let result = withdraw_reserve
+            .calculate_liquidation(amount, &obligation, &liquidity, &collateral)
+            .ok()?;
+        Some((result.repay_amount, result.withdraw_amount))
+    }
+
+    /// Whether the obligation can be liquidated now, by the same health and
+    /// grace-period checks liquidation runs. Errors with `ReserveStale` or
+    /// `ObligationStale` when a reserve or the obligation needs a refresh
+    /// first.
+    pub fn is_liquidatable(&self, user: Address) -> Result<bool, LendingError> {
+        let obligation = self.obligations.get(&user).flatten()
+            .ok_or(LendingError::InvalidObligationOwner)?;
+
+        let clock = self.env().get_block_time();
+        let stale_after = self.get_liquidation_stale_after_slots();
+        if obligation.last_update.is_stale(clock, stale_after) {
+            return Err(LendingError::ObligationStale);
+        }
+        let reserve_keys = obligation.deposits.iter().map(|collateral| collateral.deposit_reserve)
+            .chain(obligation.borrows.iter().map(|liquidity| liquidity.borrow_reserve));
+        let mut reserves_updated = 0;
+        for reserve_key in reserve_keys {
+            let reserve = self.reserves.get(&reserve_key)
+                .ok_or(LendingError::InvalidAccountInput)?;
+            if reserve.last_update.is_stale(clock, stale_after) {
+                return Err(LendingError::ReserveStale);
+            }
+            reserves_updated = reserves_updated.max(reserve.last_update.slot);
+        }
+        if !obligation.is_refreshed_since(reserves_updated) {
+            return Err(LendingError::ObligationStale);
+        }
+
+        Ok(obligation.borrowed_value > Decimal::zero()
+            && self.check_liquidatable(&obligation, clock).is_ok())
+    }
+
+    /// (current, allowed) loan-to-value as of the last refresh
+    pub fn get_obligation_ltv(&self, user: Address) -> Option<(Decimal, Decimal)> {
+        let obligation = self.obligations.get(&user).flatten()?;
+        Some((
+            obligation.loan_to_value().ok()?,
+            obligation.allowed_loan_to_value().ok()?,
+        ))
+    }
+
+    /// Collateral value the user can lose before becoming liquidatable, as of
+    /// the last refresh: deposited value less borrowed value over the blended
+    /// liquidation threshold. Zero once liquidatable.
+    pub fn liquidation_price_buffer(&self, user: Address) -> Option<Decimal> {
+        let obligation = self.obligations.get(&user).flatten()?;
+        if obligation.borrowed_value >= obligation.unhealthy_borrow_value {
+            return Some(Decimal::zero());
+        }
+        // borrowed / (unhealthy / deposited), kept in one division
+        let liquidatable_at = obligation.borrowed_value
+            .try_mul(obligation.deposited_value).ok()?
+            .try_div(obligation.unhealthy_borrow_value).ok()?;
+        obligation.deposited_value.try_sub(liquidatable_at).ok()
+    }
+
+    /// Owners of obligations whose health factor (as of their last refresh)
+    /// is below `health_threshold`. Obligations without borrows are skipped.
+    ///
+    /// Only the `limit` owners starting at `offset` are checked, so gas stays
+    /// bounded; the total is the number of owners to page through, not the
+    /// number at risk.
+    pub fn get_obligations_at_risk(
+        &self,
+        health_threshold: Decimal,
+        offset: u32,
+        limit: u32
+    ) -> (Vec<Address>, u32) {
+        let total = self.obligation_owners.len();
+        let end = offset.saturating_add(limit).min(total);
+        let page = (offset..end)
+            .filter_map(|index| self.obligation_owners.get(index))
+            .filter(|owner| {
+                self.obligations.get(owner).flatten()
+                    .and_then(|obligation| obligation.health_factor().ok().flatten())
+                    .map_or(false, |health_factor| health_factor < health_threshold)
+            })
+            .collect();
+        (page, total)
+    }
+
+    pub fn get_stale_after_slots(&self) -> u64 {
+        self.stale_after_slots.get().unwrap_or(DEFAULT_STALE_AFTER_SLOTS)
+    }
+
+    /// Never stricter than `get_stale_after_slots`, which may have been
+    /// raised since the liquidation threshold was set
+    pub fn get_liquidation_stale_after_slots(&self) -> u64 {
+        self.liquidation_stale_after_slots.get()
+            .unwrap_or(0)
+            .max(self.get_stale_after_slots())
+    }
+
+    pub fn get_max_reserves(&self) -> u64 {
+        self.max_reserves.get().unwrap_or(DEFAULT_MAX_RESERVES)
+    }
+
+    pub fn get_max_oracle_exposure(&self) -> u8 {
+        self.max_oracle_exposure.get().unwrap_or(0)
     }
 }
 
+// ===========================================================================
+// SUPPORTING STRUCTS AND IMPLEMENTATIONS
+// ===========================================================================
+
 #[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct Obligation {
+pub struct Reserve {
     pub lending_market: Address,
-    pub owner: Address,
-    pub deposits: Vec<Collateral>,
-    pub borrows: Vec<Liquidity>,
-    pub deposited_value: Decimal,
-    pub borrowed_value: Decimal,
-    pub allowed_borrow_value: Decimal,
-    pub unhealthy_borrow_value: Decimal,
+    pub liquidity: ReserveLiquidity,
+    pub collateral: ReserveCollateral,
+    pub config: ReserveConfig,
+    /// Price freshness; see `last_accrual_slot` for interest
     pub last_update: LastUpdate,
+    /// Slot interest was last compounded at
+    pub last_accrual_slot: u64,
 }
 
-impl Obligation {
-    pub fn new(params: InitObligationParams) -> Self {
+impl Reserve {
+    pub fn new(params: InitReserveParams) -> Self {
         Self {
             lending_market: params.lending_market,
-            owner: params.owner,
-            deposits: params.deposits,
-            borrows: params.borrows,
-            deposited_value: Decimal::zero(),
-            borrowed_value: Decimal::zero(),
-            allowed_borrow_value: Decimal::zero(),
-            unhealthy_borrow_value: Decimal::zero(),
+            liquidity: params.liquidity,
+            collateral: params.collateral,
+            config: params.config,
             last_update: LastUpdate {
                 slot: params.current_slot,
                 stale: false,
             },
+            last_accrual_slot: params.current_slot,
         }
     }
     
-    pub fn find_or_add_collateral_to_deposits(
-        &mut self,
-        reserve: Address
-    ) -> Result<&mut Collateral, LendingError> {
-        let has_collateral = self.deposits
-            .iter_mut()
-            .any(|c| c.deposit_reserve == reserve);
+    pub fn deposit_liquidity(&mut self, amount: TokenAmount) -> Result<TokenAmount, LendingError> {
+        let exchange_rate = self.collateral_exchange_rate()?;
+        let amount_wads = Decimal::try_from_u256(amount.0)?;
+        let deposit_fee = self.config.calculate_deposit_fee(amount.0)?;
+        let collateral_amount = amount_wads
+            .try_sub(deposit_fee)?
+            .try_div(exchange_rate)?
+            .try_floor_u256()?;
 
-        if !has_collateral {
-            self.deposits.push(Collateral {
-                deposit_reserve: reserve,
-                deposited_amount: U256::zero(),
-                market_value: Decimal::zero()
-            });
-        }
+        self.liquidity.deposit(amount.0)?;
+        self.liquidity.accumulated_protocol_fees_wads = self.liquidity.accumulated_protocol_fees_wads
+            .try_add(deposit_fee)?;
+        self.collateral.mint(collateral_amount)?;
 
-        Ok(self.deposits
-            .iter_mut()
-            .find(|c| c.deposit_reserve == reserve)
-            .unwrap())
+        Ok(TokenAmount(collateral_amount))
     }
     
-    pub fn find_collateral_in_deposits(&self, reserve: Address) -> Result<(Collateral, usize), LendingError> {
-        for (index, collateral) in self.deposits.iter().enumerate() {
-            if collateral.deposit_reserve == reserve {
-                return Ok((collateral.clone(), index));
-            }
+    /// Accounting invariants for monitoring: no borrows without collateral
+    /// supply, protocol fees covered by liquidity, and an exchange rate that
+    /// never drops below the initial 1:1 other than by `written_off`, the
+    /// bad debt suppliers have absorbed
+    pub fn check_invariants(&self, written_off: Decimal) -> Result<(), LendingError> {
+        if self.collateral.mint_total_supply.is_zero()
+            && self.liquidity.borrowed_amount_wads != Decimal::zero() {
+            return Err(LendingError::ReserveInvariantViolated);
         }
-        Err(LendingError::ObligationCollateralEmpty)
-    }
-    
-    pub fn find_or_add_liquidity_to_borrows(
-        &mut self,
-        reserve: Address
-    ) -> Result<&mut Liquidity, LendingError> {
-        let has_liquidity = self.borrows.iter().any(|l| l.borrow_reserve == reserve);
 
-        if !has_liquidity {
-            // Add new liquidity
-            self.borrows.push(Liquidity {
-                borrow_reserve: reserve,
-                borrowed_amount_wads: Decimal::zero(),
-                market_value: Decimal::zero(),
-                cumulative_borrow_rate_wads: Decimal::one()
-            });
-        }
+        // Underflows when the protocol's fees exceed the reserve's liquidity
+        self.liquidity.supply_wads()
+            .map_err(|_| LendingError::ReserveInvariantViolated)?;
 
-        Ok(self.borrows
-            .iter_mut()
-            .find(|l| l.borrow_reserve == reserve)
-            .unwrap())
-    }
-    
-    pub fn find_liquidity_in_borrows(&self, reserve: Address) -> Result<(Liquidity, usize), LendingError> {
-        for (index, liquidity) in self.borrows.iter().enumerate() {
-            if liquidity.borrow_reserve == reserve {
-                return Ok((liquidity.clone(), index));
-            }
+        self.collateral_exchange_rate()
+            .map_err(|_| LendingError::ReserveInvariantViolated)?;
+        let backing = Decimal::try_from_u256(self.liquidity.total_supply())?.try_add(written_off)?;
+        if backing < Decimal::try_from_u256(self.collateral.mint_total_supply)? {
+            return Err(LendingError::ReserveInvariantViolated);
         }
-        Err(LendingError::ObligationLiquidityEmpty)
+        Ok(())
     }
-    
-    pub fn withdraw(&mut self, amount: U256, index: usize) -> Result<(), LendingError> {
-        if index >= self.deposits.len() {
+
+    pub fn ensure_market(&self, lending_market: Address) -> Result<(), LendingError> {
+        if self.lending_market != lending_market {
             return Err(LendingError::InvalidAccountInput);
         }
-        
-        if amount > self.deposits[index].deposited_amount {
-            return Err(LendingError::WithdrawTooLarge);
-        }
-        
-        self.deposits[index].deposited_amount = self.deposits[index].deposited_amount - amount;
         Ok(())
     }
-    
-    pub fn repay(&mut self, amount: Decimal, index: usize) -> Result<(), LendingError> {
-        if index >= self.borrows.len() {
-            return Err(LendingError::InvalidAccountInput);
+
+    /// Liquidity `amount` of collateral redeems for at the current exchange
+    /// rate, before any withdraw fee
+    pub fn collateral_to_liquidity(&self, amount: U256) -> Result<U256, LendingError> {
+        let exchange_rate = self.collateral_exchange_rate()?;
+        Decimal::try_from_u256(amount)?
+            .try_mul(exchange_rate)?
+            .try_floor_u256()
+    }
+
+    /// Collateral `amount` of liquidity mints at the current exchange rate,
+    /// before any deposit fee
+    pub fn liquidity_to_collateral(&self, amount: U256) -> Result<U256, LendingError> {
+        let exchange_rate = self.collateral_exchange_rate()?;
+        Decimal::try_from_u256(amount)?
+            .try_div(exchange_rate)?
+            .try_floor_u256()
+    }
+
+    pub fn redeem_collateral(&mut self, amount: U256) -> Result<U256, LendingError> {
+        let liquidity_amount = self.collateral_to_liquidity(amount)?;
+        if liquidity_amount > U256::from(u64::MAX) {
+            return Err(LendingError::MathOverflow);
         }
-        
-        if amount > self.borrows[index].borrowed_amount_wads {
-            return Err(LendingError::RepayTooSmall);
+        let liquidity_amount = liquidity_amount.as_u64();
+
+        if liquidity_amount > self.liquidity.available_amount.as_u64() {
+            return Err(LendingError::InsufficientLiquidity);
         }
-        
-        self.borrows[index].borrowed_amount_wads = self.borrows[index].borrowed_amount_wads.try_sub(amount)?;
-        Ok(())
+
+        // The fee (and rounding dust) stays in the reserve, booked to the protocol
+        let liquidity_wads = Decimal::from(liquidity_amount);
+        let withdraw_fee = self.config.fees.calculate_withdraw_fee(liquidity_wads)?;
+        let payout = liquidity_wads.try_sub(withdraw_fee)?.try_floor_u64()?;
+
+        self.collateral.burn(amount)?;
+        self.liquidity.withdraw(payout.into())?;
+        self.liquidity.accumulated_protocol_fees_wads = self.liquidity.accumulated_protocol_fees_wads
+            .try_add(liquidity_wads.try_sub(Decimal::from(payout))?)?;
+
+        Ok(payout.into())
     }
     
-    pub fn remaining_borrow_value(&self) -> Result<Decimal, LendingError> {
-        if self.borrowed_value >= self.allowed_borrow_value {
-            return Ok(Decimal::zero());
+    /// `cumulative_borrow_rate_wads` as `accrue_interest` would leave it at
+    /// `current_slot`, without applying it
+    pub fn projected_cumulative_borrow_rate(&self, current_slot: u64) -> Result<Decimal, LendingError> {
+        let slots_elapsed = current_slot
+            .checked_sub(self.last_accrual_slot)
+            .ok_or(LendingError::MathOverflow)?;
+        let cumulative_borrow_rate = self.liquidity.cumulative_borrow_rate_wads;
+        if slots_elapsed == 0 || self.liquidity.borrowed_amount_wads == Decimal::zero() {
+            return Ok(cumulative_borrow_rate);
         }
-        self.allowed_borrow_value.try_sub(self.borrowed_value)
+
+        let growth = interest_growth(
+            self.current_borrow_rate()?,
+            slots_elapsed,
+            self.config.linear_accrual_max_slots,
+        )?;
+        cumulative_borrow_rate.try_mul_round(growth.to_decimal())
     }
-    
-    pub fn max_withdraw_value(&self, rate: Rate) -> Result<Decimal, LendingError> {
-        if self.borrows.is_empty() {
-            return Ok(self.deposited_value);
+
+    /// Compounds interest for the slots elapsed since the last accrual
+    pub fn accrue_interest(&mut self, current_slot: u64) -> Result<(), LendingError> {
+        let slots_elapsed = current_slot
+            .checked_sub(self.last_accrual_slot)
+            .ok_or(LendingError::MathOverflow)?;
+
+        // Nothing to compound without borrows; skip the rate math entirely
+        if self.liquidity.borrowed_amount_wads == Decimal::zero() {
+            self.last_accrual_slot = current_slot;
+            return Ok(());
         }
-        
-        let available_value = self.deposited_value.try_mul(rate)?;
-        if available_value <= self.borrowed_value {
+
+        if slots_elapsed > 0 {
+            let current_borrow_rate = self.current_borrow_rate()?;
+            self.liquidity.compound_interest(
+                current_borrow_rate,
+                slots_elapsed,
+                self.config.reserve_factor,
+                self.config.linear_accrual_max_slots,
+            )?;
+            self.last_accrual_slot = current_slot;
+        }
+        Ok(())
+    }
+
+    /// Annual rate earned by suppliers: the borrow rate on the utilized share,
+    /// net of the reserve factor
+    pub fn current_supply_rate(&self) -> Result<Rate, LendingError> {
+        let supplier_share = Rate::one().try_sub(Rate::from_percent(self.config.reserve_factor))?;
+        self.current_borrow_rate()?
+            .try_mul(self.liquidity.utilization_rate()?)?
+            .try_mul(supplier_share)
+    }
+
+    /// Annual borrow rate from the utilization curve
+    pub fn current_borrow_rate(&self) -> Result<Rate, LendingError> {
+        let utilization_rate = self.liquidity.utilization_rate()?;
+        let optimal_utilization_rate = Rate::from_percent(self.config.optimal_utilization_rate);
+        let low_utilization = utilization_rate < optimal_utilization_rate;
+
+        if low_utilization || self.config.optimal_utilization_rate == 100 {
+            let normalized_rate = utilization_rate.try_div(optimal_utilization_rate)?;
+            let min_rate = Rate::from_percent(self.config.min_borrow_rate);
+            let rate_range = Rate::from_percent(
+                self.config.optimal_borrow_rate
+                    .checked_sub(self.config.min_borrow_rate)
+                    .ok_or(LendingError::InvalidConfig)?
+            );
+            normalized_rate.try_mul(rate_range)?.try_add(min_rate)
+        } else {
+            let normalized_rate = utilization_rate
+                .try_sub(optimal_utilization_rate)?
+                .try_div(Rate::from_percent(100 - self.config.optimal_utilization_rate))?;
+            let optimal_rate = Rate::from_percent(self.config.optimal_borrow_rate);
+            let rate_range = Rate::from_percent(
+                self.config.max_borrow_rate
+                    .checked_sub(self.config.optimal_borrow_rate)
+                    .ok_or(LendingError::InvalidConfig)?
+            );
+            normalized_rate.try_mul(rate_range)?.try_add(optimal_rate)
+        }
+    }
+    
+    pub fn calculate_borrow(
+        &self,
+        amount: U256,
+        remaining: Decimal
+    ) -> Result<CalculateBorrowResult, LendingError> {
+        // Stays in U256 so large-supply tokens aren't capped at u64::MAX
+        let remaining_amount = remaining.try_floor_u256()?;
+        let borrow_amount: U256 = if amount == U256::max_value() {
+            remaining_amount
+        } else {
+            U256::min(amount, remaining_amount)
+        };
+        let borrow_amount_wads = Decimal::try_from_u256(borrow_amount)?;
+
+        let borrow_fee = borrow_amount_wads.percent_of(1)?; // 1% borrow fee
+        let host_fee = borrow_fee.percent_of(10)?; // 10% of borrow fee to host
+        let borrow_fee = borrow_fee.try_floor_u256()?;
+        let host_fee = host_fee.try_floor_u256()?;
+        let receive_amount = borrow_amount.try_sub(borrow_fee)?;
+
+        Ok(CalculateBorrowResult {
+            borrow_amount: borrow_amount_wads,
+            receive_amount,
+            borrow_fee,
+            host_fee
+        })
+    }
+    
+    pub fn calculate_repay(
+        &self,
+        amount: U256,
+        borrowed: Decimal
+    ) -> Result<CalculateRepayResult, LendingError> {
+        let borrowed_u256 = borrowed.try_floor_u64()?;
+        let repay_amount: U256 = if amount == U256::max_value() {
+            borrowed_u256.into()
+        } else {
+            U256::min(amount, borrowed_u256.into())
+        };
+
+        let settle_amount = Decimal::try_from_u256(repay_amount)?;
+
+        Ok(CalculateRepayResult {
+            settle_amount,
+            repay_amount
+        })
+    }
+    
+    pub fn calculate_liquidation(
+        &self,
+        amount: U256,
+        obligation: &Obligation,
+        liquidity: &Liquidity,
+        collateral: &Collateral,
+    ) -> Result<CalculateLiquidationResult, LendingError> {
+        // Simplified liquidation calculation
+        let max_repay = obligation.borrowed_value.try_sub(obligation.unhealthy_borrow_value)?;
+        let repay_value = Decimal::try_from_u256(amount)?.min(max_repay);
+        let bonus = self.liquidation_bonus_rate(obligation)?;
+        self.liquidation_result(repay_value, collateral, bonus)
+    }
+
+    /// With `dynamic_liquidation_bonus`, scales linearly from
+    /// `liquidation_bonus` at the liquidation threshold to
+    /// `max_liquidation_bonus` once borrows reach the deposited value.
+    /// Otherwise the fixed `liquidation_bonus`.
+    pub fn liquidation_bonus_rate(&self, obligation: &Obligation) -> Result<Rate, LendingError> {
+        let min_bonus = Rate::from_percent(self.config.liquidation_bonus);
+        if !self.config.dynamic_liquidation_bonus {
+            return Ok(min_bonus);
+        }
+        let max_bonus = Rate::from_percent(self.config.max_liquidation_bonus);
+
+        let unhealthy = obligation.unhealthy_borrow_value;
+        let severity = if obligation.borrowed_value <= unhealthy {
+            Rate::zero()
+        } else if obligation.deposited_value <= unhealthy {
+            Rate::one()
+        } else {
+            let excess = obligation.borrowed_value.try_sub(unhealthy)?;
+            let range = obligation.deposited_value.try_sub(unhealthy)?;
+            excess.try_div(range)?.to_rate().min(Rate::one())
+        };
+
+        max_bonus
+            .try_sub(min_bonus)?
+            .try_mul(severity)?
+            .try_add(min_bonus)
+    }
+
+    /// Matured fixed-term loans can be closed out in full, so the repay is
+    /// bounded by the borrow itself rather than by the unhealthy excess.
+    pub fn calculate_matured_liquidation(
+        &self,
+        amount: U256,
+        liquidity: &Liquidity,
+        collateral: &Collateral,
+    ) -> Result<CalculateLiquidationResult, LendingError> {
+        let repay_value = Decimal::try_from_u256(amount)?.min(liquidity.borrowed_amount_wads);
+        let bonus = Rate::from_percent(self.config.liquidation_bonus);
+        self.liquidation_result(repay_value, collateral, bonus)
+    }
+
+    fn liquidation_result(
+        &self,
+        repay_value: Decimal,
+        collateral: &Collateral,
+        bonus: Rate,
+    ) -> Result<CalculateLiquidationResult, LendingError> {
+        let withdraw_value = repay_value.try_add(repay_value.try_mul(bonus)?)?;
+        
+        let repay_amount = repay_value.try_floor_u64()?;
+        // Seize the share of the position that the premium-adjusted value represents
+        let withdraw_pct = withdraw_value.try_div(collateral.market_value)?;
+        let withdraw_amount = withdraw_pct
+            .try_mul(Decimal::try_from_u256(collateral.deposited_amount)?)?
+            .try_floor_u64()?
+            .min(collateral.deposited_amount.as_u64());
+
+        Ok(CalculateLiquidationResult {
+            settle_amount: repay_value,
+            repay_amount: repay_amount.into(),
+            withdraw_amount: withdraw_amount.into()
+        })
+    }
+    
+    /// Moves accrued protocol fees into the supply at the current exchange
+    /// rate, minting the collateral they buy. The fee liquidity is already
+    /// held by the reserve, so `available_amount` is unchanged.
+    pub fn compound_protocol_fees(&mut self) -> Result<U256, LendingError> {
+        let exchange_rate = self.collateral_exchange_rate()?;
+        let collateral_amount = self.liquidity.accumulated_protocol_fees_wads
+            .try_div(exchange_rate)?
+            .try_floor_u256()?;
+
+        self.liquidity.accumulated_protocol_fees_wads = Decimal::zero();
+        self.collateral.mint(collateral_amount)?;
+        Ok(collateral_amount)
+    }
+
+    /// Liquidity backing each unit of collateral
+    pub fn collateral_exchange_rate(&self) -> Result<Decimal, LendingError> {
+        if self.collateral.mint_total_supply.is_zero() {
+            // Liquidity without collateral (e.g. donated fees) would be
+            // captured entirely by the first depositor
+            if !self.liquidity.total_supply().is_zero() {
+                return Err(LendingError::InvalidAccountInput);
+            }
+            return Ok(Decimal::one());
+        }
+        
+        Decimal::from_fraction(self.liquidity.total_supply(), self.collateral.mint_total_supply)
+    }
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct Obligation {
+    pub lending_market: Address,
+    pub owner: Address,
+    pub deposits: Vec<Collateral>,
+    pub borrows: Vec<Liquidity>,
+    pub deposited_value: Decimal,
+    pub borrowed_value: Decimal,
+    pub allowed_borrow_value: Decimal,
+    pub unhealthy_borrow_value: Decimal,
+    pub last_update: LastUpdate,
+    /// Refresh slot at which the obligation most recently became unhealthy
+    pub first_unhealthy_slot: Option<u64>,
+    /// Slot of the most recent collateral deposit
+    pub last_deposit_slot: u64,
+    pub version: u8,
+}
+
+/// Obligation layout before the unhealthy and deposit slot markers
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct ObligationV1 {
+    pub lending_market: Address,
+    pub owner: Address,
+    pub deposits: Vec<Collateral>,
+    pub borrows: Vec<Liquidity>,
+    pub deposited_value: Decimal,
+    pub borrowed_value: Decimal,
+    pub allowed_borrow_value: Decimal,
+    pub unhealthy_borrow_value: Decimal,
+    pub last_update: LastUpdate,
+}
+
+impl ObligationV1 {
+    /// Current layout with the new fields at their defaults. The cached
+    /// values are kept but marked stale so the next use refreshes them.
+    pub fn upgrade(self) -> Obligation {
+        let mut last_update = self.last_update;
+        last_update.mark_stale();
+        Obligation {
+            lending_market: self.lending_market,
+            owner: self.owner,
+            deposits: self.deposits,
+            borrows: self.borrows,
+            deposited_value: self.deposited_value,
+            borrowed_value: self.borrowed_value,
+            allowed_borrow_value: self.allowed_borrow_value,
+            unhealthy_borrow_value: self.unhealthy_borrow_value,
+            last_update,
+            first_unhealthy_slot: None,
+            last_deposit_slot: 0,
+            version: OBLIGATION_VERSION,
+        }
+    }
+}
+
+impl Obligation {
+    pub fn new(params: InitObligationParams) -> Self {
+        Self {
+            lending_market: params.lending_market,
+            owner: params.owner,
+            deposits: params.deposits,
+            borrows: params.borrows,
+            deposited_value: Decimal::zero(),
+            borrowed_value: Decimal::zero(),
+            allowed_borrow_value: Decimal::zero(),
+            unhealthy_borrow_value: Decimal::zero(),
+            last_update: LastUpdate {
+                slot: params.current_slot,
+                stale: false,
+            },
+            first_unhealthy_slot: None,
+            last_deposit_slot: 0,
+            version: OBLIGATION_VERSION,
+        }
+    }
+
+    /// Rebuilds every position's market value and the aggregates from
+    /// scratch, dropping positions whose reserve `get_reserve` can't find.
+    /// Returns the reserves of the dropped positions.
+    pub fn recompute_aggregates<F>(
+        &mut self,
+        current_slot: u64,
+        get_reserve: F
+    ) -> Result<Vec<Address>, LendingError>
+    where
+        F: Fn(&Address) -> Option<Reserve>,
+    {
+        let mut dropped = Vec::new();
+        let mut deposited_value = Decimal::zero();
+        let mut borrowed_value = Decimal::zero();
+        let mut unhealthy_borrow_value = Decimal::zero();
+        let mut borrow_capacities = Vec::with_capacity(self.deposits.len());
+
+        let mut deposits = Vec::with_capacity(self.deposits.len());
+        for mut collateral in self.deposits.drain(..) {
+            let Some(reserve) = get_reserve(&collateral.deposit_reserve) else {
+                dropped.push(collateral.deposit_reserve);
+                continue;
+            };
+
+            let value = market_value(
+                collateral.deposited_amount,
+                reserve.liquidity.market_price,
+                reserve.liquidity.mint_decimals
+            )?;
+            collateral.market_value = value;
+
+            deposited_value = deposited_value.try_add(value)?;
+            borrow_capacities.push((
+                value.try_mul(Rate::from_percent(reserve.config.loan_to_value_ratio))?,
+                reserve.config.collateral_weight_cap,
+            ));
+            unhealthy_borrow_value = unhealthy_borrow_value
+                .try_add(value.try_mul(Rate::from_percent(reserve.config.liquidation_threshold))?)?;
+            deposits.push(collateral);
+        }
+        let allowed_borrow_value = capped_borrow_value(&borrow_capacities)?;
+
+        let mut borrows = Vec::with_capacity(self.borrows.len());
+        for mut liquidity in self.borrows.drain(..) {
+            let Some(reserve) = get_reserve(&liquidity.borrow_reserve) else {
+                dropped.push(liquidity.borrow_reserve);
+                continue;
+            };
+
+            liquidity.accrue_interest(reserve.liquidity.cumulative_borrow_rate_wads, current_slot)?;
+            let value = market_value(
+                liquidity.borrowed_amount_wads.try_floor_u256()?,
+                reserve.liquidity.market_price,
+                reserve.liquidity.mint_decimals
+            )?;
+            liquidity.market_value = value;
+
+            borrowed_value = borrowed_value.try_add(value)?;
+            borrows.push(liquidity);
+        }
+
+        self.deposits = deposits;
+        self.borrows = borrows;
+        self.deposited_value = deposited_value;
+        self.borrowed_value = borrowed_value;
+        self.allowed_borrow_value = allowed_borrow_value;
+        self.unhealthy_borrow_value = unhealthy_borrow_value;
+        Ok(dropped)
+    }
+
+    /// Collateral LTVs weighted by each deposit's market value as of the
+    /// last refresh. Zero without deposited value.
+    pub fn weighted_avg_ltv<F>(&self, get_reserve: F) -> Result<Rate, LendingError>
+    where
+        F: Fn(&Address) -> Option<Reserve>,
+    {
+        let mut total_value = Decimal::zero();
+        let mut weighted_value = Decimal::zero();
+        for collateral in self.deposits.iter() {
+            let reserve = get_reserve(&collateral.deposit_reserve)
+                .ok_or(LendingError::InvalidAccountInput)?;
+            total_value = total_value.try_add(collateral.market_value)?;
+            weighted_value = weighted_value.try_add(
+                collateral.market_value.try_mul(Rate::from_percent(reserve.config.loan_to_value_ratio))?
+            )?;
+        }
+
+        if total_value == Decimal::zero() {
+            return Ok(Rate::zero());
+        }
+        Ok(weighted_value.try_div(total_value)?.to_rate())
+    }
+
+    /// Borrowed value over deposited value
+    pub fn loan_to_value(&self) -> Result<Decimal, LendingError> {
+        if self.deposited_value == Decimal::zero() {
+            return Ok(Decimal::zero());
+        }
+        self.borrowed_value.try_div(self.deposited_value)
+    }
+
+    /// Allowed borrow value over deposited value: the collateral-weighted LTV
+    pub fn allowed_loan_to_value(&self) -> Result<Decimal, LendingError> {
+        if self.deposited_value == Decimal::zero() {
             return Ok(Decimal::zero());
         }
-        
-        available_value.try_sub(self.borrowed_value)
+        self.allowed_borrow_value.try_div(self.deposited_value)
+    }
+
+    /// Liquidation threshold value over borrowed value; below one the
+    /// obligation is liquidatable. `None` without borrows.
+    pub fn health_factor(&self) -> Result<Option<Decimal>, LendingError> {
+        if self.borrowed_value == Decimal::zero() {
+            return Ok(None);
+        }
+        Ok(Some(self.unhealthy_borrow_value.try_div(self.borrowed_value)?))
+    }
+
+    /// Starts the unhealthy marker on the first unhealthy refresh and clears
+    /// it once the obligation is healthy again
+    pub fn mark_unhealthy_since(&mut self, slot: u64) {
+        let unhealthy = self.borrowed_value > Decimal::zero()
+            && self.borrowed_value >= self.unhealthy_borrow_value;
+        if !unhealthy {
+            self.first_unhealthy_slot = None;
+        } else if self.first_unhealthy_slot.is_none() {
+            self.first_unhealthy_slot = Some(slot);
+        }
+    }
+    
+    /// Whether the cached values were rebuilt at or after `slot`, e.g. a
+    /// reserve's last price refresh
+    pub fn is_refreshed_since(&self, slot: u64) -> bool {
+        !self.last_update.stale && self.last_update.slot >= slot
+    }
+
+    pub fn find_or_add_collateral_to_deposits(
+        &mut self,
+        reserve: Address
+    ) -> Result<&mut Collateral, LendingError> {
+        let has_collateral = self.deposits
+            .iter_mut()
+            .any(|c| c.deposit_reserve == reserve);
+
+        if !has_collateral {
+            self.deposits.push(Collateral {
+                deposit_reserve: reserve,
+                deposited_amount: U256::zero(),
+                market_value: Decimal::zero()
+            });
+        }
+
+        Ok(self.deposits
+            .iter_mut()
+            .find(|c| c.deposit_reserve == reserve)
+            .unwrap())
+    }
+    
+    pub fn find_collateral_in_deposits(&self, reserve: Address) -> Result<(Collateral, usize), LendingError> {
+        for (index, collateral) in self.deposits.iter().enumerate() {
+            if collateral.deposit_reserve == reserve {
+                return Ok((collateral.clone(), index));
+            }
+        }
+        Err(LendingError::ObligationCollateralEmpty)
+    }
+    
+    pub fn find_or_add_liquidity_to_borrows(
+        &mut self,
+        reserve: Address
+    ) -> Result<&mut Liquidity, LendingError> {
+        let has_liquidity = self.borrows.iter().any(|l| l.borrow_reserve == reserve);
+
+        if !has_liquidity {
+            // Add new liquidity
+            self.borrows.push(Liquidity {
+                borrow_reserve: reserve,
+                borrowed_amount_wads: Decimal::zero(),
+                market_value: Decimal::zero(),
+                cumulative_borrow_rate_wads: Decimal::one(),
+                maturity_slot: 0,
+                rate_mode: RateMode::Variable,
+                stable_borrow_rate: Rate::zero(),
+                last_accrual_slot: 0,
+            });
+        }
+
+        Ok(self.borrows
+            .iter_mut()
+            .find(|l| l.borrow_reserve == reserve)
+            .unwrap())
+    }
+    
+    pub fn find_liquidity_in_borrows(&self, reserve: Address) -> Result<(Liquidity, usize), LendingError> {
+        for (index, liquidity) in self.borrows.iter().enumerate() {
+            if liquidity.borrow_reserve == reserve {
+                return Ok((liquidity.clone(), index));
+            }
+        }
+        Err(LendingError::ObligationLiquidityEmpty)
+    }
+    
+    pub fn withdraw(&mut self, amount: U256, index: usize) -> Result<(), LendingError> {
+        if index >= self.deposits.len() {
+            return Err(LendingError::InvalidAccountInput);
+        }
+        
+        if amount > self.deposits[index].deposited_amount {
+            return Err(LendingError::WithdrawTooLarge);
+        }
+        
+        self.deposits[index].deposited_amount = self.deposits[index].deposited_amount - amount;
+        Ok(())
+    }
+    
+    pub fn repay(&mut self, amount: Decimal, index: usize) -> Result<(), LendingError> {
+        if index >= self.borrows.len() {
+            return Err(LendingError::InvalidAccountInput);
+        }
+        
+        if amount > self.borrows[index].borrowed_amount_wads {
+            return Err(LendingError::RepayTooSmall);
+        }
+        
+        self.borrows[index].borrowed_amount_wads = self.borrows[index].borrowed_amount_wads.try_sub(amount)?;
+        Ok(())
+    }
+    
+    pub fn remaining_borrow_value(&self) -> Result<Decimal, LendingError> {
+        if self.borrowed_value >= self.allowed_borrow_value {
+            return Ok(Decimal::zero());
+        }
+        self.allowed_borrow_value.try_sub(self.borrowed_value)
+    }
+    
+    pub fn max_withdraw_value(&self, rate: Rate) -> Result<Decimal, LendingError> {
+        if self.borrows.is_empty() {
+            return Ok(self.deposited_value);
+        }
+        
+        let available_value = self.deposited_value.try_mul(rate)?;
+        if available_value <= self.borrowed_value {
+            return Ok(Decimal::zero());
+        }
+        
+        available_value.try_sub(self.borrowed_value)
+    }
+}
+
+#[derive(Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct LastUpdate {
+    pub slot: u64,
+    pub stale: bool,
+}
+
+impl LastUpdate {
+    pub fn update_slot(&mut self, slot: u64) {
+        self.slot = slot;
+        self.stale = false;
+    }
+    
+    pub fn mark_stale(&mut self) {
+        self.stale = true;
+    }
+    
+    pub fn is_stale(&self, current_slot: u64, stale_after_slots: u64) -> bool {
+        self.stale || current_slot.saturating_sub(self.slot) >= stale_after_slots
+    }
+}
+
+#[derive(Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct Collateral {
+    pub deposit_reserve: Address,
+    pub deposited_amount: U256,
+    pub market_value: Decimal,
+}
+
+impl TryAdd for U256 {
+    fn try_add(self, rhs: Self) -> Result<Self, LendingError> {
+        self.checked_add(rhs).ok_or(LendingError::MathOverflow)
+    }
+}
+
+impl TrySub for U256 {
+    fn try_sub(self, rhs: Self) -> Result<Self, LendingError> {
+        self.checked_sub(rhs).ok_or(LendingError::MathOverflow)
+    }
+}
+
+impl Collateral {
+    pub fn deposit(&mut self, amount: U256) -> Result<(), LendingError> {
+        self.deposited_amount = self.deposited_amount.try_add(amount)?;
+        Ok(())
+    }
+}
+
+impl SchemaCustomTypes for LendingError {
+    fn schema_custom_types() -> BTreeMap<String, odra::schema::casper_contract_schema::CustomType> {
+        BTreeMap::new()
+    }
+}
+
+#[derive(Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct Liquidity {
+    pub borrow_reserve: Address,
+    pub borrowed_amount_wads: Decimal,
+    pub market_value: Decimal,
+    pub cumulative_borrow_rate_wads: Decimal,
+    /// Slot after which a fixed-term borrow can be liquidated; zero is perpetual
+    pub maturity_slot: u64,
+    pub rate_mode: RateMode,
+    /// Annual rate locked in when a stable borrow opened
+    pub stable_borrow_rate: Rate,
+    pub last_accrual_slot: u64,
+}
+
+/// How a borrow accrues interest: with the reserve's utilization curve, or
+/// at the rate in effect when the position opened
+#[odra::odra_type]
+pub enum RateMode {
+    Variable,
+    Stable,
+}
+
+impl Liquidity {
+    pub fn borrow(&mut self, amount: U256) -> Result<(), LendingError> {
+        let amount_decimal = Decimal::try_from_u256(amount)?;
+        self.borrowed_amount_wads = self.borrowed_amount_wads.try_add(amount_decimal)?;
+        Ok(())
+    }
+    
+    pub fn accrue_interest(
+        &mut self,
+        cumulative_borrow_rate: Decimal,
+        current_slot: u64
+    ) -> Result<(), LendingError> {
+        // Nothing accrues within the slot the position was last settled in
+        if current_slot <= self.last_accrual_slot {
+            return Ok(());
+        }
+
+        match self.rate_mode {
+            RateMode::Variable => {
+                let compounded_interest = cumulative_borrow_rate.try_div(self.cumulative_borrow_rate_wads)?;
+                self.borrowed_amount_wads = self.borrowed_amount_wads.try_mul_round(compounded_interest)?;
+            }
+            RateMode::Stable => {
+                let slots_elapsed = current_slot.saturating_sub(self.last_accrual_slot);
+                let compounded_interest = Rate::one()
+                    .try_add(self.stable_borrow_rate.try_div(MS_PER_YEAR)?)?
+                    .try_pow_round(slots_elapsed)?;
+                self.borrowed_amount_wads = self.borrowed_amount_wads.try_mul_round(compounded_interest.to_decimal())?;
+            }
+        }
+        self.cumulative_borrow_rate_wads = cumulative_borrow_rate;
+        self.last_accrual_slot = current_slot;
+        Ok(())
+    }
+}
+
+/// Factor debt grows by over `slots_elapsed` slots at the annual
+/// `borrow_rate`. Gaps of at most `linear_max_slots` use simple interest,
+/// which skips `try_pow_round` and slightly undercounts versus compounding.
+pub fn interest_growth(
+    borrow_rate: Rate,
+    slots_elapsed: u64,
+    linear_max_slots: u64
+) -> Result<Rate, LendingError> {
+    let slot_interest_rate = borrow_rate.try_div(MS_PER_YEAR)?;
+    if slots_elapsed <= linear_max_slots {
+        Rate::one().try_add(slot_interest_rate.try_mul(slots_elapsed)?)
+    } else {
+        Rate::one()
+            .try_add(slot_interest_rate)?
+            .try_pow_round(slots_elapsed)
+    }
+}
+
+// ===========================================================================
+// PARAMETER STRUCTS
+// ===========================================================================
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct InitReserveParams {
+    pub current_slot: u64,
+    pub lending_market: Address,
+    pub liquidity: ReserveLiquidity,
+    pub collateral: ReserveCollateral,
+    pub config: ReserveConfig,
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct InitObligationParams {
+    pub current_slot: u64,
+    pub lending_market: Address,
+    pub owner: Address,
+    pub deposits: Vec<Collateral>,
+    pub borrows: Vec<Liquidity>,
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct NewReserveLiquidityParams {
+    pub mint_pubkey: Address,
+    pub mint_decimals: u8,
+    pub supply_pubkey: Address,
+    pub fee_receiver: Address,
+    pub oracle_pubkey: Address,
+    pub market_price: Decimal,
+    pub available_amount: U256,
+    pub borrowed_amount_wads: Decimal,
+    pub cumulative_borrow_rate_wads: Decimal,
+    pub underlying: Option<Address>,
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct NewReserveCollateralParams {
+    pub mint_pubkey: Address,
+    pub supply_pubkey: Address,
+    pub mint_total_supply: U256,
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct ReserveConfig {
+    pub optimal_utilization_rate: u8,
+    pub loan_to_value_ratio: u8,
+    pub liquidation_threshold: u8,
+    pub liquidation_bonus: u8,
+    pub min_borrow_rate: u8,
+    pub optimal_borrow_rate: u8,
+    pub max_borrow_rate: u8,
+    /// Percent of accrued borrow interest kept by the protocol
+    pub reserve_factor: u8,
+    /// Accrual gaps up to this many slots advance the borrow index linearly
+    /// instead of compounding; 0 always compounds
+    pub linear_accrual_max_slots: u64,
+    /// Scale the bonus with how unhealthy the obligation is, up to
+    /// `max_liquidation_bonus`
+    pub dynamic_liquidation_bonus: bool,
+    pub max_liquidation_bonus: u8,
+    /// Collateral-only reserves accept deposits but cannot be borrowed from
+    pub borrow_enabled: bool,
+    /// Max percent of an obligation's borrow capacity this collateral can
+    /// provide; 0 leaves it uncapped
+    pub collateral_weight_cap: u8,
+    pub flash_loan_enabled: bool,
+    /// Max percent of available liquidity one flash loan can take; 0 leaves
+    /// it uncapped
+    pub flash_loan_max_utilization: u8,
+    /// Most that one borrow call can take; zero leaves it unlimited
+    pub max_borrow_per_tx: U256,
+    /// Deposit fee tiers as (minimum deposit, fee in bps), ascending. The
+    /// highest tier a deposit reaches replaces `fees.deposit_fee_wad`.
+    pub fee_tiers: Vec<(U256, u8)>,
+    pub fees: ReserveFees,
+}
+
+impl ReserveConfig {
+    pub fn validate(&self) -> Result<(), LendingError> {
+        if self.optimal_utilization_rate > 100 {
+            return Err(LendingError::InvalidConfig);
+        }
+        if self.min_borrow_rate > self.optimal_borrow_rate
+            || self.optimal_borrow_rate > self.max_borrow_rate {
+            return Err(LendingError::InvalidConfig);
+        }
+        if self.loan_to_value_ratio > 100 {
+            return Err(LendingError::InvalidConfig);
+        }
+        if self.liquidation_threshold > 100 {
+            return Err(LendingError::InvalidConfig);
+        }
+        if self.liquidation_bonus > 100 {
+            return Err(LendingError::InvalidConfig);
+        }
+        // A bonus past the gap between borrowing and liquidation limits can
+        // seize more collateral value than the debt it clears
+        let safety_margin = self.liquidation_threshold.saturating_sub(self.loan_to_value_ratio);
+        if self.liquidation_bonus > safety_margin
+            || (self.dynamic_liquidation_bonus && self.max_liquidation_bonus > safety_margin) {
+            return Err(LendingError::InvalidConfig);
+        }
+        if self.reserve_factor > 100 {
+            return Err(LendingError::InvalidConfig);
+        }
+        if self.collateral_weight_cap > 100 || self.flash_loan_max_utilization > 100 {
+            return Err(LendingError::InvalidConfig);
+        }
+        // A flash loan must be able to return at least what it took
+        if self.flash_loan_enabled && self.fees.flash_loan_fee_wad >= Decimal::one().raw() {
+            return Err(LendingError::InvalidConfig);
+        }
+        if self.dynamic_liquidation_bonus
+            && (self.max_liquidation_bonus > 100 || self.max_liquidation_bonus < self.liquidation_bonus) {
+            return Err(LendingError::InvalidConfig);
+        }
+        // Tiers ascend by threshold, and a larger deposit never pays more
+        if self.fee_tiers.len() > MAX_FEE_TIERS
+            || self.fee_tiers.windows(2).any(|tiers| tiers[0].0 >= tiers[1].0 || tiers[0].1 < tiers[1].1) {
+            return Err(LendingError::InvalidConfig);
+        }
+        if let Some((_, fee_bps)) = self.fee_tiers.first() {
+            if Decimal::one().bps_of(u64::from(*fee_bps))?.raw() > self.fees.deposit_fee_wad {
+                return Err(LendingError::InvalidConfig);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fee kept from a deposit of `amount`, at its fee tier if it reaches one
+    pub fn calculate_deposit_fee(&self, amount: U256) -> Result<Decimal, LendingError> {
+        let amount_wads = Decimal::try_from_u256(amount)?;
+        match self.fee_tiers.iter().rev().find(|(min_amount, _)| amount >= *min_amount) {
+            Some((_, fee_bps)) => amount_wads.bps_of(u64::from(*fee_bps)),
+            None => self.fees.calculate_deposit_fee(amount_wads),
+        }
+    }
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct ReserveFees {
+    pub borrow_fee_wad: U256,
+    pub flash_loan_fee_wad: U256,
+    pub host_fee_percentage: u8,
+    /// Fraction of deposited liquidity kept by the protocol, WAD-scaled
+    pub deposit_fee_wad: U256,
+    /// Fraction of redeemed liquidity kept by the protocol, WAD-scaled
+    pub withdraw_fee_wad: U256,
+}
+
+impl ReserveFees {
+    pub fn calculate_deposit_fee(&self, amount: Decimal) -> Result<Decimal, LendingError> {
+        amount.try_mul(Decimal(self.deposit_fee_wad))
+    }
+
+    pub fn calculate_withdraw_fee(&self, amount: Decimal) -> Result<Decimal, LendingError> {
+        amount.try_mul(Decimal(self.withdraw_fee_wad))
+    }
+
+    pub fn calculate_flash_loan_fees(&self, amount: Decimal) -> Result<(Decimal, Decimal), LendingError> {
+        let fee = amount.try_mul(Decimal(self.flash_loan_fee_wad))?;
+        let host_fee = fee.percent_of(self.host_fee_percentage)?;
+        let origination_fee = fee.try_sub(host_fee)?;
+        Ok((origination_fee, host_fee))
+    }
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct ReserveLiquidity {
+    pub mint_pubkey: Address,
+    pub mint_decimals: u8,
+    pub supply_pubkey: Address,
+    pub fee_receiver: Address,
+    pub oracle_pubkey: Address,
+    /// Decimals of the oracle's raw price, normalized to WAD on refresh
+    pub price_decimals: u8,
+    pub market_price: Decimal,
+    pub available_amount: U256,
+    pub borrowed_amount_wads: Decimal,
+    pub cumulative_borrow_rate_wads: Decimal,
+    pub underlying: Option<Address>,
+    /// Protocol's share of accrued interest, owed out of borrows and not
+    /// counted towards suppliers' `total_supply`
+    pub accumulated_protocol_fees_wads: Decimal,
+    /// Part of `borrowed_amount_wads` owed by stable positions, which grows
+    /// at `average_stable_borrow_rate` rather than the utilization curve
+    pub stable_borrowed_amount_wads: Decimal,
+    /// Principal-weighted annual rate of the stable borrows
+    pub average_stable_borrow_rate: Rate,
+}
+
+impl ReserveLiquidity {
+    pub fn new(params: NewReserveLiquidityParams) -> Self {
+        Self {
+            mint_pubkey: params.mint_pubkey,
+            mint_decimals: params.mint_decimals,
+            supply_pubkey: params.supply_pubkey,
+            fee_receiver: params.fee_receiver,
+            oracle_pubkey: params.oracle_pubkey,
+            price_decimals: SCALE as u8,
+            market_price: params.market_price,
+            available_amount: params.available_amount,
+            borrowed_amount_wads: params.borrowed_amount_wads,
+            cumulative_borrow_rate_wads: params.cumulative_borrow_rate_wads,
+            underlying: params.underlying,
+            accumulated_protocol_fees_wads: Decimal::zero(),
+            stable_borrowed_amount_wads: Decimal::zero(),
+            average_stable_borrow_rate: Rate::zero(),
+        }
+    }
+
+    pub fn deposit(&mut self, amount: U256) -> Result<(), LendingError> {
+        self.available_amount = self.available_amount.try_add(amount)?;
+        Ok(())
+    }
+
+    pub fn withdraw(&mut self, amount: U256) -> Result<(), LendingError> {
+        if amount > self.available_amount {
+            return Err(LendingError::InsufficientLiquidity);
+        }
+        self.available_amount = self.available_amount.try_sub(amount)?;
+        Ok(())
+    }
+
+    /// Available amount that would remain after borrowing `amount`, without
+    /// mutating the reserve
+    pub fn simulate_borrow(&self, amount: Decimal) -> Result<U256, LendingError> {
+        let amount_u256: U256 = amount.try_floor_u64()?.into();
+        if amount_u256 > self.available_amount {
+            return Err(LendingError::InsufficientLiquidity);
+        }
+        self.available_amount.try_sub(amount_u256)
+    }
+
+    pub fn borrow(&mut self, amount: Decimal) -> Result<(), LendingError> {
+        self.available_amount = self.simulate_borrow(amount)?;
+        self.borrowed_amount_wads = self.borrowed_amount_wads.try_add(amount)?;
+        Ok(())
+    }
+
+    pub fn repay(
+        &mut self,
+        repay_amount: U256,
+        settle_amount: Decimal
+    ) -> Result<(), LendingError> {
+        self.available_amount = self.available_amount.try_add(repay_amount)?;
+        self.borrowed_amount_wads = self.borrowed_amount_wads.try_sub(settle_amount)?;
+        Ok(())
+    }
+
+    /// Books `amount` of an existing borrow as stable at the locked `rate`
+    pub fn add_stable_borrow(&mut self, amount: Decimal, rate: Rate) -> Result<(), LendingError> {
+        let stable_borrowed = self.stable_borrowed_amount_wads.try_add(amount)?;
+        self.average_stable_borrow_rate = self.stable_borrowed_amount_wads
+            .try_mul(self.average_stable_borrow_rate)?
+            .try_add(amount.try_mul(rate)?)?
+            .try_div(stable_borrowed)?
+            .to_rate();
+        self.stable_borrowed_amount_wads = stable_borrowed;
+        Ok(())
+    }
+
+    /// Repays `liquidity` out of the side of the borrows it accrues on.
+    /// Compounding stable debt at the average rate can leave the aggregate a
+    /// little below the positions, so a stable settle stops at zero.
+    pub fn repay_borrow(
+        &mut self,
+        repay_amount: U256,
+        settle_amount: Decimal,
+        liquidity: &Liquidity
+    ) -> Result<(), LendingError> {
+        if matches!(liquidity.rate_mode, RateMode::Variable) {
+            return self.repay(repay_amount, settle_amount);
+        }
+
+        let settle_amount = settle_amount.min(self.stable_borrowed_amount_wads);
+        let stable_borrowed = self.stable_borrowed_amount_wads.try_sub(settle_amount)?;
+        let weighted_rate = self.stable_borrowed_amount_wads.try_mul(self.average_stable_borrow_rate)?;
+        let removed_rate = settle_amount.try_mul(liquidity.stable_borrow_rate)?;
+        self.average_stable_borrow_rate = if stable_borrowed == Decimal::zero() || removed_rate >= weighted_rate {
+            Rate::zero()
+        } else {
+            weighted_rate.try_sub(removed_rate)?.try_div(stable_borrowed)?.to_rate()
+        };
+        self.stable_borrowed_amount_wads = stable_borrowed;
+        self.repay(repay_amount, settle_amount)
+    }
+
+    pub fn utilization_rate(&self) -> Result<Rate, LendingError> {
+        let total_supply = Decimal::try_from_u256(self.available_amount)?
+            .try_add(self.borrowed_amount_wads)?;
+        if total_supply == Decimal::zero() {
+            return Ok(Rate::zero());
+        }
+        Ok(self.borrowed_amount_wads.try_div(total_supply)?.to_rate())
+    }
+
+    /// Applies `slots_elapsed` slots of interest at the annual `borrow_rate`.
+    /// `reserve_factor` percent of the interest goes to the protocol; the
+    /// rest grows `total_supply` for suppliers.
+    pub fn compound_interest(
+        &mut self,
+        borrow_rate: Rate,
+        slots_elapsed: u64,
+        reserve_factor: u8,
+        linear_max_slots: u64
+    ) -> Result<(), LendingError> {
+        let previous_borrowed = self.borrowed_amount_wads;
+        let compounded_interest_rate = interest_growth(borrow_rate, slots_elapsed, linear_max_slots)?;
+        let stable_interest_rate = interest_growth(self.average_stable_borrow_rate, slots_elapsed, linear_max_slots)?;
+
+        // Rounded so compounding over many refreshes doesn't drift downward
+        self.cumulative_borrow_rate_wads = self.cumulative_borrow_rate_wads
+            .try_mul_round(compounded_interest_rate.to_decimal())?;
+        // Stable positions grow at their locked rates, not the curve
+        let variable_borrowed = self.borrowed_amount_wads
+            .try_sub(self.stable_borrowed_amount_wads)?
+            .try_mul_round(compounded_interest_rate.to_decimal())?;
+        self.stable_borrowed_amount_wads = self.stable_borrowed_amount_wads
+            .try_mul_round(stable_interest_rate.to_decimal())?;
+        self.borrowed_amount_wads = variable_borrowed.try_add(self.stable_borrowed_amount_wads)?;
+
+        let accrued_interest = self.borrowed_amount_wads.try_sub(previous_borrowed)?;
+        self.accumulated_protocol_fees_wads = self.accumulated_protocol_fees_wads
+            .try_add(accrued_interest.percent_of(reserve_factor)?)?;
+        Ok(())
+    }
+
+    /// Liquidity owed to suppliers: available plus borrows, less the
+    /// protocol's accrued fees
+    pub fn supply_wads(&self) -> Result<Decimal, LendingError> {
+        Decimal::try_from_u256(self.available_amount)?
+            .try_add(self.borrowed_amount_wads)?
+            .try_sub(self.accumulated_protocol_fees_wads)
+    }
+
+    pub fn total_supply(&self) -> U256 {
+        self.supply_wads()
+            .and_then(|supply| supply.try_floor_u256())
+            .unwrap_or(self.available_amount)
+    }
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct ReserveCollateral {
+    pub mint_pubkey: Address,
+    pub supply_pubkey: Address,
+    pub mint_total_supply: U256,
+}
+
+impl ReserveCollateral {
+    pub fn new(params: NewReserveCollateralParams) -> Self {
+        Self {
+            mint_pubkey: params.mint_pubkey,
+            supply_pubkey: params.supply_pubkey,
+            mint_total_supply: params.mint_total_supply,
+        }
+    }
+
+    pub fn mint(&mut self, amount: U256) -> Result<(), LendingError> {
+        self.mint_total_supply = self.mint_total_supply.try_add(amount)?;
+        Ok(())
+    }
+
+    pub fn burn(&mut self, amount: U256) -> Result<(), LendingError> {
+        if amount > self.mint_total_supply {
+            return Err(LendingError::InsufficientCollateral);
+        }
+        self.mint_total_supply = self.mint_total_supply.try_sub(amount)?;
+        Ok(())
+    }
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct PendingReserveConfig {
+    pub config: ReserveConfig,
+    pub proposed_slot: u64,
+}
+
+// ===========================================================================
+// RESULT STRUCTS
+// ===========================================================================
+
+/// Returned by `market_overview`
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct MarketOverview {
+    pub reserve_count: u64,
+    pub obligation_count: u32,
+    pub total_value_locked: Decimal,
+    pub total_borrowed: Decimal,
+    pub total_protocol_fees: Decimal,
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct CalculateBorrowResult {
+    pub borrow_amount: Decimal,
+    pub receive_amount: U256,
+    pub borrow_fee: U256,
+    pub host_fee: U256,
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct CalculateRepayResult {
+    pub settle_amount: Decimal,
+    pub repay_amount: U256,
+}
+
+#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
+pub struct CalculateLiquidationResult {
+    pub settle_amount: Decimal,
+    pub repay_amount: U256,
+    pub withdraw_amount: U256,
+}
+
+fn zero_address() -> Address {
+    Address::Account(odra::casper_types::account::AccountHash::new([0u8; 32]))
+}
+
+/// Sums each collateral's borrow capacity, discounting whatever a capped
+/// collateral contributes beyond its `collateral_weight_cap` percent of the
+/// uncapped total.
+fn capped_borrow_value(capacities: &[(Decimal, u8)]) -> Result<Decimal, LendingError> {
+    let mut uncapped = Decimal::zero();
+    for (capacity, _) in capacities {
+        uncapped = uncapped.try_add(*capacity)?;
+    }
+
+    let mut allowed = Decimal::zero();
+    for (capacity, cap) in capacities {
+        let contribution = if *cap == 0 {
+            *capacity
+        } else {
+            (*capacity).min(uncapped.percent_of(*cap)?)
+        };
+        allowed = allowed.try_add(contribution)?;
+    }
+    Ok(allowed)
+}
+
+fn market_value(amount: U256, price: Decimal, decimals: u8) -> Result<Decimal, LendingError> {
+    market_value_wads(Decimal::try_from_u256(amount)?, price, decimals)
+}
+
+/// `market_value` of a fractional amount, such as accrued fees
+fn market_value_wads(amount: Decimal, price: Decimal, decimals: u8) -> Result<Decimal, LendingError> {
+    let decimals_factor = 10u64
+        .checked_pow(decimals as u32)
+        .ok_or(LendingError::MathOverflow)?;
+
+    // amount * price is already WAD-scaled through the price, so the
+    // amount's own WAD scale comes off with the decimals
+    let denominator = U256::from(decimals_factor)
+        .checked_mul(Decimal::one().raw())
+        .ok_or(LendingError::MathOverflow)?;
+    Ok(Decimal(mul_div(amount.raw(), price.raw(), denominator)?))
+}
+
+/// Inverse of `market_value`: unfloored token amount worth `value`
+fn token_amount(value: Decimal, price: Decimal, decimals: u8) -> Result<Decimal, LendingError> {
+    let decimals_factor = 10u64
+        .checked_pow(decimals as u32)
+        .ok_or(LendingError::MathOverflow)?;
+
+    value.try_mul(Decimal::from(decimals_factor))?.try_div(price)
+}
+
+// ===========================================================================
+// EXTERNAL CONTRACTS
+// ===========================================================================
+
+/// The parts of a CEP-18 token the market calls into
+#[odra::external_contract]
+pub trait Cep18Token {
+    fn balance_of(&self, address: &Address) -> U256;
+    fn transfer(&mut self, recipient: &Address, amount: &U256);
+}
+
+// ===========================================================================
+// EVENTS
+// ===========================================================================
+
+#[odra::event]
+pub struct ReserveConfigUpdated {
+    pub reserve: Address,
+    pub old_config: ReserveConfig,
+    pub new_config: ReserveConfig,
+    /// Block time the event was emitted at
+    pub slot: u64,
+}
+
+#[odra::event]
+pub struct HostFeePaid {
+    pub reserve: Address,
+    pub receiver: Address,
+    pub amount: U256,
+    /// Block time the event was emitted at
+    pub slot: u64,
+}
+
+#[odra::event]
+pub struct ReserveFeeReceiverUpdated {
+    pub reserve: Address,
+    pub old_receiver: Address,
+    pub new_receiver: Address,
+    /// Block time the event was emitted at
+    pub slot: u64,
+}
+
+/// A position dropped by `recompute_obligation` because its reserve is gone
+#[odra::event]
+pub struct ObligationPositionDropped {
+    pub obligation_owner: Address,
+    pub reserve: Address,
+    /// Block time the event was emitted at
+    pub slot: u64,
+}
+
+/// Debt left behind when a liquidation seized an obligation's last collateral
+#[odra::event]
+pub struct BadDebtRecorded {
+    pub reserve: Address,
+    pub obligation_owner: Address,
+    pub amount: Decimal,
+    /// Block time the event was emitted at
+    pub slot: u64,
+}
+
+/// Bad debt written off a reserve's borrows at its suppliers' expense
+#[odra::event]
+pub struct BadDebtSocialized {
+    pub reserve: Address,
+    pub amount: Decimal,
+    /// Block time the event was emitted at
+    pub slot: u64,
+}
+
+/// An account's `ROLE_*` bits after a grant or revoke
+#[odra::event]
+pub struct RolesUpdated {
+    pub account: Address,
+    pub roles: u8,
+    /// Block time the event was emitted at
+    pub slot: u64,
+}
+
+/// Token balance recovered by `sweep_untracked`
+#[odra::event]
+pub struct UntrackedTokensSwept {
+    pub token: Address,
+    pub to: Address,
+    pub amount: U256,
+    /// Block time the event was emitted at
+    pub slot: u64,
+}
+
+/// Post-refresh reserve state, so indexers can build rate and utilization
+/// history without polling
+#[odra::event]
+pub struct ReserveRefreshed {
+    pub reserve: Address,
+    pub cumulative_borrow_rate_wads: Decimal,
+    pub available_amount: U256,
+    pub borrowed_amount_wads: Decimal,
+    pub market_price: Decimal,
+    pub slot: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use odra::host::{Deployer, HostEnv, HostRef, NoArgs};
+
+    /// Bare CEP-18 stand-in for the sweep tests
+    #[odra::module]
+    pub struct MockToken {
+        balances: Mapping<Address, U256>,
+    }
+
+    #[odra::module]
+    impl MockToken {
+        pub fn mint(&mut self, owner: Address, amount: U256) {
+            let balance = self.balance_of(&owner);
+            self.balances.set(&owner, balance + amount);
+        }
+
+        pub fn balance_of(&self, address: &Address) -> U256 {
+            self.balances.get(address).unwrap_or_default()
+        }
+
+        pub fn transfer(&mut self, recipient: &Address, amount: &U256) {
+            let sender = self.env().caller();
+            let sender_balance = self.balance_of(&sender);
+            self.balances.set(&sender, sender_balance - *amount);
+            let recipient_balance = self.balance_of(recipient);
+            self.balances.set(recipient, recipient_balance + *amount);
+        }
+    }
+
+    fn setup() -> (HostEnv, NovaLendingHostRef) {
+        let env = odra_test::env();
+        let lending = NovaLending::deploy(
+            &env,
+            NovaLendingInitArgs {
+                owner: env.get_account(0),
+                quote_currency: [0u8; 32],
+                token_program_id: env.get_account(1),
+                oracle_program_id: env.get_account(2),
+            },
+        );
+        (env, lending)
+    }
+
+    fn test_config() -> ReserveConfig {
+        ReserveConfig {
+            optimal_utilization_rate: 80,
+            loan_to_value_ratio: 50,
+            liquidation_threshold: 80,
+            liquidation_bonus: 5,
+            min_borrow_rate: 0,
+            optimal_borrow_rate: 10,
+            max_borrow_rate: 30,
+            reserve_factor: 0,
+            linear_accrual_max_slots: 0,
+            dynamic_liquidation_bonus: false,
+            max_liquidation_bonus: 0,
+            borrow_enabled: true,
+            collateral_weight_cap: 0,
+            flash_loan_enabled: true,
+            flash_loan_max_utilization: 0,
+            max_borrow_per_tx: U256::zero(),
+            fee_tiers: Vec::new(),
+            fees: ReserveFees {
+                borrow_fee_wad: U256::zero(),
+                flash_loan_fee_wad: U256::zero(),
+                host_fee_percentage: 0,
+                deposit_fee_wad: U256::zero(),
+                withdraw_fee_wad: U256::zero(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_precise_borrow_respects_slippage_limit() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+
+        // The 1% borrow fee leaves 990 of a precise 1_000 borrow
+        assert_eq!(
+            lending.try_borrow_obligation_liquidity(reserve_key, U256::from(1_000u64), U256::from(1_000u64), 0, RateMode::Variable, None, Decimal::zero()),
+            Err(LendingError::ExceededSlippage.into())
+        );
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(1_000u64), U256::from(990u64), 0, RateMode::Variable, None, Decimal::zero());
+    }
+
+    #[test]
+    fn test_init_reserve_rejects_duplicate_underlying() {
+        let (env, mut lending) = setup();
+        let underlying = env.get_account(5);
+        lending.init_reserve(U256::from(1_000u64), test_config(), Some(underlying), 9, None);
+
+        assert_eq!(
+            lending.try_init_reserve(U256::from(1_000u64), test_config(), Some(underlying), 9, None),
+            Err(LendingError::AlreadyInitialized.into())
+        );
+        assert_eq!(lending.get_reserve_count(), 1);
+    }
+
+    #[test]
+    fn test_obligation_position_getters_match_obligation() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(1_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        let deposits = lending.get_obligation_deposits(borrower);
+        let borrows = lending.get_obligation_borrows(borrower);
+
+        assert_eq!(deposits.len(), obligation.deposits.len());
+        assert_eq!(deposits[0], (
+            obligation.deposits[0].deposit_reserve,
+            obligation.deposits[0].deposited_amount,
+            obligation.deposits[0].market_value,
+        ));
+        assert_eq!(borrows.len(), obligation.borrows.len());
+        assert_eq!(borrows[0], (
+            obligation.borrows[0].borrow_reserve,
+            obligation.borrows[0].borrowed_amount_wads,
+            obligation.borrows[0].market_value,
+        ));
+        assert!(lending.get_obligation_deposits(env.get_account(4)).is_empty());
+    }
+
+    #[test]
+    fn test_stale_after_slots_extends_reserve_freshness() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        env.advance_block_time(DEFAULT_STALE_AFTER_SLOTS);
+        assert_eq!(
+            lending.try_deposit_reserve_liquidity(reserve_key, TokenAmount::from(100u64)),
+            Err(LendingError::ReserveStale.into())
+        );
+
+        lending.set_stale_after_slots(DEFAULT_STALE_AFTER_SLOTS * 2);
+        lending.deposit_reserve_liquidity(reserve_key, TokenAmount::from(100u64));
+    }
+
+    /// Initializes each `(config, liquidity, price)` reserve behind its own
+    /// oracle and refreshes it at that price. Leaves the owner as caller.
+    fn test_bootstrap(
+        env: &HostEnv,
+        lending: &mut NovaLendingHostRef,
+        reserves: Vec<(ReserveConfig, U256, Decimal)>
+    ) -> Vec<Address> {
+        env.set_caller(env.get_account(0));
+        reserves.into_iter().enumerate().map(|(index, (config, liquidity, price))| {
+            let reserve_key = lending.init_reserve(liquidity, config, None, 9, None);
+            let oracle = env.get_account(10 + index);
+            lending.set_reserve_oracle(reserve_key, oracle, SCALE as u8);
+            lending.set_oracle_price(oracle, price.raw());
+            lending.refresh_reserve(reserve_key);
+            reserve_key
+        }).collect()
+    }
+
+    fn open_position(env: &HostEnv, lending: &mut NovaLendingHostRef, borrower: Address, maturity_slot: u64) -> Address {
+        env.set_caller(env.get_account(0));
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(1_000u64), U256::zero(), maturity_slot, RateMode::Variable, None, Decimal::zero());
+        reserve_key
+    }
+
+    #[test]
+    fn test_matured_loan_is_liquidatable() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let maturity_slot = env.block_time() + 10;
+        let reserve_key = open_position(&env, &mut lending, borrower, maturity_slot);
+
+        env.advance_block_time(11);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        env.set_caller(env.get_account(6));
+        lending.liquidate_matured(borrower, reserve_key, reserve_key, U256::from(500u64));
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::from(500u64));
+    }
+
+    #[test]
+    fn test_unmatured_healthy_loan_is_not_liquidatable() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let maturity_slot = env.block_time() + 10;
+        let reserve_key = open_position(&env, &mut lending, borrower, maturity_slot);
+
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        env.set_caller(env.get_account(6));
+        assert_eq!(
+            lending.try_liquidate_matured(borrower, reserve_key, reserve_key, U256::from(500u64)),
+            Err(LendingError::LoanNotMatured.into())
+        );
+        assert_eq!(
+            lending.try_liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64)),
+            Err(LendingError::ObligationHealthy.into())
+        );
+    }
+
+    #[test]
+    fn test_reserve_config_timelock() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+        lending.set_config_timelock_slots(100);
+
+        let mut new_config = test_config();
+        new_config.loan_to_value_ratio = 40;
+
+        assert_eq!(
+            lending.try_modify_reserve_config(reserve_key, new_config.clone()),
+            Err(LendingError::TimelockNotElapsed.into())
+        );
+
+        lending.propose_reserve_config(reserve_key, new_config.clone());
+        assert_eq!(
+            lending.try_apply_reserve_config(reserve_key),
+            Err(LendingError::TimelockNotElapsed.into())
+        );
+
+        env.advance_block_time(100);
+        lending.apply_reserve_config(reserve_key);
+
+        assert_eq!(lending.get_reserve(reserve_key).unwrap().config.loan_to_value_ratio, 40);
+        assert!(lending.get_pending_reserve_config(reserve_key).is_none());
+        assert!(env.emitted_event(&lending, ReserveConfigUpdated {
+            reserve: reserve_key,
+            old_config: test_config(),
+            new_config,
+            slot: env.block_time(),
+        }));
+    }
+
+    #[test]
+    fn test_refresh_and_liquidate_handles_stale_state() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(5_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        // Tightening the threshold makes the position unhealthy on next refresh
+        env.set_caller(env.get_account(0));
+        let mut risky_config = test_config();
+        risky_config.loan_to_value_ratio = 30;
+        risky_config.liquidation_threshold = 40;
+        lending.modify_reserve_config(reserve_key, risky_config);
+
+        env.set_caller(env.get_account(6));
+        assert_eq!(
+            lending.try_liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64)),
+            Err(LendingError::ReserveStale.into())
+        );
+        lending.refresh_and_liquidate(borrower, reserve_key, reserve_key, U256::from(500u64));
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::from(4_500u64));
+    }
+
+    #[test]
+    fn test_max_borrowable_bounded_by_available_liquidity() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+
+        // Collateral would allow 5_000, but the reserve only holds 1_000
+        assert_eq!(lending.max_borrowable(borrower, reserve_key), U256::from(1_000u64));
+    }
+
+    fn test_liquidity(env: &HostEnv, available_amount: U256) -> ReserveLiquidity {
+        ReserveLiquidity::new(NewReserveLiquidityParams {
+            mint_pubkey: env.get_account(7),
+            mint_decimals: 9,
+            supply_pubkey: env.get_account(7),
+            fee_receiver: env.get_account(0),
+            oracle_pubkey: env.get_account(2),
+            market_price: Decimal::one(),
+            available_amount,
+            borrowed_amount_wads: Decimal::zero(),
+            cumulative_borrow_rate_wads: Decimal::one(),
+            underlying: None,
+        })
+    }
+
+    #[test]
+    fn test_simulate_borrow_matches_borrow() {
+        let env = odra_test::env();
+        let mut liquidity = test_liquidity(&env, U256::from(1_000u64));
+
+        let simulated = liquidity.simulate_borrow(Decimal::from(400u64)).unwrap();
+        assert_eq!(liquidity.available_amount, U256::from(1_000u64));
+
+        liquidity.borrow(Decimal::from(400u64)).unwrap();
+        assert_eq!(liquidity.available_amount, simulated);
+        assert_eq!(
+            liquidity.simulate_borrow(Decimal::from(601u64)),
+            Err(LendingError::InsufficientLiquidity)
+        );
+    }
+
+    #[test]
+    fn test_reserve_key_by_underlying() {
+        let (env, mut lending) = setup();
+        let underlying = env.get_account(5);
+        lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), Some(underlying), 9, None);
+
+        assert_eq!(lending.get_reserve_key_by_underlying(underlying), Some(reserve_key));
+        assert_eq!(lending.get_reserve_key_by_underlying(env.get_account(6)), None);
+    }
+
+    #[test]
+    fn test_partial_refresh_updates_only_given_positions() {
+        let (env, mut lending) = setup();
+        let reserve_a = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+        let reserve_b = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_a, U256::from(1_000u64));
+        lending.deposit_obligation_collateral(reserve_b, U256::from(1_000u64));
+        lending.refresh_obligation(borrower);
+
+        lending.deposit_obligation_collateral(reserve_a, U256::from(500u64));
+        lending.deposit_obligation_collateral(reserve_b, U256::from(500u64));
+        lending.refresh_obligation_partial(borrower, vec![reserve_a]);
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.deposits[0].market_value, Decimal::from(1_500u64));
+        assert_eq!(obligation.deposits[1].market_value, Decimal::from(1_000u64));
+        assert_eq!(obligation.deposited_value, Decimal::from(2_500u64));
+        assert!(!obligation.last_update.stale);
+    }
+
+    fn open_unhealthy_position(env: &HostEnv, lending: &mut NovaLendingHostRef, borrower: Address) -> Address {
+        env.set_caller(env.get_account(0));
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(5_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        env.set_caller(env.get_account(0));
+        let mut risky_config = test_config();
+        risky_config.loan_to_value_ratio = 30;
+        risky_config.liquidation_threshold = 40;
+        lending.modify_reserve_config(reserve_key, risky_config);
+
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+        reserve_key
+    }
+
+    #[test]
+    fn test_liquidation_without_grace_period() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_unhealthy_position(&env, &mut lending, borrower);
+
+        env.set_caller(env.get_account(6));
+        lending.liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64));
+    }
+
+    #[test]
+    fn test_liquidation_respects_grace_period() {
+        let (env, mut lending) = setup();
+        lending.set_liquidation_grace_slots(100);
+        let borrower = env.get_account(3);
+        let reserve_key = open_unhealthy_position(&env, &mut lending, borrower);
+
+        env.set_caller(env.get_account(6));
+        assert_eq!(
+            lending.try_liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64)),
+            Err(LendingError::LiquidationGracePeriod.into())
+        );
+        assert!(!lending.is_liquidatable(borrower));
+
+        env.advance_block_time(100);
+        assert!(lending.is_liquidatable(borrower));
+        lending.liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64));
+    }
+
+    #[test]
+    fn test_price_refresh_keeps_accrual_baseline() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        let opened_at = lending.get_reserve(reserve_key).unwrap().last_accrual_slot;
+
+        env.advance_block_time(1_000);
+        lending.refresh_reserve_price(reserve_key);
+
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.last_update.slot, opened_at + 1_000);
+        assert_eq!(reserve.last_accrual_slot, opened_at);
+        assert_eq!(reserve.liquidity.cumulative_borrow_rate_wads, Decimal::one());
+
+        env.advance_block_time(1_000);
+        lending.refresh_reserve(reserve_key);
+
+        // Accrues over both gaps, from the original baseline
+        let mut expected = lending.get_reserve(reserve_key).unwrap();
+        expected.liquidity.cumulative_borrow_rate_wads = Decimal::one();
+        expected.liquidity.borrowed_amount_wads = reserve.liquidity.borrowed_amount_wads;
+        let rate = reserve.current_borrow_rate().unwrap();
+        expected.liquidity.compound_interest(rate, 2_000, 0, 0).unwrap();
+
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.last_accrual_slot, opened_at + 2_000);
+        assert_eq!(
+            reserve.liquidity.cumulative_borrow_rate_wads,
+            expected.liquidity.cumulative_borrow_rate_wads
+        );
+        assert!(reserve.liquidity.cumulative_borrow_rate_wads > Decimal::one());
+    }
+
+    #[test]
+    fn test_borrow_cooldown_after_deposit() {
+        let (env, mut lending) = setup();
+        lending.set_borrow_cooldown_slots(100);
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+
+        assert_eq!(
+            lending.try_borrow_obligation_liquidity(reserve_key, U256::from(1_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero()),
+            Err(LendingError::BorrowCooldown.into())
+        );
+
+        env.advance_block_time(100);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(1_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+    }
+
+    fn flat_rate_config(rate: u8) -> ReserveConfig {
+        let mut config = test_config();
+        config.min_borrow_rate = rate;
+        config.optimal_borrow_rate = rate;
+        config.max_borrow_rate = rate;
+        config
+    }
+
+    #[test]
+    fn test_blended_borrow_rate_weights_by_value() {
+        let (env, mut lending) = setup();
+        let reserve_a = lending.init_reserve(U256::from(1_000_000u64), flat_rate_config(10), None, 9, None);
+        let reserve_b = lending.init_reserve(U256::from(1_000_000u64), flat_rate_config(20), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        assert_eq!(lending.obligation_blended_borrow_rate(borrower), Rate::zero());
+
+        lending.deposit_obligation_collateral(reserve_a, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_a, U256::from(1_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        lending.refresh_reserve(reserve_a);
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_b, U256::from(3_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        lending.refresh_reserve(reserve_b);
+        lending.refresh_obligation(borrower);
+
+        // (10% * 1_000 + 20% * 3_000) / 4_000
+        assert_eq!(
+            lending.obligation_blended_borrow_rate(borrower),
+            Rate::from_scaled_val(175_000_000_000_000_000)
+        );
+    }
+
+    fn test_reserve(env: &HostEnv, available_amount: U256) -> Reserve {
+        Reserve::new(InitReserveParams {
+            current_slot: 0,
+            lending_market: env.get_account(0),
+            liquidity: test_liquidity(env, available_amount),
+            collateral: ReserveCollateral::new(NewReserveCollateralParams {
+                mint_pubkey: env.get_account(7),
+                supply_pubkey: env.get_account(7),
+                mint_total_supply: U256::zero(),
+            }),
+            config: test_config(),
+        })
+    }
+
+    #[test]
+    fn test_deposit_rejected_with_donated_liquidity() {
+        let env = odra_test::env();
+        let mut reserve = test_reserve(&env, U256::from(1_000u64));
+
+        assert_eq!(
+            reserve.deposit_liquidity(TokenAmount::from(100u64)),
+            Err(LendingError::InvalidAccountInput)
+        );
+    }
+
+    #[test]
+    fn test_init_reserve_seeds_collateral_supply() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.collateral.mint_total_supply, U256::from(1_000u64));
+
+        env.set_caller(env.get_account(3));
+        assert_eq!(lending.deposit_reserve_liquidity(reserve_key, TokenAmount::from(500u64)), TokenAmount::from(500u64));
+    }
+
+    #[test]
+    fn test_proportional_liquidation_splits_across_collaterals() {
+        let (env, mut lending) = setup();
+        let reserve_keys: Vec<Address> = (0..3)
+            .map(|_| lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None))
+            .collect();
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        for reserve_key in reserve_keys.iter() {
+            lending.deposit_obligation_collateral(*reserve_key, U256::from(10_000u64));
+            lending.refresh_reserve(*reserve_key);
+        }
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_keys[0], U256::from(14_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        env.set_caller(env.get_account(0));
+        let mut risky_config = test_config();
+        risky_config.loan_to_value_ratio = 30;
+        risky_config.liquidation_threshold = 40;
+        for reserve_key in reserve_keys.iter() {
+            lending.modify_reserve_config(*reserve_key, risky_config.clone());
+            lending.refresh_reserve(*reserve_key);
+        }
+        lending.refresh_obligation(borrower);
+
+        // Repay is capped at 14_000 - 12_000; the 2_100 seized splits evenly
+        env.set_caller(env.get_account(6));
+        lending.liquidate_obligation_proportional(borrower, reserve_keys[0], U256::from(3_000u64));
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::from(12_000u64));
+        for collateral in obligation.deposits.iter() {
+            assert_eq!(collateral.deposited_amount, U256::from(9_300u64));
+        }
+    }
+
+    #[test]
+    fn test_proportional_liquidation_uses_repay_reserve_bonus() {
+        let (env, mut lending) = setup();
+        let reserve_keys: Vec<Address> = (0..2)
+            .map(|_| lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None))
+            .collect();
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        for reserve_key in reserve_keys.iter() {
+            lending.deposit_obligation_collateral(*reserve_key, U256::from(10_000u64));
+            lending.refresh_reserve(*reserve_key);
+        }
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_keys[0], U256::from(9_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        env.set_caller(env.get_account(0));
+        let mut risky_config = test_config();
+        risky_config.loan_to_value_ratio = 30;
+        risky_config.liquidation_threshold = 40;
+        for reserve_key in reserve_keys.iter() {
+            lending.modify_reserve_config(*reserve_key, risky_config.clone());
+        }
+        risky_config.liquidation_bonus = 10;
+        lending.modify_reserve_config(reserve_keys[0], risky_config);
+        for reserve_key in reserve_keys.iter() {
+            lending.refresh_reserve(*reserve_key);
+        }
+        lending.refresh_obligation(borrower);
+
+        // Repay is capped at 9_000 - 8_000; the 10% bonus seizes 1_100
+        env.set_caller(env.get_account(6));
+        lending.liquidate_obligation_proportional(borrower, reserve_keys[0], U256::from(3_000u64));
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        for collateral in obligation.deposits.iter() {
+            assert_eq!(collateral.deposited_amount, U256::from(9_450u64));
+        }
+    }
+
+    #[test]
+    fn test_reserve_config_accessors() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+
+        assert_eq!(lending.get_reserve_fee_receiver(reserve_key), Some(env.get_account(0)));
+        assert_eq!(
+            lending.get_reserve_config(reserve_key).unwrap().loan_to_value_ratio,
+            test_config().loan_to_value_ratio
+        );
+
+        let mut new_config = test_config();
+        new_config.loan_to_value_ratio = 40;
+        lending.modify_reserve_config(reserve_key, new_config);
+        assert_eq!(lending.get_reserve_config(reserve_key).unwrap().loan_to_value_ratio, 40);
+
+        assert!(lending.get_reserve_config(env.get_account(9)).is_none());
+        assert_eq!(lending.get_reserve_fee_receiver(env.get_account(9)), None);
+    }
+
+    #[test]
+    fn test_collateral_only_reserve_rejects_borrows() {
+        let (env, mut lending) = setup();
+        let mut config = test_config();
+        config.borrow_enabled = false;
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), config, None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.deposits[0].deposited_amount, U256::from(10_000u64));
+        assert_eq!(
+            lending.try_borrow_obligation_liquidity(reserve_key, U256::from(1_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero()),
+            Err(LendingError::BorrowingDisabled.into())
+        );
+    }
+
+    #[test]
+    fn test_close_empty_obligation() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+
+        lending.close_obligation();
+        assert!(lending.get_obligation(borrower).is_none());
+        assert_eq!(
+            lending.try_close_obligation(),
+            Err(LendingError::InvalidObligationOwner.into())
+        );
+
+        // The slot can be reused after closing
+        lending.init_obligation();
+        assert!(lending.get_obligation(borrower).is_some());
+    }
+
+    #[test]
+    fn test_close_obligation_with_open_borrow_fails() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        open_position(&env, &mut lending, borrower, 0);
+
+        env.set_caller(borrower);
+        assert_eq!(
+            lending.try_close_obligation(),
+            Err(LendingError::ObligationNotEmpty.into())
+        );
+        assert!(lending.get_obligation(borrower).is_some());
+    }
+
+    #[test]
+    fn test_init_reserve_respects_max_reserves() {
+        let (_env, mut lending) = setup();
+        assert_eq!(lending.get_max_reserves(), DEFAULT_MAX_RESERVES);
+
+        lending.set_max_reserves(2);
+        lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+        lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+        assert_eq!(
+            lending.try_init_reserve(U256::from(1_000u64), test_config(), None, 9, None),
+            Err(LendingError::ReserveLimitReached.into())
+        );
+        assert_eq!(lending.get_reserve_count(), 2);
+    }
+
+    #[test]
+    fn test_calculate_borrow_above_u64_max() {
+        let env = odra_test::env();
+        let reserve = test_reserve(&env, U256::zero());
+        let remaining_amount = U256::from(u64::MAX) * U256::from(4u64);
+        let remaining = Decimal::try_from_u256(remaining_amount).unwrap();
+
+        let result = reserve.calculate_borrow(U256::max_value(), remaining).unwrap();
+        assert_eq!(result.borrow_amount, remaining);
+        assert_eq!(result.receive_amount + result.borrow_fee, remaining_amount);
+    }
+
+    #[test]
+    fn test_accrual_splits_interest_between_protocol_and_suppliers() {
+        let env = odra_test::env();
+        let mut liquidity = test_liquidity(&env, U256::from(1_000u64));
+        liquidity.borrow(Decimal::from(400u64)).unwrap();
+
+        let borrowed_before = liquidity.borrowed_amount_wads;
+        let supply_before = liquidity.supply_wads().unwrap();
+        liquidity.compound_interest(Rate::from_percent(10), MS_PER_YEAR / 12, 20, 0).unwrap();
+
+        let accrued = liquidity.borrowed_amount_wads.try_sub(borrowed_before).unwrap();
+        let supplier_share = liquidity.supply_wads().unwrap().try_sub(supply_before).unwrap();
+        let protocol_fee = liquidity.accumulated_protocol_fees_wads;
+
+        assert!(accrued > Decimal::zero());
+        assert_eq!(protocol_fee.try_add(supplier_share).unwrap(), accrued);
+        assert_eq!(protocol_fee, accrued.percent_of(20).unwrap());
+        assert!(liquidity.total_supply() > U256::from(1_000u64));
+    }
+
+    #[test]
+    fn test_recompute_drops_positions_in_missing_reserves() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+
+        let mut obligation = lending.get_obligation(borrower).unwrap();
+        let missing = env.get_account(9);
+        obligation.deposits.push(Collateral {
+            deposit_reserve: missing,
+            deposited_amount: U256::from(5_000u64),
+            market_value: Decimal::from(5_000u64),
+        });
+        obligation.deposited_value = Decimal::from(99_999u64);
+
+        let dropped = obligation
+            .recompute_aggregates(env.block_time(), |key| if *key == reserve_key { Some(reserve.clone()) } else { None })
+            .unwrap();
+
+        assert_eq!(dropped, vec![missing]);
+        assert_eq!(obligation.deposits.len(), 1);
+        assert_eq!(obligation.deposited_value, Decimal::from(10_000u64));
+        assert_eq!(obligation.allowed_borrow_value, Decimal::from(5_000u64));
+        assert_eq!(obligation.borrowed_value, Decimal::from(1_000u64));
+    }
+
+    #[test]
+    fn test_recompute_obligation_is_owner_only() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+
+        env.set_caller(env.get_account(0));
+        lending.refresh_reserve(reserve_key);
+        lending.recompute_obligation(borrower);
+        assert_eq!(
+            lending.get_obligation(borrower).unwrap().deposited_value,
+            Decimal::from(10_000u64)
+        );
+
+        env.set_caller(borrower);
+        assert_eq!(
+            lending.try_recompute_obligation(borrower),
+            Err(LendingError::InvalidMarketOwner.into())
+        );
+    }
+
+    #[test]
+    fn test_emergency_redeem_while_paused() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+        let collateral_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(collateral_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(900u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        // The borrow left the reserve stale, which only a pause excuses
+        env.set_caller(env.get_account(0));
+        assert_eq!(
+            lending.try_emergency_redeem(reserve_key, U256::from(100u64)),
+            Err(LendingError::ReserveStale.into())
+        );
+
+        lending.set_paused(true);
+        lending.refresh_reserve(reserve_key);
+        assert_eq!(
+            lending.try_redeem_reserve_collateral(reserve_key, U256::from(100u64)),
+            Err(LendingError::MarketPaused.into())
+        );
+
+        // Only the 100 left unborrowed can be paid out
+        assert_eq!(
+            lending.try_emergency_redeem(reserve_key, U256::from(500u64)),
+            Err(LendingError::InsufficientLiquidity.into())
+        );
+        assert_eq!(lending.emergency_redeem(reserve_key, U256::from(100u64)), U256::from(100u64));
+        assert_eq!(lending.get_reserve(reserve_key).unwrap().liquidity.available_amount, U256::zero());
+    }
+
+    #[test]
+    fn test_obligations_at_risk_by_health_factor() {
+        let (env, mut lending) = setup();
+        let safe = env.get_account(3);
+        let risky = env.get_account(4);
+        let idle = env.get_account(5);
+
+        // 8_000 threshold value against 1_000 borrowed: health factor 8
+        let reserve_key = open_position(&env, &mut lending, safe, 0);
+
+        // 8_000 threshold value against 5_000 borrowed: health factor 1.6
+        env.set_caller(risky);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(risky);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(5_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(risky);
+        lending.refresh_obligation(safe);
+
+        env.set_caller(idle);
+        lending.init_obligation();
+
+        assert_eq!(lending.get_obligations_at_risk(Decimal::from(2u64), 0, 10), (vec![risky], 3));
+        assert_eq!(lending.get_obligations_at_risk(Decimal::from(10u64), 0, 10), (vec![safe, risky], 3));
+        assert!(lending.get_obligations_at_risk(Decimal::one(), 0, 10).0.is_empty());
+
+        // Paging scans only the requested owners
+        assert_eq!(lending.get_obligations_at_risk(Decimal::from(10u64), 1, 2), (vec![risky], 3));
+        assert_eq!(lending.get_obligations_at_risk(Decimal::from(10u64), 3, 2), (vec![], 3));
+    }
+
+    #[test]
+    fn test_get_all_reserves_pagination() {
+        let (_env, mut lending) = setup();
+        let reserve_keys: Vec<Address> = (0..5)
+            .map(|_| lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None))
+            .collect();
+
+        assert_eq!(lending.get_all_reserves(0, 2), (reserve_keys[0..2].to_vec(), 5));
+        assert_eq!(lending.get_all_reserves(2, 2), (reserve_keys[2..4].to_vec(), 5));
+        assert_eq!(lending.get_all_reserves(4, 2), (reserve_keys[4..].to_vec(), 5));
+        assert_eq!(lending.get_all_reserves(7, 2), (vec![], 5));
+    }
+
+    #[test]
+    fn test_obligation_owner_list_tracks_open_and_close() {
+        let (env, mut lending) = setup();
+        let owners = [env.get_account(3), env.get_account(4), env.get_account(5)];
+        for owner in owners.iter() {
+            env.set_caller(*owner);
+            lending.init_obligation();
+        }
+        assert_eq!(lending.get_obligation_count(), 3);
+
+        // Closing from the middle moves the last owner into its slot
+        env.set_caller(owners[0]);
+        lending.close_obligation();
+        assert_eq!(lending.get_obligation_owners(0, 10), (vec![owners[2], owners[1]], 2));
+
+        env.set_caller(owners[2]);
+        lending.close_obligation();
+        env.set_caller(owners[0]);
+        lending.init_obligation();
+        assert_eq!(lending.get_obligation_owners(0, 10), (vec![owners[1], owners[0]], 2));
+
+        env.set_caller(owners[1]);
+        lending.close_obligation();
+        env.set_caller(owners[0]);
+        lending.close_obligation();
+        assert_eq!(lending.get_obligation_count(), 0);
+    }
+
+    #[test]
+    fn test_stable_borrow_ignores_later_rate_changes() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), flat_rate_config(10), None, 9, None);
+
+        let stable = env.get_account(3);
+        let variable = env.get_account(4);
+        for (borrower, rate_mode) in [(stable, RateMode::Stable), (variable, RateMode::Variable)] {
+            env.set_caller(borrower);
+            lending.init_obligation();
+            lending.deposit_obligation_collateral(reserve_key, U256::from(100_000u64));
+            lending.refresh_reserve(reserve_key);
+            lending.refresh_obligation(borrower);
+            lending.borrow_obligation_liquidity(reserve_key, U256::from(10_000u64), U256::zero(), 0, rate_mode, None, Decimal::zero());
+        }
+
+        // Triple the reserve rate, then let a year pass
+        env.set_caller(env.get_account(0));
+        lending.refresh_reserve(reserve_key);
+        lending.modify_reserve_config(reserve_key, flat_rate_config(30));
+        env.advance_block_time(MS_PER_YEAR);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(stable);
+        lending.refresh_obligation(variable);
+
+        let stable_debt = lending.get_obligation(stable).unwrap().borrows[0].borrowed_amount_wads;
+        let variable_debt = lending.get_obligation(variable).unwrap().borrows[0].borrowed_amount_wads;
+
+        // ~10% versus ~30% continuously compounded over the year
+        let stable_debt = stable_debt.try_floor_u64().unwrap();
+        assert!((11_040..=11_060).contains(&stable_debt));
+        assert!(variable_debt.try_floor_u64().unwrap() > 13_400);
+        assert_eq!(
+            lending.get_obligation(stable).unwrap().borrows[0].stable_borrow_rate,
+            Rate::from_percent(10)
+        );
+    }
+
+    #[test]
+    fn test_stable_borrow_repays_in_full_after_rate_cut() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), flat_rate_config(30), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(100_000u64));
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(10_000u64), U256::zero(), 0, RateMode::Stable, None, Decimal::zero());
+
+        // The curve falls to a third of the locked rate
+        env.set_caller(env.get_account(0));
+        lending.refresh_reserve(reserve_key);
+        lending.modify_reserve_config(reserve_key, flat_rate_config(10));
+        env.advance_block_time(MS_PER_YEAR);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        // The reserve books the stable position at its 30%, not the curve's 10%
+        let debt = lending.get_obligation(borrower).unwrap().borrows[0].borrowed_amount_wads;
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.liquidity.stable_borrowed_amount_wads, reserve.liquidity.borrowed_amount_wads);
+        assert_eq!(reserve.liquidity.average_stable_borrow_rate, Rate::from_percent(30));
+        assert_eq!(
+            reserve.liquidity.borrowed_amount_wads.try_floor_u64().unwrap(),
+            debt.try_floor_u64().unwrap()
+        );
+
+        env.set_caller(borrower);
+        lending.repay_obligation_liquidity(reserve_key, U256::max_value());
+
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert!(reserve.liquidity.borrowed_amount_wads < Decimal::one());
+        assert!(lending.get_obligation(borrower).unwrap().borrows[0].borrowed_amount_wads < Decimal::one());
+    }
+
+    #[test]
+    fn test_deposit_and_withdraw_fees_go_to_protocol() {
+        let (env, mut lending) = setup();
+        let mut config = test_config();
+        config.fees.deposit_fee_wad = U256::from(10_000_000_000_000_000u64); // 1%
+        config.fees.withdraw_fee_wad = U256::from(20_000_000_000_000_000u64); // 2%
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), config, None, 9, None);
+
+        env.set_caller(env.get_account(3));
+        assert_eq!(
+            lending.deposit_reserve_liquidity(reserve_key, TokenAmount::from(1_000u64)),
+            TokenAmount::from(990u64)
+        );
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.liquidity.accumulated_protocol_fees_wads, Decimal::from(10u64));
+        assert_eq!(reserve.liquidity.total_supply(), U256::from(1_990u64));
+
+        lending.refresh_reserve(reserve_key);
+        assert_eq!(lending.redeem_reserve_collateral(reserve_key, U256::from(995u64)), U256::from(975u64));
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.liquidity.accumulated_protocol_fees_wads, Decimal::from(30u64));
+    }
+
+    #[test]
+    fn test_init_reserve_validates_mint_decimals() {
+        let (_env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 6, None);
+        assert_eq!(lending.get_reserve(reserve_key).unwrap().liquidity.mint_decimals, 6);
+
+        assert_eq!(
+            lending.try_init_reserve(U256::from(1_000u64), test_config(), None, 30, None),
+            Err(LendingError::InvalidConfig.into())
+        );
+    }
+
+    #[test]
+    fn test_collateral_liquidity_conversions_are_inverse() {
+        let env = odra_test::env();
+        let mut reserve = test_reserve(&env, U256::from(1_000u64));
+        reserve.collateral.mint(U256::from(800u64)).unwrap();
+        reserve.liquidity.borrow(Decimal::from(300u64)).unwrap();
+
+        // Flooring the collateral loses under one unit's worth of liquidity
+        let unit_value = reserve.collateral_to_liquidity(U256::one()).unwrap() + U256::one();
+        for amount in [1u64, 7, 333, 1_000] {
+            let amount = U256::from(amount);
+            let collateral = reserve.liquidity_to_collateral(amount).unwrap();
+            let round_trip = reserve.collateral_to_liquidity(collateral).unwrap();
+            assert!(round_trip <= amount && amount - round_trip <= unit_value);
+        }
+    }
+
+    #[test]
+    fn test_conversion_queries() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+
+        assert_eq!(lending.liquidity_to_collateral(reserve_key, U256::from(250u64)), Some(U256::from(250u64)));
+        assert_eq!(lending.collateral_to_liquidity(reserve_key, U256::from(250u64)), Some(U256::from(250u64)));
+        assert_eq!(lending.collateral_to_liquidity(env.get_account(9), U256::from(250u64)), None);
+    }
+
+    #[test]
+    fn test_ensure_market_rejects_foreign_reserve() {
+        let env = odra_test::env();
+        let reserve = test_reserve(&env, U256::from(1_000u64));
+
+        assert_eq!(reserve.ensure_market(env.get_account(0)), Ok(()));
+        assert_eq!(
+            reserve.ensure_market(env.get_account(1)),
+            Err(LendingError::InvalidAccountInput)
+        );
+    }
+
+    #[test]
+    fn test_set_reserve_fee_receiver() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+        let treasury = env.get_account(8);
+
+        assert_eq!(
+            lending.try_set_reserve_fee_receiver(reserve_key, zero_address()),
+            Err(LendingError::InvalidAccountInput.into())
+        );
+
+        lending.set_reserve_fee_receiver(reserve_key, treasury);
+        assert_eq!(lending.get_reserve_fee_receiver(reserve_key), Some(treasury));
+        assert!(env.emitted_event(
+            &lending,
+            ReserveFeeReceiverUpdated {
+                reserve: reserve_key,
+                old_receiver: env.get_account(0),
+                new_receiver: treasury,
+                slot: env.block_time(),
+            }
+        ));
+
+        env.set_caller(treasury);
+        assert_eq!(
+            lending.try_set_reserve_fee_receiver(reserve_key, treasury),
+            Err(LendingError::InvalidMarketOwner.into())
+        );
+    }
+
+    #[test]
+    fn test_refresh_obligations_skips_stale_reserves() {
+        let (env, mut lending) = setup();
+        let fresh_reserve = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+        let stale_reserve = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+
+        let users = [env.get_account(3), env.get_account(4), env.get_account(5)];
+        for (user, reserve_key) in users.iter().zip([fresh_reserve, fresh_reserve, stale_reserve]) {
+            env.set_caller(*user);
+            lending.init_obligation();
+            lending.deposit_obligation_collateral(reserve_key, U256::from(1_000u64));
+        }
+
+        env.advance_block_time(DEFAULT_STALE_AFTER_SLOTS);
+        lending.refresh_reserve(fresh_reserve);
+
+        assert_eq!(lending.refresh_obligations(users.to_vec()), vec![users[0], users[1]]);
+        let stale = lending.get_obligation(users[2]).unwrap();
+        assert_eq!(stale.deposited_value, Decimal::zero());
+    }
+
+    #[test]
+    fn test_reserve_invariants() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+        assert_eq!(lending.try_verify_reserve(reserve_key), Ok(()));
+
+        let mut reserve = test_reserve(&env, U256::from(1_000u64));
+        reserve.collateral.mint(U256::from(1_000u64)).unwrap();
+        assert_eq!(reserve.check_invariants(Decimal::zero()), Ok(()));
+
+        // Collateral worth less than the initial 1:1
+        let mut diluted = reserve.clone();
+        diluted.collateral.mint(U256::from(1_000u64)).unwrap();
+        assert_eq!(diluted.check_invariants(Decimal::zero()), Err(LendingError::ReserveInvariantViolated));
+        // unless suppliers absorbed that much bad debt
+        assert_eq!(diluted.check_invariants(Decimal::from(1_000u64)), Ok(()));
+
+        // Borrows with no collateral supply
+        let mut unbacked = test_reserve(&env, U256::zero());
+        unbacked.liquidity.borrowed_amount_wads = Decimal::from(10u64);
+        assert_eq!(unbacked.check_invariants(Decimal::zero()), Err(LendingError::ReserveInvariantViolated));
+
+        // Protocol fees exceeding liquidity
+        let mut overdrawn = reserve.clone();
+        overdrawn.liquidity.accumulated_protocol_fees_wads = Decimal::from(2_000u64);
+        assert_eq!(overdrawn.check_invariants(Decimal::zero()), Err(LendingError::ReserveInvariantViolated));
+    }
+
+    #[test]
+    fn test_get_obligation_ltv() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        let expected = obligation.borrowed_value.try_div(obligation.deposited_value).unwrap();
+        let (current, allowed) = lending.get_obligation_ltv(borrower).unwrap();
+
+        assert_eq!(current, expected);
+        assert_eq!(current, Decimal::from_percent(10));
+        assert_eq!(allowed, Decimal::from_percent(50));
+        assert_eq!(lending.get_obligation_ltv(env.get_account(9)), None);
+    }
+
+    #[test]
+    fn test_borrow_host_fee_routing() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+        let host = env.get_account(8);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(100_000u64));
+        lending.refresh_obligation(borrower);
+
+        // 1% borrow fee on 10_000, a tenth of which goes to the host
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(10_000u64), U256::zero(), 0, RateMode::Variable, Some(host), Decimal::zero());
+        assert!(env.emitted_event(
+            &lending,
+            HostFeePaid { reserve: reserve_key, receiver: host, amount: U256::from(10u64), slot: env.block_time() }
+        ));
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.liquidity.accumulated_protocol_fees_wads, Decimal::zero());
+
+        // Without a host the fee is kept as protocol fees
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(10_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.liquidity.accumulated_protocol_fees_wads, Decimal::from(10u64));
+    }
+
+    #[test]
+    fn test_set_reserve_oracle_forces_refresh() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+        let new_oracle = env.get_account(7);
+
+        lending.set_reserve_oracle(reserve_key, new_oracle, 18);
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.liquidity.oracle_pubkey, new_oracle);
+
+        env.set_caller(env.get_account(3));
+        assert_eq!(
+            lending.try_deposit_reserve_liquidity(reserve_key, TokenAmount::from(100u64)),
+            Err(LendingError::ReserveStale.into())
+        );
+        lending.refresh_reserve(reserve_key);
+        lending.deposit_reserve_liquidity(reserve_key, TokenAmount::from(100u64));
+    }
+
+    fn valued_obligation(env: &HostEnv, deposited: u64, unhealthy: u64, borrowed: u64) -> Obligation {
+        let mut obligation = Obligation::new(InitObligationParams {
+            current_slot: 0,
+            lending_market: env.get_account(0),
+            owner: env.get_account(3),
+            deposits: vec![],
+            borrows: vec![],
+        });
+        obligation.deposited_value = Decimal::from(deposited);
+        obligation.unhealthy_borrow_value = Decimal::from(unhealthy);
+        obligation.borrowed_value = Decimal::from(borrowed);
+        obligation
+    }
+
+    #[test]
+    fn test_dynamic_liquidation_bonus_scales_with_severity() {
+        let env = odra_test::env();
+        let mut reserve = test_reserve(&env, U256::zero());
+        reserve.config.dynamic_liquidation_bonus = true;
+        reserve.config.max_liquidation_bonus = 20;
+
+        // Right at the threshold: the configured minimum
+        let just_unhealthy = valued_obligation(&env, 10_000, 8_000, 8_000);
+        assert_eq!(reserve.liquidation_bonus_rate(&just_unhealthy).unwrap(), Rate::from_percent(5));
+
+        // 90% of the way from threshold to fully underwater
+        let deep = valued_obligation(&env, 10_000, 8_000, 9_800);
+        assert_eq!(
+            reserve.liquidation_bonus_rate(&deep).unwrap(),
+            Rate::from_scaled_val(185_000_000_000_000_000)
+        );
+
+        let underwater = valued_obligation(&env, 10_000, 8_000, 12_000);
+        assert_eq!(reserve.liquidation_bonus_rate(&underwater).unwrap(), Rate::from_percent(20));
+
+        // The flag off keeps the fixed bonus
+        reserve.config.dynamic_liquidation_bonus = false;
+        assert_eq!(reserve.liquidation_bonus_rate(&deep).unwrap(), Rate::from_percent(5));
+    }
+
+    #[test]
+    fn test_exchange_rate_grows_with_interest() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(10_000u64), flat_rate_config(10), None, 9, None);
+        assert_eq!(lending.get_exchange_rate(reserve_key), Some(Decimal::one()));
+        assert_eq!(lending.get_exchange_rate(env.get_account(9)), None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(4_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        env.advance_block_time(MS_PER_YEAR);
+        lending.refresh_reserve(reserve_key);
+        assert!(lending.get_exchange_rate(reserve_key).unwrap() > Decimal::one());
+    }
+
+    #[test]
+    fn test_collateral_weight_cap_discounts_dominant_collateral() {
+        let (env, mut lending) = setup();
+        let mut capped_config = test_config();
+        capped_config.collateral_weight_cap = 60;
+        let dominant = lending.init_reserve(U256::from(1_000_000u64), capped_config, None, 9, None);
+        let minor = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(dominant, U256::from(9_000u64));
+        lending.deposit_obligation_collateral(minor, U256::from(1_000u64));
+        // Deposits apply the cap too
+        assert_eq!(lending.get_obligation(borrower).unwrap().allowed_borrow_value, Decimal::from(3_500u64));
+        lending.refresh_obligation(borrower);
+
+        // Uncapped capacity is 4_500 + 500; the dominant side is held to 60%
+        // of 5_000
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.deposited_value, Decimal::from(10_000u64));
+        assert_eq!(obligation.allowed_borrow_value, Decimal::from(3_500u64));
+
+        lending.refresh_obligation_partial(borrower, vec![dominant]);
+        assert_eq!(lending.get_obligation(borrower).unwrap().allowed_borrow_value, Decimal::from(3_500u64));
+    }
+
+    #[test]
+    fn test_withdraw_with_auto_repay_repays_shortfall() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        lending.refresh_obligation(borrower);
+
+        // Dropping 9_000 of 10_000 collateral would leave 500 of borrow
+        // capacity against 1_000 of debt
+        assert_eq!(
+            lending.try_withdraw_obligation_collateral(reserve_key, U256::from(9_000u64)),
+            Err(LendingError::WithdrawTooLarge.into())
+        );
+
+        let before = lending.get_reserve(reserve_key).unwrap();
+        lending.withdraw_with_auto_repay(reserve_key, reserve_key, U256::from(9_000u64));
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.deposits[0].deposited_amount, U256::from(1_000u64));
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::from(500u64));
+
+        // The 500 of seized collateral was redeemed into the liquidity that
+        // repaid the debt
+        let after = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(after.collateral.mint_total_supply, before.collateral.mint_total_supply - U256::from(500u64));
+        assert_eq!(after.liquidity.available_amount, before.liquidity.available_amount);
+        assert_eq!(after.liquidity.borrowed_amount_wads, Decimal::from(500u64));
+    }
+
+    #[test]
+    fn test_withdraw_with_auto_repay_rejects_unfixable_shortfall() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        env.set_caller(env.get_account(0));
+        let other_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        env.set_caller(borrower);
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(other_key, U256::from(2_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        lending.refresh_obligation(borrower);
+
+        // Withdrawing 8_000 leaves 1_000 of capacity against 3_000 of debt,
+        // more than the 1_000 borrowed from the repay reserve can cover
+        assert_eq!(
+            lending.try_withdraw_with_auto_repay(reserve_key, reserve_key, U256::from(8_000u64)),
+            Err(LendingError::WithdrawTooLarge.into())
+        );
+    }
+
+    #[test]
+    fn test_withdraw_with_auto_repay_swaps_at_oracle_prices() {
+        let (env, mut lending) = setup();
+        let mock_price = Decimal::from(1_000_000_000u64);
+        let keys = test_bootstrap(&env, &mut lending, vec![
+            (test_config(), U256::from(1_000_000u64), mock_price),
+            (test_config(), U256::from(1_000_000u64), Decimal::from(2_000_000_000u64)),
+        ]);
+        let (collateral_key, borrow_key) = (keys[0], keys[1]);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(collateral_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        // 2_000 tokens at 2 each owe 4_000 of value
+        lending.borrow_obligation_liquidity(borrow_key, U256::from(2_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        lending.refresh_reserve(collateral_key);
+        lending.refresh_reserve(borrow_key);
+        lending.refresh_obligation(borrower);
+
+        let collateral_before = lending.get_reserve(collateral_key).unwrap();
+        let borrow_before = lending.get_reserve(borrow_key).unwrap();
+
+        // Withdrawing 4_000 leaves 3_000 of capacity, so 1_000 of the
+        // collateral's value buys 500 repay tokens
+        lending.withdraw_with_auto_repay(collateral_key, borrow_key, U256::from(4_000u64));
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.deposits[0].deposited_amount, U256::from(6_000u64));
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::from(1_500u64));
+
+        let collateral_after = lending.get_reserve(collateral_key).unwrap();
+        assert_eq!(
+            collateral_after.liquidity.available_amount,
+            collateral_before.liquidity.available_amount - U256::from(1_000u64)
+        );
+        let borrow_after = lending.get_reserve(borrow_key).unwrap();
+        assert_eq!(
+            borrow_after.liquidity.available_amount,
+            borrow_before.liquidity.available_amount + U256::from(500u64)
+        );
+        assert_eq!(borrow_after.liquidity.borrowed_amount_wads, Decimal::from(1_500u64));
+    }
+
+    #[test]
+    fn test_total_protocol_fees_sums_reserves() {
+        let (env, mut lending) = setup();
+        assert_eq!(lending.total_protocol_fees(), Decimal::zero());
+
+        let mut config = flat_rate_config(30);
+        config.reserve_factor = 20;
+        let reserve_keys: Vec<Address> = (0..2)
+            .map(|_| lending.init_reserve(U256::from(1_000_000u64), config.clone(), None, 9, None))
+            .collect();
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_keys[0], U256::from(100_000u64));
+        for reserve_key in reserve_keys.iter() {
+            reserve_keys.iter().for_each(|key| lending.refresh_reserve(*key));
+            lending.refresh_obligation(borrower);
+            lending.borrow_obligation_liquidity(*reserve_key, U256::from(10_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        }
+
+        env.advance_block_time(MS_PER_YEAR / 12);
+        let mut expected = Decimal::zero();
+        for reserve_key in reserve_keys.iter() {
+            lending.refresh_reserve(*reserve_key);
+            let reserve = lending.get_reserve(*reserve_key).unwrap();
+            assert!(reserve.liquidity.accumulated_protocol_fees_wads > Decimal::zero());
+            expected = expected.try_add(
+                market_value(U256::one(), reserve.liquidity.market_price, reserve.liquidity.mint_decimals)
+                    .unwrap()
+                    .try_mul(reserve.liquidity.accumulated_protocol_fees_wads)
+                    .unwrap()
+            ).unwrap();
+        }
+
+        assert_eq!(lending.total_protocol_fees(), expected);
+    }
+
+    #[test]
+    fn test_same_slot_borrow_and_repay_charges_only_fee() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), flat_rate_config(30), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+
+        // The reserve hasn't accrued for a while when the borrow lands
+        env.advance_block_time(50_000);
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(1_000u64), U256::from(990u64), 0, RateMode::Variable, None, Decimal::zero());
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::from(1_000u64));
+
+        lending.repay_obligation_liquidity(reserve_key, U256::from(1_000u64));
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::zero());
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.liquidity.borrowed_amount_wads, Decimal::zero());
+    }
+
+    #[test]
+    fn test_topping_up_a_borrow_charges_no_back_interest() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), flat_rate_config(30), None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(100_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(10_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        // Both stay fresh, so the top-up is what brings the reserve's
+        // cumulative rate forward
+        env.advance_block_time(DEFAULT_STALE_AFTER_SLOTS / 2);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(10_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        // Only the first 10_000 owes interest for the gap
+        let debt = lending.get_obligation(borrower).unwrap().borrows[0].borrowed_amount_wads;
+        let reserve_debt = lending.get_reserve(reserve_key).unwrap().liquidity.borrowed_amount_wads;
+        let drift = if debt > reserve_debt { debt.try_sub(reserve_debt) } else { reserve_debt.try_sub(debt) }.unwrap();
+        assert!(drift < Decimal::from_fraction(U256::one(), U256::from(1_000_000u64)).unwrap());
+    }
+
+    #[test]
+    fn test_flash_loan_respects_reserve_flag() {
+        let (env, mut lending) = setup();
+        let mut disabled_config = test_config();
+        disabled_config.flash_loan_enabled = false;
+        let disabled = lending.init_reserve(U256::from(1_000_000u64), disabled_config, None, 9, None);
+        let enabled = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+
+        env.set_caller(env.get_account(3));
+        assert_eq!(
+            lending.try_flash_loan(disabled, U256::from(1_000u64)),
+            Err(LendingError::FlashLoanDisabled.into())
+        );
+        lending.flash_loan(enabled, U256::from(1_000u64));
+    }
+
+    #[test]
+    fn test_config_rejects_full_flash_loan_fee() {
+        let mut config = test_config();
+        config.fees.flash_loan_fee_wad = Decimal::one().raw();
+        assert_eq!(config.validate(), Err(LendingError::InvalidConfig));
+
+        config.flash_loan_enabled = false;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_init_reserve_with_exchange_rate_peg() {
+        let (env, mut lending) = setup();
+        let peg = Decimal::from(2u64);
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, Some(peg));
+
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.collateral.mint_total_supply, U256::from(500_000u64));
+        assert_eq!(lending.get_exchange_rate(reserve_key), Some(peg));
+
+        env.set_caller(env.get_account(3));
+        assert_eq!(lending.deposit_reserve_liquidity(reserve_key, TokenAmount::from(1_000u64)), TokenAmount::from(500u64));
+
+        env.set_caller(env.get_account(0));
+        assert_eq!(
+            lending.try_init_reserve(U256::from(1_000u64), test_config(), None, 9, Some(Decimal::from_percent(50))),
+            Err(LendingError::InvalidConfig.into())
+        );
+    }
+
+    #[test]
+    fn test_liquidation_rejects_outdated_obligation() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        // The reserve reprices after the obligation's last refresh, while
+        // both are still inside the staleness window
+        env.advance_block_time(1_000);
+        lending.refresh_reserve(reserve_key);
+
+        env.set_caller(env.get_account(6));
+        assert_eq!(
+            lending.try_liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64)),
+            Err(LendingError::ObligationStale.into())
+        );
+
+        lending.refresh_obligation(borrower);
+        assert_eq!(
+            lending.try_liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64)),
+            Err(LendingError::ObligationHealthy.into())
+        );
+    }
+
+    #[test]
+    fn test_liquidation_price_buffer() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        // 1_000 borrowed at an 80% threshold is liquidatable at 1_250 of the
+        // 10_000 deposited
+        assert_eq!(lending.liquidation_price_buffer(borrower), Some(Decimal::from(8_750u64)));
+        assert_eq!(lending.liquidation_price_buffer(env.get_account(9)), None);
+
+        env.set_caller(env.get_account(0));
+        let mut risky_config = test_config();
+        risky_config.loan_to_value_ratio = 0;
+        risky_config.liquidation_threshold = 5;
+        lending.modify_reserve_config(reserve_key, risky_config);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+        assert_eq!(lending.liquidation_price_buffer(borrower), Some(Decimal::zero()));
+    }
+
+    #[test]
+    fn test_accrual_without_borrows_only_moves_slot() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), flat_rate_config(30), None, 9, None);
+        let opened_at = env.block_time();
+
+        env.advance_block_time(10_000);
+        lending.refresh_reserve(reserve_key);
+
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(reserve.last_accrual_slot, opened_at + 10_000);
+        assert_eq!(reserve.liquidity.cumulative_borrow_rate_wads, Decimal::one());
+        assert_eq!(reserve.liquidity.accumulated_protocol_fees_wads, Decimal::zero());
+    }
+
+    #[test]
+    fn test_obligation_v1_upgrade_defaults_new_fields() {
+        let env = odra_test::env();
+        let legacy = ObligationV1 {
+            lending_market: env.get_account(0),
+            owner: env.get_account(3),
+            deposits: vec![Collateral {
+                deposit_reserve: env.get_account(7),
+                deposited_amount: U256::from(10_000u64),
+                market_value: Decimal::from(10_000u64),
+            }],
+            borrows: vec![],
+            deposited_value: Decimal::from(10_000u64),
+            borrowed_value: Decimal::zero(),
+            allowed_borrow_value: Decimal::from(5_000u64),
+            unhealthy_borrow_value: Decimal::from(8_000u64),
+            last_update: LastUpdate { slot: 42, stale: false },
+        };
+
+        let obligation = legacy.upgrade();
+        assert_eq!(obligation.version, OBLIGATION_VERSION);
+        assert_eq!(obligation.first_unhealthy_slot, None);
+        assert_eq!(obligation.last_deposit_slot, 0);
+        assert_eq!(obligation.deposits[0].deposited_amount, U256::from(10_000u64));
+        assert_eq!(obligation.allowed_borrow_value, Decimal::from(5_000u64));
+        assert!(obligation.last_update.stale);
+    }
+
+    #[test]
+    fn test_migrate_obligation_rejects_current_layout() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+
+        assert_eq!(lending.get_obligation(borrower).unwrap().version, OBLIGATION_VERSION);
+        assert_eq!(
+            lending.try_migrate_obligation(borrower),
+            Err(LendingError::AlreadyInitialized.into())
+        );
+        assert_eq!(
+            lending.try_migrate_obligation(env.get_account(4)),
+            Err(LendingError::InvalidObligationOwner.into())
+        );
+    }
+
+    #[test]
+    fn test_borrow_per_tx_cap() {
+        let (env, mut lending) = setup();
+        let mut config = test_config();
+        config.max_borrow_per_tx = U256::from(1_000u64);
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), config, None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+
+        assert_eq!(
+            lending.try_borrow_obligation_liquidity(reserve_key, U256::from(1_001u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero()),
+            Err(LendingError::BorrowTooLarge.into())
+        );
+
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(600u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(401u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::from(1_001u64));
+    }
+
+    #[test]
+    fn test_deposit_returns_health_factor() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        // 12_000 deposited at an 80% threshold against 1_000 borrowed
+        let health_factor = lending.deposit_obligation_collateral(reserve_key, U256::from(2_000u64));
+        let expected = Decimal::from(96u64).try_div(10u64).unwrap();
+        assert_eq!(health_factor, expected);
+        assert_eq!(lending.get_obligation_health(borrower), Some(expected));
+
+        lending.refresh_obligation(borrower);
+        assert_eq!(lending.get_obligation_health(borrower), Some(expected));
+    }
+
+    #[test]
+    fn test_reserve_available_value() {
+        let (env, mut lending) = setup();
+        // 2.5 tokens of a 6-decimal mint at the mock price of 1e9
+        let reserve_key = lending.init_reserve(U256::from(2_500_000u64), test_config(), None, 6, None);
+
+        assert_eq!(lending.get_reserve_available_value(reserve_key), Some(Decimal::from(2_500_000_000u64)));
+        assert_eq!(lending.get_reserve_available_value(env.get_account(9)), None);
+    }
+
+    #[test]
+    fn test_borrower_cannot_self_liquidate() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+
+        env.set_caller(env.get_account(0));
+        let mut risky_config = test_config();
+        risky_config.loan_to_value_ratio = 0;
+        risky_config.liquidation_threshold = 5;
+        lending.modify_reserve_config(reserve_key, risky_config);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        env.set_caller(borrower);
+        assert_eq!(
+            lending.try_liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64)),
+            Err(LendingError::SelfLiquidation.into())
+        );
+        assert_eq!(
+            lending.try_liquidate_obligation_proportional(borrower, reserve_key, U256::from(500u64)),
+            Err(LendingError::SelfLiquidation.into())
+        );
+
+        env.set_caller(env.get_account(6));
+        lending.liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64));
+    }
+
+    #[test]
+    fn test_obligation_net_apy_sign() {
+        let (env, mut lending) = setup();
+        let high_rate = lending.init_reserve(U256::from(10_000u64), flat_rate_config(20), None, 9, None);
+        let low_rate = lending.init_reserve(U256::from(1_000_000u64), flat_rate_config(10), None, 9, None);
+
+        let mut open = |user: Address, deposit_reserve: Address, deposit: u64, borrow_reserve: Address, borrow: u64| {
+            env.set_caller(user);
+            lending.init_obligation();
+            lending.deposit_obligation_collateral(deposit_reserve, U256::from(deposit));
+            lending.refresh_reserve(high_rate);
+            lending.refresh_reserve(low_rate);
+            lending.refresh_obligation(user);
+            lending.borrow_obligation_liquidity(borrow_reserve, U256::from(borrow), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        };
+        // Half the high-rate reserve is lent out, so its suppliers earn well
+        open(env.get_account(4), low_rate, 100_000, high_rate, 5_000);
+        open(env.get_account(3), high_rate, 10_000, low_rate, 1_000);
+        open(env.get_account(5), low_rate, 1_000, high_rate, 400);
+
+        lending.refresh_reserve(high_rate);
+        lending.refresh_reserve(low_rate);
+        for user in [env.get_account(3), env.get_account(5)] {
+            lending.refresh_obligation(user);
+        }
+
+        let earner = lending.obligation_net_apy(env.get_account(3));
+        assert!(!earner.is_negative());
+        assert!(earner.magnitude() > Decimal::zero());
+        assert!(lending.obligation_net_apy(env.get_account(5)).is_negative());
     }
-}
 
-#[derive(Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct LastUpdate {
-    pub slot: u64,
-    pub stale: bool,
-}
+    #[test]
+    fn test_deposit_reserve_liquidity_batch() {
+        let (env, mut lending) = setup();
+        let first = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+        let second = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+
+        env.set_caller(env.get_account(3));
+        let minted = lending.deposit_reserve_liquidity_batch(vec![
+            (first, U256::from(500u64)),
+            (second, U256::from(200u64)),
+        ]);
+        assert_eq!(minted, vec![U256::from(500u64), U256::from(200u64)]);
+        assert_eq!(lending.get_reserve(first).unwrap().liquidity.available_amount, U256::from(1_500u64));
+        assert_eq!(lending.get_reserve(second).unwrap().liquidity.available_amount, U256::from(1_200u64));
+
+        // The repeated reserve is stale by then, so the first deposit unwinds too
+        lending.refresh_reserve(first);
+        lending.refresh_reserve(second);
+        assert_eq!(
+            lending.try_deposit_reserve_liquidity_batch(vec![
+                (first, U256::from(100u64)),
+                (first, U256::from(100u64)),
+            ]),
+            Err(LendingError::ReserveStale.into())
+        );
+        assert_eq!(lending.get_reserve(first).unwrap().liquidity.available_amount, U256::from(1_500u64));
+    }
 
-impl LastUpdate {
-    pub fn update_slot(&mut self, slot: u64) {
-        self.slot = slot;
-        self.stale = false;
+    #[test]
+    fn test_borrow_min_health_factor() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(100_000u64), test_config(), None, 9, None);
+        let borrower = env.get_account(3);
+
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+
+        // Health factor after the borrow is 8_000 / 1_000 = 8; the protocol
+        // allows it but the borrower asked for 10
+        assert_eq!(
+            lending.try_borrow_obligation_liquidity(reserve_key, U256::from(1_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::from(10u64)),
+            Err(LendingError::BorrowTooLarge.into())
+        );
+
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(1_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::from(5u64));
+        assert_eq!(lending.get_obligation(borrower).unwrap().borrows.len(), 1);
     }
-    
-    pub fn mark_stale(&mut self) {
-        self.stale = true;
+
+    #[test]
+    fn test_refresh_reserve_emits_snapshot() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        let before = lending.get_reserve(reserve_key).unwrap();
+
+        env.advance_block_time(1_000);
+        lending.refresh_reserve(reserve_key);
+
+        let reserve = lending.get_reserve(reserve_key).unwrap();
+        assert!(reserve.liquidity.cumulative_borrow_rate_wads > before.liquidity.cumulative_borrow_rate_wads);
+        assert!(env.emitted_event(
+            &lending,
+            ReserveRefreshed {
+                reserve: reserve_key,
+                cumulative_borrow_rate_wads: reserve.liquidity.cumulative_borrow_rate_wads,
+                available_amount: reserve.liquidity.available_amount,
+                borrowed_amount_wads: reserve.liquidity.borrowed_amount_wads,
+                market_price: reserve.liquidity.market_price,
+                slot: env.block_time(),
+            }
+        ));
     }
-    
-    pub fn is_stale(&self, current_slot: u64) -> bool {
-        self.stale || self.slot < current_slot
+
+    #[test]
+    fn test_flash_loan_fee_above_u128() {
+        let mut config = test_config();
+        // 0.3%, a tenth of it to the host
+        config.fees.flash_loan_fee_wad = U256::from(3_000_000_000_000_000u64);
+        config.fees.host_fee_percentage = 10;
+
+        let amount = U256::from(u128::MAX) * U256::from(1_000u64);
+        let (origination_fee, host_fee) = config.fees
+            .calculate_flash_loan_fees(Decimal::try_from_u256(amount).unwrap())
+            .unwrap();
+        let fee = origination_fee.try_add(host_fee).unwrap();
+        assert_eq!(fee.try_floor_u256().unwrap(), amount * U256::from(3u64) / U256::from(1_000u64));
+
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(amount, config, None, 9, None);
+        env.set_caller(env.get_account(3));
+        lending.flash_loan(reserve_key, U256::max_value());
+        assert_eq!(lending.get_reserve(reserve_key).unwrap().liquidity.available_amount, amount);
     }
-}
 
-#[derive(Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct Collateral {
-    pub deposit_reserve: Address,
-    pub deposited_amount: U256,
-    pub market_value: Decimal,
-}
+    #[test]
+    fn test_borrow_against_ltv_zero_collateral() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(100_000u64), test_config(), None, 9, None);
+        let borrower = env.get_account(3);
+
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+
+        let mut config = test_config();
+        config.loan_to_value_ratio = 0;
+        env.set_caller(env.get_account(0));
+        lending.modify_reserve_config(reserve_key, config);
+
+        env.set_caller(borrower);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+        assert_eq!(
+            lending.try_borrow_obligation_liquidity(reserve_key, U256::from(100u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero()),
+            Err(LendingError::ReserveCollateralDisabled.into())
+        );
+    }
 
-impl TryAdd for U256 {
-    fn try_add(self, rhs: Self) -> Result<Self, LendingError> {
-        self.checked_add(rhs).ok_or(LendingError::MathOverflow)
+    #[test]
+    fn test_bootstrap_seeds_reserves() {
+        let (env, mut lending) = setup();
+        let keys = test_bootstrap(&env, &mut lending, vec![
+            (test_config(), U256::from(1_000u64), Decimal::from(2u64)),
+            (flat_rate_config(5), U256::from(50_000u64), Decimal::from(30_000u64)),
+        ]);
+        assert_eq!(keys.len(), 2);
+
+        let first = lending.get_reserve(keys[0]).unwrap();
+        assert_eq!(first.liquidity.available_amount, U256::from(1_000u64));
+        assert_eq!(first.liquidity.market_price, Decimal::from(2u64));
+        assert_eq!(first.liquidity.oracle_pubkey, env.get_account(10));
+        assert!(!first.last_update.stale);
+
+        let second = lending.get_reserve(keys[1]).unwrap();
+        assert_eq!(second.liquidity.available_amount, U256::from(50_000u64));
+        assert_eq!(second.liquidity.market_price, Decimal::from(30_000u64));
+        assert_eq!(second.config.min_borrow_rate, 5);
     }
-}
 
-impl TrySub for U256 {
-    fn try_sub(self, rhs: Self) -> Result<Self, LendingError> {
-        self.checked_sub(rhs).ok_or(LendingError::MathOverflow)
+    #[test]
+    fn test_set_oracle_price_requires_owner() {
+        let (env, mut lending) = setup();
+        env.set_caller(env.get_account(3));
+        assert_eq!(
+            lending.try_set_oracle_price(env.get_account(10), U256::one()),
+            Err(LendingError::InvalidMarketOwner.into())
+        );
     }
-}
 
-impl Collateral {
-    pub fn deposit(&mut self, amount: U256) -> Result<(), LendingError> {
-        self.deposited_amount = self.deposited_amount.try_add(amount)?;
-        Ok(())
+    #[test]
+    fn test_oracle_price_decimals_normalize() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+        let oracle = env.get_account(10);
+
+        // Chainlink-style feed: 25_000.5 with 8 decimals
+        lending.set_reserve_oracle(reserve_key, oracle, 8);
+        lending.set_oracle_price(oracle, U256::from(2_500_050_000_000u64));
+        lending.refresh_reserve(reserve_key);
+
+        let expected = Decimal::from(25_000u64).try_add(Decimal::from_percent(50)).unwrap();
+        assert_eq!(lending.get_reserve(reserve_key).unwrap().liquidity.market_price, expected);
+
+        assert_eq!(
+            lending.try_set_reserve_oracle(reserve_key, oracle, MAX_PRICE_DECIMALS + 1),
+            Err(LendingError::InvalidOracleConfig.into())
+        );
     }
-}
 
-impl SchemaCustomTypes for LendingError {
-    fn schema_custom_types() -> BTreeMap<String, odra::schema::casper_contract_schema::CustomType> {
-        BTreeMap::new()
+    #[test]
+    fn test_is_liquidatable() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+
+        // The borrow itself leaves the obligation stale
+        assert_eq!(lending.try_is_liquidatable(borrower), Err(LendingError::ObligationStale.into()));
+
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+        assert!(!lending.is_liquidatable(borrower));
+
+        // 10_000 deposited at a 5% threshold no longer covers the 1_010 owed
+        let mut config = test_config();
+        config.loan_to_value_ratio = 0;
+        config.liquidation_threshold = 5;
+        env.set_caller(env.get_account(0));
+        lending.modify_reserve_config(reserve_key, config);
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+        assert!(lending.is_liquidatable(borrower));
+
+        // A reserve refreshed after the obligation leaves its values behind
+        env.advance_block_time(1);
+        lending.refresh_reserve(reserve_key);
+        assert_eq!(lending.try_is_liquidatable(borrower), Err(LendingError::ObligationStale.into()));
+        lending.refresh_obligation(borrower);
+        assert!(lending.is_liquidatable(borrower));
+
+        env.advance_block_time(DEFAULT_STALE_AFTER_SLOTS);
+        assert_eq!(lending.try_is_liquidatable(borrower), Err(LendingError::ObligationStale.into()));
     }
-}
 
-#[derive(Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct Liquidity {
-    pub borrow_reserve: Address,
-    pub borrowed_amount_wads: Decimal,
-    pub market_value: Decimal,
-    pub cumulative_borrow_rate_wads: Decimal,
-}
+    #[test]
+    fn test_deposit_fee_tiers() {
+        let mut config = test_config();
+        config.fees.deposit_fee_wad = U256::from(10_000_000_000_000_000u64); // 1%
+        config.fee_tiers = vec![(U256::from(10_000u64), 50), (U256::from(100_000u64), 0)];
+
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), config.clone(), None, 9, None);
+        env.set_caller(env.get_account(3));
+
+        // Below every tier: the base 1%
+        assert_eq!(lending.deposit_reserve_liquidity(reserve_key, TokenAmount::from(1_000u64)), TokenAmount::from(990u64));
+        // 0.5% tier
+        lending.refresh_reserve(reserve_key);
+        let fees_before = lending.get_reserve(reserve_key).unwrap().liquidity.accumulated_protocol_fees_wads;
+        lending.deposit_reserve_liquidity(reserve_key, TokenAmount::from(20_000u64));
+        let fees_after = lending.get_reserve(reserve_key).unwrap().liquidity.accumulated_protocol_fees_wads;
+        assert_eq!(fees_after.try_sub(fees_before).unwrap(), Decimal::from(100u64));
+        // Fee waived
+        lending.refresh_reserve(reserve_key);
+        lending.deposit_reserve_liquidity(reserve_key, TokenAmount::from(100_000u64));
+        assert_eq!(lending.get_reserve(reserve_key).unwrap().liquidity.accumulated_protocol_fees_wads, fees_after);
+
+        config.fee_tiers = vec![(U256::from(100_000u64), 0), (U256::from(10_000u64), 50)];
+        assert_eq!(config.validate(), Err(LendingError::InvalidConfig));
+    }
 
-impl Liquidity {
-    pub fn borrow(&mut self, amount: U256) -> Result<(), LendingError> {
-        let amount_decimal = Decimal::from(amount.as_u128());
-        self.borrowed_amount_wads = self.borrowed_amount_wads.try_add(amount_decimal)?;
-        Ok(())
+    #[test]
+    fn test_deposit_fee_tiers_must_not_increase() {
+        let mut config = test_config();
+        config.fees.deposit_fee_wad = U256::from(10_000_000_000_000_000u64); // 1%
+
+        // A larger deposit would pay 0.8% after 0.5%
+        config.fee_tiers = vec![(U256::from(10_000u64), 50), (U256::from(100_000u64), 80)];
+        assert_eq!(config.validate(), Err(LendingError::InvalidConfig));
+
+        // The first tier can't charge more than the base 1%
+        config.fee_tiers = vec![(U256::from(10_000u64), 150)];
+        assert_eq!(config.validate(), Err(LendingError::InvalidConfig));
+
+        config.fee_tiers = vec![(U256::from(10_000u64), 100), (U256::from(100_000u64), 50)];
+        assert_eq!(config.validate(), Ok(()));
     }
-    
-    pub fn accrue_interest(&mut self, cumulative_borrow_rate: Decimal) -> Result<(), LendingError> {
-        let compounded_interest = cumulative_borrow_rate.try_div(self.cumulative_borrow_rate_wads)?;
-        self.borrowed_amount_wads = self.borrowed_amount_wads.try_mul(compounded_interest)?;
-        self.cumulative_borrow_rate_wads = cumulative_borrow_rate;
-        Ok(())
+
+    #[test]
+    fn test_sweep_untracked_leaves_reserve_liquidity() {
+        let (env, mut lending) = setup();
+        let mut token = MockToken::deploy(&env, NoArgs);
+        let token_address = token.address();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), Some(token_address), 9, None);
+
+        // The reserve's liquidity plus a 250 donation
+        token.mint(lending.address(), U256::from(1_250u64));
+
+        let recipient = env.get_account(5);
+        env.set_caller(env.get_account(3));
+        assert_eq!(
+            lending.try_sweep_untracked(token_address, recipient),
+            Err(LendingError::InvalidMarketOwner.into())
+        );
+
+        env.set_caller(env.get_account(0));
+        assert_eq!(lending.sweep_untracked(token_address, recipient), U256::from(250u64));
+        assert_eq!(token.balance_of(&recipient), U256::from(250u64));
+        assert_eq!(token.balance_of(&lending.address()), U256::from(1_000u64));
+        assert_eq!(
+            lending.get_reserve(reserve_key).unwrap().liquidity.available_amount,
+            U256::from(1_000u64)
+        );
+
+        assert_eq!(
+            lending.try_sweep_untracked(token_address, recipient),
+            Err(LendingError::InvalidAmount.into())
+        );
     }
-}
 
-// ===========================================================================
-// PARAMETER STRUCTS
-// ===========================================================================
+    #[test]
+    fn test_flash_loan_accrues_interest() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        let before = lending.get_reserve(reserve_key).unwrap();
+
+        env.advance_block_time(1_000_000);
+        lending.flash_loan(reserve_key, U256::from(1_000u64));
+
+        let after = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(after.last_accrual_slot, env.block_time());
+        assert!(after.liquidity.cumulative_borrow_rate_wads > before.liquidity.cumulative_borrow_rate_wads);
+        assert!(after.liquidity.borrowed_amount_wads > before.liquidity.borrowed_amount_wads);
+        assert_eq!(after.liquidity.available_amount, before.liquidity.available_amount);
+    }
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct InitReserveParams {
-    pub current_slot: u64,
-    pub lending_market: Address,
-    pub liquidity: ReserveLiquidity,
-    pub collateral: ReserveCollateral,
-    pub config: ReserveConfig,
-}
+    #[test]
+    fn test_estimate_liquidation_reward_matches_liquidation() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_unhealthy_position(&env, &mut lending, borrower);
+
+        let (repaid, seized) = lending
+            .estimate_liquidation_reward(borrower, reserve_key, reserve_key, U256::from(500u64))
+            .unwrap();
+        assert!(seized > repaid);
+        let before = lending.get_obligation(borrower).unwrap();
+
+        env.set_caller(env.get_account(6));
+        lending.liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64));
+
+        let after = lending.get_obligation(borrower).unwrap();
+        assert_eq!(before.deposits[0].deposited_amount - after.deposits[0].deposited_amount, seized);
+        assert_eq!(
+            before.borrows[0].borrowed_amount_wads.try_sub(after.borrows[0].borrowed_amount_wads).unwrap(),
+            Decimal::try_from_u256(repaid).unwrap()
+        );
+    }
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct InitObligationParams {
-    pub current_slot: u64,
-    pub lending_market: Address,
-    pub owner: Address,
-    pub deposits: Vec<Collateral>,
-    pub borrows: Vec<Liquidity>,
-}
+    #[test]
+    fn test_liquidation_bonus_within_safety_margin() {
+        // 80% threshold less 50% LTV leaves a 30% margin
+        let mut config = test_config();
+        config.liquidation_bonus = 30;
+        assert_eq!(config.validate(), Ok(()));
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct NewReserveLiquidityParams {
-    pub mint_pubkey: Address,
-    pub mint_decimals: u8,
-    pub supply_pubkey: Address,
-    pub fee_receiver: Address,
-    pub oracle_pubkey: Address,
-    pub market_price: Decimal,
-    pub available_amount: U256,
-    pub borrowed_amount_wads: Decimal,
-    pub cumulative_borrow_rate_wads: Decimal,
-}
+        config.liquidation_bonus = 31;
+        assert_eq!(config.validate(), Err(LendingError::InvalidConfig));
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct NewReserveCollateralParams {
-    pub mint_pubkey: Address,
-    pub supply_pubkey: Address,
-    pub mint_total_supply: U256,
-}
+        config.liquidation_bonus = 10;
+        config.dynamic_liquidation_bonus = true;
+        config.max_liquidation_bonus = 40;
+        assert_eq!(config.validate(), Err(LendingError::InvalidConfig));
+    }
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct ReserveConfig {
-    pub loan_to_value_ratio: u8,
-    pub liquidation_threshold: u8,
-    pub liquidation_bonus: u8,
-    pub fees: ReserveFees,
-}
+    /// Liquidates a position whose collateral fell to a tenth of its price,
+    /// leaving 1_040 of bad debt. Returns (borrower, collateral, borrow) keys.
+    fn open_bad_debt_position(env: &HostEnv, lending: &mut NovaLendingHostRef) -> (Address, Address, Address) {
+        let mock_price = Decimal::from(1_000_000_000u64);
+        let keys = test_bootstrap(env, lending, vec![
+            (test_config(), U256::from(1_000_000u64), mock_price),
+            (test_config(), U256::from(1_000_000u64), mock_price),
+        ]);
+        let (collateral_key, borrow_key) = (keys[0], keys[1]);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(collateral_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(borrow_key, U256::from(4_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        // Collateral drops to a tenth: 1_000 of value against 4_040 owed
+        env.set_caller(env.get_account(0));
+        lending.set_oracle_price(env.get_account(10), mock_price.raw() / U256::from(10u64));
+        lending.refresh_reserve(collateral_key);
+        lending.refresh_reserve(borrow_key);
+        lending.refresh_obligation(borrower);
+
+        env.set_caller(env.get_account(6));
+        lending.liquidate_obligation(borrower, borrow_key, collateral_key, U256::from(3_000u64));
+        (borrower, collateral_key, borrow_key)
+    }
 
-impl ReserveConfig {
-    pub fn validate(&self) -> Result<(), LendingError> {
-        if self.loan_to_value_ratio > 100 {
-            return Err(LendingError::InvalidConfig);
-        }
-        if self.liquidation_threshold > 100 {
-            return Err(LendingError::InvalidConfig);
-        }
-        if self.liquidation_bonus > 100 {
-            return Err(LendingError::InvalidConfig);
-        }
-        Ok(())
+    #[test]
+    fn test_proportional_liquidation_prices_repay_token() {
+        let (env, mut lending) = setup();
+        let mock_price = Decimal::from(1_000_000_000u64);
+        let keys = test_bootstrap(&env, &mut lending, vec![
+            (test_config(), U256::from(1_000_000u64), mock_price),
+            (test_config(), U256::from(1_000_000u64), Decimal::from(2_000_000_000u64)),
+        ]);
+        let (collateral_key, borrow_key) = (keys[0], keys[1]);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(collateral_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        // 2_000 tokens at 2 each owe 4_000 of value
+        lending.borrow_obligation_liquidity(borrow_key, U256::from(2_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        env.set_caller(env.get_account(0));
+        let mut risky_config = test_config();
+        risky_config.loan_to_value_ratio = 20;
+        risky_config.liquidation_threshold = 30;
+        lending.modify_reserve_config(collateral_key, risky_config);
+        lending.refresh_reserve(collateral_key);
+        lending.refresh_reserve(borrow_key);
+        lending.refresh_obligation(borrower);
+
+        // 1_000 of value over the 3_000 threshold is 500 repay tokens, and
+        // the 5% bonus seizes 1_050 of collateral value
+        env.set_caller(env.get_account(6));
+        lending.liquidate_obligation_proportional(borrower, borrow_key, U256::from(3_000u64));
+
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::from(1_500u64));
+        assert_eq!(obligation.deposits[0].deposited_amount, U256::from(8_950u64));
     }
-}
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct ReserveFees {
-    pub borrow_fee_wad: U256,
-    pub flash_loan_fee_wad: U256,
-    pub host_fee_percentage: u8,
-}
+    #[test]
+    fn test_proportional_liquidation_records_bad_debt() {
+        let (env, mut lending) = setup();
+        let mock_price = Decimal::from(1_000_000_000u64);
+        let keys = test_bootstrap(&env, &mut lending, vec![
+            (test_config(), U256::from(1_000_000u64), mock_price),
+            (test_config(), U256::from(1_000_000u64), mock_price),
+        ]);
+        let (collateral_key, borrow_key) = (keys[0], keys[1]);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(collateral_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(borrow_key, U256::from(4_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        env.set_caller(env.get_account(0));
+        lending.set_oracle_price(env.get_account(10), mock_price.raw() / U256::from(10u64));
+        lending.refresh_reserve(collateral_key);
+        lending.refresh_reserve(borrow_key);
+        lending.refresh_obligation(borrower);
+
+        env.set_caller(env.get_account(6));
+        lending.liquidate_obligation_proportional(borrower, borrow_key, U256::from(3_000u64));
+
+        assert_eq!(lending.get_obligation(borrower).unwrap().deposits[0].deposited_amount, U256::zero());
+        assert_eq!(lending.get_reserve_bad_debt(borrow_key), Decimal::from(1_040u64));
+    }
 
-impl ReserveFees {
-    pub fn calculate_flash_loan_fees(&self, amount: Decimal) -> Result<(Decimal, Decimal), LendingError> {
-        let fee = amount.try_mul(Decimal::from(self.flash_loan_fee_wad.as_u128()))?;
-        let host_fee = fee.try_mul(Decimal::from(self.host_fee_percentage as u64))?;
-        let origination_fee = fee.try_sub(host_fee)?;
-        Ok((origination_fee, host_fee))
+    #[test]
+    fn test_liquidation_records_bad_debt() {
+        let (env, mut lending) = setup();
+        let (borrower, collateral_key, borrow_key) = open_bad_debt_position(&env, &mut lending);
+
+        assert_eq!(lending.get_obligation(borrower).unwrap().deposits[0].deposited_amount, U256::zero());
+        assert_eq!(lending.get_reserve_bad_debt(borrow_key), Decimal::from(1_040u64));
+        assert_eq!(lending.get_reserve_bad_debt(collateral_key), Decimal::zero());
+        assert!(env.emitted_event(
+            &lending,
+            BadDebtRecorded {
+                reserve: borrow_key,
+                obligation_owner: borrower,
+                amount: Decimal::from(1_040u64),
+                slot: env.block_time(),
+            }
+        ));
     }
-}
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct ReserveLiquidity {
-    pub mint_pubkey: Address,
-    pub mint_decimals: u8,
-    pub supply_pubkey: Address,
-    pub fee_receiver: Address,
-    pub oracle_pubkey: Address,
-    pub market_price: Decimal,
-    pub available_amount: U256,
-    pub borrowed_amount_wads: Decimal,
-    pub cumulative_borrow_rate_wads: Decimal,
-}
+    #[test]
+    fn test_socialize_bad_debt_lowers_exchange_rate() {
+        let (env, mut lending) = setup();
+        let (borrower, _, borrow_key) = open_bad_debt_position(&env, &mut lending);
+        let before = lending.get_reserve(borrow_key).unwrap();
+
+        assert_eq!(
+            lending.try_socialize_bad_debt(borrow_key),
+            Err(LendingError::InvalidMarketOwner.into())
+        );
+
+        env.set_caller(env.get_account(0));
+        lending.socialize_bad_debt(borrow_key);
+
+        let expected = Decimal::from_fraction(
+            before.liquidity.total_supply() - U256::from(1_040u64),
+            before.collateral.mint_total_supply
+        ).unwrap();
+        assert_eq!(lending.get_exchange_rate(borrow_key), Some(expected));
+        assert!(expected < before.collateral_exchange_rate().unwrap());
+        assert_eq!(lending.get_reserve_bad_debt(borrow_key), Decimal::zero());
+        assert_eq!(lending.try_verify_reserve(borrow_key), Ok(()));
+
+        // The obligation no longer owes what was written off
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::zero());
+        lending.refresh_reserve(borrow_key);
+        lending.refresh_obligation(borrower);
+        env.set_caller(borrower);
+        assert_eq!(
+            lending.try_repay_obligation_liquidity(borrow_key, U256::from(100u64)),
+            Err(LendingError::ObligationLiquidityEmpty.into())
+        );
+        env.set_caller(env.get_account(0));
+        assert_eq!(
+            lending.try_socialize_bad_debt(borrow_key),
+            Err(LendingError::InvalidAmount.into())
+        );
+    }
 
-impl ReserveLiquidity {
-    pub fn new(params: NewReserveLiquidityParams) -> Self {
-        Self {
-            mint_pubkey: params.mint_pubkey,
-            mint_decimals: params.mint_decimals,
-            supply_pubkey: params.supply_pubkey,
-            fee_receiver: params.fee_receiver,
-            oracle_pubkey: params.oracle_pubkey,
-            market_price: params.market_price,
-            available_amount: params.available_amount,
-            borrowed_amount_wads: params.borrowed_amount_wads,
-            cumulative_borrow_rate_wads: params.cumulative_borrow_rate_wads,
-        }
+    #[test]
+    fn test_event_slot_is_emission_block_time() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000u64), test_config(), None, 9, None);
+
+        env.advance_block_time(5_000);
+        let slot = env.block_time();
+        let treasury = env.get_account(8);
+        lending.set_reserve_fee_receiver(reserve_key, treasury);
+
+        assert!(env.emitted_event(
+            &lending,
+            ReserveFeeReceiverUpdated {
+                reserve: reserve_key,
+                old_receiver: env.get_account(0),
+                new_receiver: treasury,
+                slot,
+            }
+        ));
     }
 
-    pub fn deposit(&mut self, amount: U256) -> Result<(), LendingError> {
-        self.available_amount = self.available_amount.try_add(amount)?;
-        Ok(())
+    #[test]
+    fn test_user_active_reserves() {
+        let (env, mut lending) = setup();
+        let first = lending.init_reserve(U256::from(100_000u64), test_config(), None, 9, None);
+        let second = lending.init_reserve(U256::from(100_000u64), test_config(), None, 9, None);
+        let borrower = env.get_account(3);
+        assert!(lending.user_active_reserves(borrower).is_empty());
+
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(first, U256::from(10_000u64));
+        lending.deposit_obligation_collateral(second, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(second, U256::from(1_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        assert_eq!(lending.user_active_reserves(borrower), vec![first, second]);
     }
 
-    pub fn withdraw(&mut self, amount: U256) -> Result<(), LendingError> {
-        if amount > self.available_amount {
-            return Err(LendingError::InsufficientLiquidity);
-        }
-        self.available_amount = self.available_amount.try_sub(amount)?;
-        Ok(())
+    #[test]
+    fn test_liquidation_tolerates_older_prices_than_borrow() {
+        let (env, mut lending) = setup();
+        assert_eq!(
+            lending.try_set_liquidation_stale_after_slots(DEFAULT_STALE_AFTER_SLOTS - 1),
+            Err(LendingError::InvalidConfig.into())
+        );
+        lending.set_liquidation_stale_after_slots(DEFAULT_STALE_AFTER_SLOTS * 2);
+
+        let borrower = env.get_account(3);
+        let reserve_key = open_unhealthy_position(&env, &mut lending, borrower);
+        env.advance_block_time(DEFAULT_STALE_AFTER_SLOTS);
+
+        env.set_caller(borrower);
+        assert_eq!(
+            lending.try_borrow_obligation_liquidity(reserve_key, U256::from(100u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero()),
+            Err(LendingError::ReserveStale.into())
+        );
+
+        env.set_caller(env.get_account(6));
+        assert!(lending.is_liquidatable(borrower));
+        lending.liquidate_obligation(borrower, reserve_key, reserve_key, U256::from(500u64));
     }
 
-    pub fn borrow(&mut self, amount: Decimal) -> Result<(), LendingError> {
-        let amount_u256: U256 = amount.try_floor_u64()?.into();
-        if amount_u256 > self.available_amount {
-            return Err(LendingError::InsufficientLiquidity);
-        }
-        self.available_amount = self.available_amount.try_sub(amount_u256)?;
-        self.borrowed_amount_wads = self.borrowed_amount_wads.try_add(amount)?;
-        Ok(())
+    #[test]
+    fn test_withdraw_uses_weighted_avg_ltv() {
+        let (env, mut lending) = setup();
+        let half = lending.init_reserve(U256::from(100_000u64), test_config(), None, 9, None);
+        let mut low_ltv_config = test_config();
+        low_ltv_config.loan_to_value_ratio = 20;
+        let fifth = lending.init_reserve(U256::from(100_000u64), low_ltv_config, None, 9, None);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(half, U256::from(10_000u64));
+        lending.deposit_obligation_collateral(fifth, U256::from(30_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(half, U256::from(5_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        lending.refresh_reserve(half);
+        lending.refresh_obligation(borrower);
+
+        // (10_000 * 50% + 30_000 * 20%) / 40_000
+        let obligation = lending.get_obligation(borrower).unwrap();
+        let ltv = obligation.weighted_avg_ltv(|key| lending.get_reserve(*key)).unwrap();
+        assert_eq!(ltv.to_decimal(), Decimal::from_fraction(U256::from(275u64), U256::from(1_000u64)).unwrap());
+
+        // 40_000 * 27.5% less the 5_050 owed; the half-LTV reserve's own 50%
+        // would have released all of it
+        lending.withdraw_obligation_collateral(half, U256::max_value());
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.deposits[0].deposited_amount, U256::from(4_050u64));
     }
 
-    pub fn repay(
-        &mut self,
-        repay_amount: U256,
-        settle_amount: Decimal
-    ) -> Result<(), LendingError> {
-        self.available_amount = self.available_amount.try_add(repay_amount)?;
-        self.borrowed_amount_wads = self.borrowed_amount_wads.try_sub(settle_amount)?;
-        Ok(())
+    #[test]
+    fn test_compound_reserve_fees() {
+        let mut config = test_config();
+        config.fees.deposit_fee_wad = U256::from(10_000_000_000_000_000u64); // 1%
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(100_000u64), config, None, 9, None);
+
+        env.set_caller(env.get_account(3));
+        lending.deposit_reserve_liquidity(reserve_key, TokenAmount::from(100_000u64));
+        let before = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(before.liquidity.accumulated_protocol_fees_wads, Decimal::from(1_000u64));
+        assert_eq!(
+            lending.try_compound_reserve_fees(reserve_key),
+            Err(LendingError::InvalidMarketOwner.into())
+        );
+
+        env.set_caller(env.get_account(0));
+        assert_eq!(lending.compound_reserve_fees(reserve_key), U256::from(1_000u64));
+
+        let after = lending.get_reserve(reserve_key).unwrap();
+        assert_eq!(after.liquidity.accumulated_protocol_fees_wads, Decimal::zero());
+        assert_eq!(after.liquidity.total_supply(), before.liquidity.total_supply() + U256::from(1_000u64));
+        assert_eq!(after.liquidity.available_amount, before.liquidity.available_amount);
+        assert_eq!(after.collateral_exchange_rate(), before.collateral_exchange_rate());
+        assert_eq!(lending.get_protocol_collateral(reserve_key), U256::from(1_000u64));
+
+        assert_eq!(
+            lending.try_compound_reserve_fees(reserve_key),
+            Err(LendingError::InvalidAmount.into())
+        );
     }
 
-    pub fn total_supply(&self) -> U256 {
-        self.available_amount
-            .try_add(
-                self.borrowed_amount_wads
-                    .try_floor_u64()
-                    .unwrap_or(0)
-                    .into()
-            )
-            .unwrap_or(self.available_amount)
+    #[test]
+    fn test_market_overview_matches_queries() {
+        let (env, mut lending) = setup();
+        open_position(&env, &mut lending, env.get_account(3), 0);
+        env.set_caller(env.get_account(0));
+        lending.init_reserve(U256::from(50_000u64), test_config(), None, 9, None);
+
+        let overview = lending.market_overview();
+        assert_eq!(overview.reserve_count, lending.get_reserve_count());
+        assert_eq!(overview.reserve_count, 2);
+        assert_eq!(overview.obligation_count, lending.get_obligation_count());
+        assert_eq!(overview.total_protocol_fees, lending.total_protocol_fees());
+
+        // At the mock price a token is worth one unit: the 1_000 borrow plus
+        // its 1% fee is owed, and borrowing doesn't change what suppliers hold
+        assert_eq!(overview.total_borrowed, Decimal::from(1_010u64));
+        assert_eq!(overview.total_value_locked, Decimal::from(1_050_000u64));
     }
-}
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct ReserveCollateral {
-    pub mint_pubkey: Address,
-    pub supply_pubkey: Address,
-    pub mint_total_supply: U256,
-}
+    #[test]
+    fn test_flash_loan_max_utilization() {
+        let mut config = test_config();
+        config.flash_loan_max_utilization = 90;
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(10_000u64), config, None, 9, None);
+
+        env.set_caller(env.get_account(3));
+        assert_eq!(
+            lending.try_flash_loan(reserve_key, U256::max_value()),
+            Err(LendingError::NotEnoughLiquidityAfterFlashLoan.into())
+        );
+        assert_eq!(
+            lending.try_flash_loan(reserve_key, U256::from(9_001u64)),
+            Err(LendingError::NotEnoughLiquidityAfterFlashLoan.into())
+        );
+        lending.flash_loan(reserve_key, U256::from(9_000u64));
+    }
 
-impl ReserveCollateral {
-    pub fn new(params: NewReserveCollateralParams) -> Self {
-        Self {
-            mint_pubkey: params.mint_pubkey,
-            supply_pubkey: params.supply_pubkey,
-            mint_total_supply: params.mint_total_supply,
-        }
+    #[test]
+    fn test_withdraw_zero_priced_collateral() {
+        let (env, mut lending) = setup();
+        let mock_price = Decimal::from(1_000_000_000u64);
+        let keys = test_bootstrap(&env, &mut lending, vec![
+            (test_config(), U256::from(100_000u64), mock_price),
+            (test_config(), U256::from(100_000u64), mock_price),
+        ]);
+        let (collateral_key, borrow_key) = (keys[0], keys[1]);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(collateral_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(borrow_key, U256::from(1_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        // A raw price of 1 at 36 decimals normalizes to zero
+        env.set_caller(env.get_account(0));
+        lending.set_reserve_oracle(collateral_key, env.get_account(10), MAX_PRICE_DECIMALS);
+        lending.set_oracle_price(env.get_account(10), U256::one());
+        lending.refresh_reserve(collateral_key);
+        lending.refresh_reserve(borrow_key);
+        lending.refresh_obligation(borrower);
+        assert_eq!(lending.get_obligation(borrower).unwrap().deposited_value, Decimal::zero());
+
+        env.set_caller(borrower);
+        assert_eq!(
+            lending.try_withdraw_obligation_collateral(collateral_key, U256::from(100u64)),
+            Err(LendingError::CollateralValueZero.into())
+        );
     }
 
-    pub fn mint(&mut self, amount: U256) -> Result<(), LendingError> {
-        self.mint_total_supply = self.mint_total_supply.try_add(amount)?;
-        Ok(())
+    #[test]
+    fn test_repay_obligation_liquidity_pct() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        lending.refresh_obligation(borrower);
+
+        assert_eq!(
+            lending.try_repay_obligation_liquidity_pct(reserve_key, 101),
+            Err(LendingError::InvalidAmount.into())
+        );
+
+        lending.repay_obligation_liquidity_pct(reserve_key, 50);
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::from(500u64));
+
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+        lending.repay_obligation_liquidity_pct(reserve_key, 100);
+        let obligation = lending.get_obligation(borrower).unwrap();
+        assert_eq!(obligation.borrows[0].borrowed_amount_wads, Decimal::zero());
     }
 
-    pub fn burn(&mut self, amount: U256) -> Result<(), LendingError> {
-        if amount > self.mint_total_supply {
-            return Err(LendingError::InsufficientCollateral);
-        }
-        self.mint_total_supply = self.mint_total_supply.try_sub(amount)?;
-        Ok(())
+    #[test]
+    fn test_linear_accrual_tracks_compounding_for_short_gaps() {
+        let env = odra_test::env();
+        let mut exact = test_liquidity(&env, U256::from(1_000_000u64));
+        exact.borrow(Decimal::from(500_000u64)).unwrap();
+        let mut linear = exact.clone();
+
+        // An hour of slots at 30% a year
+        let gap = 3_600_000;
+        exact.compound_interest(Rate::from_percent(30), gap, 0, 0).unwrap();
+        linear.compound_interest(Rate::from_percent(30), gap, 0, gap).unwrap();
+
+        assert!(linear.cumulative_borrow_rate_wads > Decimal::one());
+        assert!(linear.cumulative_borrow_rate_wads <= exact.cumulative_borrow_rate_wads);
+        // Simple interest trails compounding by under a billionth here
+        let error = exact.cumulative_borrow_rate_wads
+            .try_sub(linear.cumulative_borrow_rate_wads)
+            .unwrap();
+        assert!(error < Decimal::from_fraction(U256::one(), U256::from(1_000_000_000u64)).unwrap());
+
+        // Past the crossover the exact path is used
+        let mut beyond = test_liquidity(&env, U256::from(1_000_000u64));
+        beyond.borrow(Decimal::from(500_000u64)).unwrap();
+        beyond.compound_interest(Rate::from_percent(30), gap, 0, gap - 1).unwrap();
+        assert_eq!(beyond.cumulative_borrow_rate_wads, exact.cumulative_borrow_rate_wads);
     }
-}
 
-// ===========================================================================
-// RESULT STRUCTS
-// ===========================================================================
+    #[test]
+    fn test_pending_interest_matches_refresh() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        assert_eq!(lending.pending_interest(borrower, reserve_key), Decimal::zero());
+
+        env.advance_block_time(MS_PER_YEAR / 12);
+        let pending = lending.pending_interest(borrower, reserve_key);
+        assert!(pending > Decimal::zero());
+        // Querying leaves the stored debt alone
+        let before = lending.get_obligation(borrower).unwrap().borrows[0].borrowed_amount_wads;
+
+        lending.refresh_reserve(reserve_key);
+        lending.refresh_obligation(borrower);
+        let after = lending.get_obligation(borrower).unwrap().borrows[0].borrowed_amount_wads;
+        assert_eq!(after.try_sub(before).unwrap(), pending);
+        assert_eq!(lending.pending_interest(borrower, reserve_key), Decimal::zero());
+    }
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct CalculateBorrowResult {
-    pub borrow_amount: Decimal,
-    pub receive_amount: U256,
-    pub borrow_fee: U256,
-    pub host_fee: U256,
-}
+    #[test]
+    fn test_keeper_can_recompute_but_not_change_owner() {
+        let (env, mut lending) = setup();
+        let borrower = env.get_account(3);
+        let reserve_key = open_position(&env, &mut lending, borrower, 0);
+        let keeper = env.get_account(6);
+
+        env.set_caller(env.get_account(0));
+        lending.grant_role(keeper, ROLE_KEEPER);
+        assert!(lending.has_role(keeper, ROLE_KEEPER));
+        assert!(!lending.has_role(keeper, ROLE_PAUSER));
+
+        env.set_caller(keeper);
+        lending.refresh_reserve(reserve_key);
+        lending.recompute_obligation(borrower);
+        assert_eq!(
+            lending.try_set_lending_market_owner(keeper),
+            Err(LendingError::InvalidMarketOwner.into())
+        );
+
+        env.set_caller(env.get_account(0));
+        lending.revoke_role(keeper, ROLE_KEEPER);
+        env.set_caller(keeper);
+        assert_eq!(
+            lending.try_recompute_obligation(borrower),
+            Err(LendingError::InvalidMarketOwner.into())
+        );
+    }
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct CalculateRepayResult {
-    pub settle_amount: Decimal,
-    pub repay_amount: U256,
-}
+    #[test]
+    fn test_pauser_can_pause_but_not_modify_config() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(1_000_000u64), test_config(), None, 9, None);
+        let pauser = env.get_account(6);
+        lending.grant_role(pauser, ROLE_PAUSER);
+
+        env.set_caller(pauser);
+        lending.set_paused(true);
+        assert!(lending.is_paused());
+        assert_eq!(
+            lending.try_modify_reserve_config(reserve_key, test_config()),
+            Err(LendingError::InvalidMarketOwner.into())
+        );
+        assert_eq!(
+            lending.try_grant_role(pauser, ROLE_RISK_ADMIN),
+            Err(LendingError::InvalidMarketOwner.into())
+        );
+
+        env.set_caller(env.get_account(0));
+        assert_eq!(
+            lending.try_grant_role(pauser, 1 << 3),
+            Err(LendingError::InvalidConfig.into())
+        );
+        lending.grant_role(pauser, ROLE_RISK_ADMIN);
+        env.set_caller(pauser);
+        lending.modify_reserve_config(reserve_key, test_config());
+    }
 
-#[derive(OdraSchema, Debug, Clone, ToBytes, FromBytes, CLTyped)]
-pub struct CalculateLiquidationResult {
-    pub settle_amount: Decimal,
-    pub repay_amount: U256,
-    pub withdraw_amount: U256,
-}
\ No newline at end of file
+    #[test]
+    fn test_borrow_rejected_past_oracle_exposure_cap() {
+        let (env, mut lending) = setup();
+        let mock_price = Decimal::from(1_000_000_000u64);
+        let keys = test_bootstrap(&env, &mut lending, vec![
+            (test_config(), U256::from(100_000u64), mock_price),
+            (test_config(), U256::from(100_000u64), mock_price),
+        ]);
+        let (collateral_key, borrow_key) = (keys[0], keys[1]);
+        let collateral_oracle = env.get_account(10);
+        assert_eq!(
+            lending.try_set_max_oracle_exposure(101),
+            Err(LendingError::InvalidConfig.into())
+        );
+        // Around 4_000 of the 200_000 supplied
+        lending.set_max_oracle_exposure(2);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(collateral_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(borrow_key, U256::from(3_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        lending.refresh_reserve(collateral_key);
+        lending.refresh_reserve(borrow_key);
+        lending.refresh_obligation(borrower);
+        assert_eq!(lending.oracle_exposure(collateral_oracle), Decimal::from(3_000u64));
+        assert_eq!(lending.oracle_exposure(env.get_account(11)), Decimal::zero());
+
+        assert_eq!(
+            lending.try_borrow_obligation_liquidity(borrow_key, U256::from(2_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero()),
+            Err(LendingError::OracleConcentrationLimit.into())
+        );
+        lending.borrow_obligation_liquidity(borrow_key, U256::from(500u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+        assert_eq!(lending.oracle_exposure(collateral_oracle), Decimal::from(3_500u64));
+
+        // Repaying frees up the oracle's headroom
+        lending.refresh_reserve(borrow_key);
+        lending.refresh_obligation(borrower);
+        lending.repay_obligation_liquidity(borrow_key, U256::from(1_000u64));
+        assert_eq!(lending.oracle_exposure(collateral_oracle), Decimal::from(2_500u64));
+    }
+
+    #[test]
+    fn test_redeem_pays_more_after_interest() {
+        let (env, mut lending) = setup();
+        let reserve_key = lending.init_reserve(U256::from(10_000u64), flat_rate_config(10), None, 9, None);
+
+        let supplier = env.get_account(4);
+        env.set_caller(supplier);
+        let minted = lending.deposit_reserve_liquidity(reserve_key, TokenAmount::from(1_000u64));
+        assert_eq!(minted, TokenAmount::from(1_000u64));
+        lending.refresh_reserve(reserve_key);
+
+        let borrower = env.get_account(3);
+        env.set_caller(borrower);
+        lending.init_obligation();
+        lending.deposit_obligation_collateral(reserve_key, U256::from(10_000u64));
+        lending.refresh_obligation(borrower);
+        lending.borrow_obligation_liquidity(reserve_key, U256::from(4_000u64), U256::zero(), 0, RateMode::Variable, None, Decimal::zero());
+
+        env.advance_block_time(MS_PER_YEAR);
+        lending.refresh_reserve(reserve_key);
+
+        env.set_caller(supplier);
+        let redeemed = lending.redeem_reserve_collateral(reserve_key, minted.0);
+        assert!(redeemed > U256::from(1_000u64));
+    }
+}
@@ -1,12 +1,17 @@
 use odra::prelude::*;
+use odra::casper_types::U256;
 
 use crate::error::LendingError;
+use crate::math::{common::WAD, Rate};
 
 /// Pyth Oracle implementation for Odra/Casper
 /// Simplified version for Casper ecosystem
 
 // Constants
 pub const STALE_PRICE_THRESHOLD_SLOTS: u64 = 5;
+/// Exponents supported by the price conversion; `10^19` no longer fits in u64
+pub const MIN_PRICE_EXPONENT: i32 = -18;
+pub const MAX_PRICE_EXPONENT: i32 = 18;
 
 #[odra::module]
 pub struct PythOracle {
@@ -21,6 +26,8 @@ pub struct PythOracle {
     product_attributes: Mapping<Address, Vec<(String, String)>>,
     
     approved_publishers: Mapping<Address, bool>,
+    publishers: List<Address>,
+    tokens: List<Address>,
     admin: Var<Address>,
     min_confidence_ratio: Var<u64>,
 }
@@ -53,6 +60,14 @@ impl PythOracle {
             self.env().revert(LendingError::InvalidOracleConfig);
         }
 
+        if !(MIN_PRICE_EXPONENT..=MAX_PRICE_EXPONENT).contains(&exponent) {
+            self.env().revert(LendingError::InvalidOracleConfig);
+        }
+
+        if self.price_values.get(&token_address).is_none() {
+            self.tokens.push(token_address);
+        }
+
         // Store price data in separate mappings
         self.price_values.set(&token_address, price);
         self.price_confidences.set(&token_address, confidence);
@@ -100,6 +115,11 @@ impl PythOracle {
             self.env().revert(LendingError::InvalidOracleConfig);
         }
 
+        // Removed publishers stay listed and are filtered on read, so only
+        // push addresses that were never added before
+        if self.approved_publishers.get(&publisher).is_none() {
+            self.publishers.push(publisher);
+        }
         self.approved_publishers.set(&publisher, true);
 
         self.env().emit_event(PublisherAdded {
@@ -185,6 +205,26 @@ impl PythOracle {
         Some((market_price, confidence_value))
     }
 
+    /// Confidence interval as a fraction of the price. Both share the feed's
+    /// exponent, so the raw values are compared directly. None for a missing
+    /// or non-positive price.
+    pub fn get_confidence_ratio(&self, token_address: Address) -> Option<Rate> {
+        let price = self.price_values.get(&token_address)?;
+        let confidence = self.price_confidences.get(&token_address)?;
+        if price <= 0 {
+            return None;
+        }
+
+        let scaled_confidence = U256::from(confidence).checked_mul(U256::from(WAD))?;
+        Some(Rate(scaled_confidence / U256::from(price.unsigned_abs())))
+    }
+
+    /// Slot the token's price was last published at, even once it is too
+    /// stale for `get_price`. None if no price was ever set.
+    pub fn get_last_update_slot(&self, token_address: Address) -> Option<u64> {
+        self.price_publish_slots.get(&token_address)
+    }
+
     /// Get product information
     pub fn get_product(&self, product_address: Address) -> Option<(Address, Vec<(String, String)>)> {
         let price_address = self.product_price_addresses.get(&product_address)?;
@@ -242,11 +282,23 @@ impl PythOracle {
         self.approved_publishers.get(&address).unwrap_or(false)
     }
 
-    /// Get all supported tokens
-    pub fn get_supported_tokens(&self) -> Vec<Address> {
-        // Note: In Odra, we need to maintain a list separately since Mapping doesn't support keys()
-        // For now, return empty vec - in practice you'd maintain a List of tokens
-        vec![]
+    /// Get all currently approved publishers
+    pub fn get_approved_publishers(&self) -> Vec<Address> {
+        self.publishers
+            .iter()
+            .filter(|publisher| self.is_approved_publisher(*publisher))
+            .collect()
+    }
+
+    /// Get tokens that have received a price, `limit` at a time starting at
+    /// `offset`, with the total number of tokens
+    pub fn get_supported_tokens(&self, offset: u32, limit: u32) -> (Vec<Address>, u32) {
+        let total = self.tokens.len();
+        let end = offset.saturating_add(limit).min(total);
+        let page = (offset..end)
+            .filter_map(|index| self.tokens.get(index))
+            .collect();
+        (page, total)
     }
 }
 
@@ -388,4 +440,101 @@ impl PriceFeedAggregator {
         // For now, return a mock price
         Some(100u64)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use odra::host::{Deployer, HostEnv};
+
+    fn setup() -> (HostEnv, PythOracleHostRef) {
+        let env = odra_test::env();
+        let oracle = PythOracle::deploy(&env, PythOracleInitArgs { admin: env.get_account(0) });
+        (env, oracle)
+    }
+
+    #[test]
+    fn test_get_approved_publishers() {
+        let (env, mut oracle) = setup();
+        let (a, b, c) = (env.get_account(1), env.get_account(2), env.get_account(3));
+        oracle.add_publisher(a);
+        oracle.add_publisher(b);
+        oracle.add_publisher(c);
+        oracle.remove_publisher(b);
+
+        assert_eq!(oracle.get_approved_publishers(), vec![a, c]);
+
+        // Re-approving does not list the publisher twice
+        oracle.add_publisher(b);
+        assert_eq!(oracle.get_approved_publishers(), vec![a, b, c]);
+    }
+
+    #[test]
+    fn test_update_price_accepts_boundary_exponents() {
+        let (env, mut oracle) = setup();
+        let token = env.get_account(4);
+
+        oracle.update_price(token, 1, 0, MAX_PRICE_EXPONENT, 1, 0);
+        assert_eq!(oracle.get_price(token, 0), Some(10u64.pow(18)));
+
+        oracle.update_price(token, 100, 1, MIN_PRICE_EXPONENT, 1, 0);
+        assert_eq!(oracle.get_price(token, 0), Some(0));
+    }
+
+    #[test]
+    fn test_update_price_rejects_out_of_range_exponents() {
+        let (env, mut oracle) = setup();
+        let token = env.get_account(4);
+
+        assert_eq!(
+            oracle.try_update_price(token, 100, 1, MAX_PRICE_EXPONENT + 1, 1, 0),
+            Err(LendingError::InvalidOracleConfig.into())
+        );
+        assert_eq!(
+            oracle.try_update_price(token, 100, 1, MIN_PRICE_EXPONENT - 1, 1, 0),
+            Err(LendingError::InvalidOracleConfig.into())
+        );
+    }
+
+    #[test]
+    fn test_get_supported_tokens_pagination() {
+        let (env, mut oracle) = setup();
+        let tokens = [env.get_account(4), env.get_account(5), env.get_account(6)];
+        for token in tokens.iter() {
+            oracle.update_price(*token, 1, 0, 0, 1, 0);
+        }
+        // Updating a known token does not list it twice
+        oracle.update_price(tokens[0], 2, 0, 0, 1, 1);
+
+        assert_eq!(oracle.get_supported_tokens(0, 2), (tokens[0..2].to_vec(), 3));
+        assert_eq!(oracle.get_supported_tokens(1, 1), (vec![tokens[1]], 3));
+        assert_eq!(oracle.get_supported_tokens(5, 2), (vec![], 3));
+    }
+
+    #[test]
+    fn test_get_confidence_ratio() {
+        let (env, mut oracle) = setup();
+        let token = env.get_account(4);
+        assert_eq!(oracle.get_confidence_ratio(token), None);
+
+        oracle.update_price(token, 1_000, 25, -2, 1, 0);
+        assert_eq!(oracle.get_confidence_ratio(token), Some(Rate::from_scaled_val(25_000_000_000_000_000)));
+
+        oracle.update_price(token, 0, 25, -2, 1, 1);
+        assert_eq!(oracle.get_confidence_ratio(token), None);
+    }
+
+    #[test]
+    fn test_get_last_update_slot() {
+        let (env, mut oracle) = setup();
+        let token = env.get_account(4);
+        assert_eq!(oracle.get_last_update_slot(token), None);
+
+        oracle.update_price(token, 100, 1, 0, 1, 42);
+        assert_eq!(oracle.get_last_update_slot(token), Some(42));
+
+        // Still reported after the price itself goes stale
+        assert_eq!(oracle.get_price(token, 42 + STALE_PRICE_THRESHOLD_SLOTS), None);
+        assert_eq!(oracle.get_last_update_slot(token), Some(42));
+    }
+}
@@ -49,8 +49,8 @@ pub struct InitLendingMarketParams {
 // - This file migrates the Solana `LendingMarket` state into an Odra module backed
 //   by Var<T> storage primitives. Pubkeys are represented as fixed-size arrays [u8;32].
 // - The old Pack/IsInitialized/Sealed traits and byte-level packing are removed;
-//   Odra manages on-chain storage by keys. If deterministic byte layout is required
-//   (for migrations), add explicit serialize/deserialize helpers.
+//   Odra manages on-chain storage by keys. A deterministic byte layout for
+//   migrating reserves and obligations lives in `state::serde`.
 // - If you need to interact with Solana-style `Pubkey` off-chain, convert between
 //   `[u8;32]` and `Pubkey` in your client tooling.
 // - Consider adding access control (e.g., only_owner) guards using `odr::access_control` macros
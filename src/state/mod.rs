@@ -4,6 +4,7 @@ pub mod last_update;
 pub mod lending_market;
 pub mod obligation;
 pub mod reserve;
+pub mod serde;
 
 pub use lending_market::*;
 pub use reserve::*;
@@ -29,8 +30,7 @@ pub const SLOTS_PER_YEAR: u64 = 31_536_000; // 365 * 24 * 60 * 60 — keep seman
 
 // Helper helpers for converting Decimal <-> scaled storage values are
 // implemented in `crate::math::Decimal` as `to_scaled_val()` / `from_scaled_val()`.
-// If you need byte-level packing for cross-chain migration, implement explicit
-// serialize/deserialize helpers here.
+// Byte-level packing for cross-chain migration lives in `serde`.
 
 #[cfg(test)]
 mod test {
@@ -90,7 +90,7 @@ impl Obligation {
         }
         
         // Konversi Rate ke Decimal secara eksplisit
-        let ltv_decimal: Decimal = withdraw_collateral_ltv.into();
+        let ltv_decimal = withdraw_collateral_ltv.to_decimal();
         
         // (Allowed - Borrowed) / LTV Collateral
         self.allowed_borrow_value
@@ -99,7 +99,11 @@ impl Obligation {
     }
 
     /// Menghitung sisa limit pinjaman
+    /// Nol jika pinjaman sudah melebihi batas (misalnya menjelang likuidasi)
     pub fn remaining_borrow_value(&self) -> Result<Decimal, LendingError> {
+        if self.borrowed_value >= self.allowed_borrow_value {
+            return Ok(Decimal::zero());
+        }
         self.allowed_borrow_value.try_sub(self.borrowed_value)
     }
 
@@ -221,6 +225,8 @@ pub struct ObligationLiquidity {
     pub cumulative_borrow_rate_wads: Decimal,
     pub borrowed_amount_wads: Decimal,
     pub market_value: Decimal,
+    /// Slot after which a fixed-term borrow can be liquidated; zero is perpetual
+    pub maturity_slot: u64,
 }
 
 impl ObligationLiquidity {
@@ -230,6 +236,7 @@ impl ObligationLiquidity {
             cumulative_borrow_rate_wads: Decimal::one(),
             borrowed_amount_wads: Decimal::zero(),
             market_value: Decimal::zero(),
+            maturity_slot: 0,
         }
     }
 
@@ -260,4 +267,22 @@ impl ObligationLiquidity {
         self.cumulative_borrow_rate_wads = cumulative_borrow_rate_wads;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_remaining_borrow_value_is_zero_when_over_allowed() {
+        let env = odra_test::env();
+        let mut obligation = Obligation::new(env.get_account(0), env.get_account(1), 0);
+        obligation.allowed_borrow_value = Decimal::from(100u64);
+        obligation.borrowed_value = Decimal::from(150u64);
+
+        assert_eq!(obligation.remaining_borrow_value(), Ok(Decimal::zero()));
+
+        obligation.borrowed_value = Decimal::from(40u64);
+        assert_eq!(obligation.remaining_borrow_value(), Ok(Decimal::from(60u64)));
+    }
+}
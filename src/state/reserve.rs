@@ -191,7 +191,7 @@ impl CollateralExchangeRate {
             
             // FIXED: Use direct conversion instead of TryFrom
             let rate_decimal = mint_total_supply.try_div(total_liquidity).unwrap_or(Decimal::zero());
-            Rate::from(rate_decimal) // Use From<Decimal> for Rate
+            rate_decimal.to_rate()
         };
         CollateralExchangeRate(rate)
     }
@@ -450,8 +450,7 @@ impl ReserveModule {
         }
         let borrowed = liq.borrowed_amount_wads();
         let utilization = borrowed.try_div(total_supply)?;
-        // FIXED: Use From<Decimal> for Rate instead of TryFrom
-        Ok(Rate::from(utilization))
+        Ok(utilization.to_rate())
     }
 
     fn compound_interest_storage(
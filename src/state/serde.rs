@@ -0,0 +1,598 @@
+//! Fixed, versioned byte layout for exporting reserves and obligations
+//! between deployments, independent of Odra's storage encoding.
+//!
+//! Integers are big-endian, `U256`/`Decimal`/`Rate` take 32 bytes, and an
+//! address is a kind byte (0 account, 1 contract) followed by its 32-byte
+//! hash. Optional values always take their full width behind a presence byte
+//! so reserves pack to a fixed length.
+
+use odra::prelude::*;
+use odra::casper_types::{account::AccountHash, ContractPackageHash, U256};
+
+use crate::error::LendingError;
+use crate::math::{Decimal, Rate};
+use crate::processor::{
+    MAX_FEE_TIERS, Collateral, LastUpdate, Liquidity, Obligation, RateMode, Reserve, ReserveCollateral,
+    ReserveConfig, ReserveFees, ReserveLiquidity,
+};
+
+/// Version byte leading every packed record
+pub const SERDE_VERSION: u8 = 6;
+
+const ADDRESS_LEN: usize = 33;
+const U256_LEN: usize = 32;
+const LAST_UPDATE_LEN: usize = 8 + 1;
+
+const RESERVE_LIQUIDITY_LEN: usize =
+    4 * ADDRESS_LEN + 2 + 7 * U256_LEN + 1 + ADDRESS_LEN;
+const RESERVE_COLLATERAL_LEN: usize = 2 * ADDRESS_LEN + U256_LEN;
+const RESERVE_FEES_LEN: usize = 4 * U256_LEN + 1;
+const FEE_TIERS_LEN: usize = 1 + MAX_FEE_TIERS * (U256_LEN + 1);
+const RESERVE_CONFIG_LEN: usize = 8 + 6 + 8 + U256_LEN + FEE_TIERS_LEN + RESERVE_FEES_LEN;
+
+/// Packed length of a reserve
+pub const RESERVE_LEN: usize = 1
+    + ADDRESS_LEN
+    + RESERVE_LIQUIDITY_LEN
+    + RESERVE_COLLATERAL_LEN
+    + RESERVE_CONFIG_LEN
+    + LAST_UPDATE_LEN
+    + 8;
+
+const COLLATERAL_LEN: usize = ADDRESS_LEN + 2 * U256_LEN;
+const LIQUIDITY_LEN: usize = ADDRESS_LEN + 4 * U256_LEN + 2 * 8 + 1;
+/// Packed obligation length before its deposits and borrows
+const OBLIGATION_HEADER_LEN: usize = 1 + 1 + 2 * ADDRESS_LEN + 4 * U256_LEN + LAST_UPDATE_LEN + 1 + 8 + 8 + 2;
+
+/// Packed length of an obligation with the given number of positions
+pub fn obligation_len(deposits: usize, borrows: usize) -> usize {
+    OBLIGATION_HEADER_LEN + deposits * COLLATERAL_LEN + borrows * LIQUIDITY_LEN
+}
+
+pub fn pack_reserve(reserve: &Reserve) -> Vec<u8> {
+    let mut writer = Writer(Vec::with_capacity(RESERVE_LEN));
+    writer.u8(SERDE_VERSION);
+    writer.address(&reserve.lending_market);
+
+    let liquidity = &reserve.liquidity;
+    writer.address(&liquidity.mint_pubkey);
+    writer.u8(liquidity.mint_decimals);
+    writer.address(&liquidity.supply_pubkey);
+    writer.address(&liquidity.fee_receiver);
+    writer.address(&liquidity.oracle_pubkey);
+    writer.u8(liquidity.price_decimals);
+    writer.decimal(liquidity.market_price);
+    writer.u256(liquidity.available_amount);
+    writer.decimal(liquidity.borrowed_amount_wads);
+    writer.decimal(liquidity.cumulative_borrow_rate_wads);
+    writer.decimal(liquidity.accumulated_protocol_fees_wads);
+    writer.decimal(liquidity.stable_borrowed_amount_wads);
+    writer.u256(liquidity.average_stable_borrow_rate.raw());
+    writer.option_address(&liquidity.underlying);
+
+    let collateral = &reserve.collateral;
+    writer.address(&collateral.mint_pubkey);
+    writer.address(&collateral.supply_pubkey);
+    writer.u256(collateral.mint_total_supply);
+
+    let config = &reserve.config;
+    writer.u8(config.optimal_utilization_rate);
+    writer.u8(config.loan_to_value_ratio);
+    writer.u8(config.liquidation_threshold);
+    writer.u8(config.liquidation_bonus);
+    writer.u8(config.min_borrow_rate);
+    writer.u8(config.optimal_borrow_rate);
+    writer.u8(config.max_borrow_rate);
+    writer.u8(config.reserve_factor);
+    writer.u64(config.linear_accrual_max_slots);
+    writer.bool(config.dynamic_liquidation_bonus);
+    writer.u8(config.max_liquidation_bonus);
+    writer.bool(config.borrow_enabled);
+    writer.u8(config.collateral_weight_cap);
+    writer.bool(config.flash_loan_enabled);
+    writer.u8(config.flash_loan_max_utilization);
+    writer.u256(config.max_borrow_per_tx);
+    writer.fee_tiers(&config.fee_tiers);
+    writer.u256(config.fees.borrow_fee_wad);
+    writer.u256(config.fees.flash_loan_fee_wad);
+    writer.u8(config.fees.host_fee_percentage);
+    writer.u256(config.fees.deposit_fee_wad);
+    writer.u256(config.fees.withdraw_fee_wad);
+
+    writer.last_update(&reserve.last_update);
+    writer.u64(reserve.last_accrual_slot);
+    writer.0
+}
+
+pub fn unpack_reserve(bytes: &[u8]) -> Result<Reserve, LendingError> {
+    if bytes.len() != RESERVE_LEN {
+        return Err(LendingError::InstructionUnpackError);
+    }
+    let mut reader = Reader::new(bytes)?;
+
+    let lending_market = reader.address()?;
+    let liquidity = ReserveLiquidity {
+        mint_pubkey: reader.address()?,
+        mint_decimals: reader.u8()?,
+        supply_pubkey: reader.address()?,
+        fee_receiver: reader.address()?,
+        oracle_pubkey: reader.address()?,
+        price_decimals: reader.u8()?,
+        market_price: reader.decimal()?,
+        available_amount: reader.u256()?,
+        borrowed_amount_wads: reader.decimal()?,
+        cumulative_borrow_rate_wads: reader.decimal()?,
+        accumulated_protocol_fees_wads: reader.decimal()?,
+        stable_borrowed_amount_wads: reader.decimal()?,
+        average_stable_borrow_rate: Rate(reader.u256()?),
+        underlying: reader.option_address()?,
+    };
+    let collateral = ReserveCollateral {
+        mint_pubkey: reader.address()?,
+        supply_pubkey: reader.address()?,
+        mint_total_supply: reader.u256()?,
+    };
+    let config = ReserveConfig {
+        optimal_utilization_rate: reader.u8()?,
+        loan_to_value_ratio: reader.u8()?,
+        liquidation_threshold: reader.u8()?,
+        liquidation_bonus: reader.u8()?,
+        min_borrow_rate: reader.u8()?,
+        optimal_borrow_rate: reader.u8()?,
+        max_borrow_rate: reader.u8()?,
+        reserve_factor: reader.u8()?,
+        linear_accrual_max_slots: reader.u64()?,
+        dynamic_liquidation_bonus: reader.bool()?,
+        max_liquidation_bonus: reader.u8()?,
+        borrow_enabled: reader.bool()?,
+        collateral_weight_cap: reader.u8()?,
+        flash_loan_enabled: reader.bool()?,
+        flash_loan_max_utilization: reader.u8()?,
+        max_borrow_per_tx: reader.u256()?,
+        fee_tiers: reader.fee_tiers()?,
+        fees: ReserveFees {
+            borrow_fee_wad: reader.u256()?,
+            flash_loan_fee_wad: reader.u256()?,
+            host_fee_percentage: reader.u8()?,
+            deposit_fee_wad: reader.u256()?,
+            withdraw_fee_wad: reader.u256()?,
+        },
+    };
+
+    let reserve = Reserve {
+        lending_market,
+        liquidity,
+        collateral,
+        config,
+        last_update: reader.last_update()?,
+        last_accrual_slot: reader.u64()?,
+    };
+    reader.finish()?;
+    Ok(reserve)
+}
+
+pub fn pack_obligation(obligation: &Obligation) -> Result<Vec<u8>, LendingError> {
+    let deposits = u8::try_from(obligation.deposits.len())
+        .map_err(|_| LendingError::ObligationReserveLimit)?;
+    let borrows = u8::try_from(obligation.borrows.len())
+        .map_err(|_| LendingError::ObligationReserveLimit)?;
+
+    let mut writer = Writer(Vec::with_capacity(obligation_len(deposits as usize, borrows as usize)));
+    writer.u8(SERDE_VERSION);
+    writer.u8(obligation.version);
+    writer.address(&obligation.lending_market);
+    writer.address(&obligation.owner);
+    writer.decimal(obligation.deposited_value);
+    writer.decimal(obligation.borrowed_value);
+    writer.decimal(obligation.allowed_borrow_value);
+    writer.decimal(obligation.unhealthy_borrow_value);
+    writer.last_update(&obligation.last_update);
+    writer.bool(obligation.first_unhealthy_slot.is_some());
+    writer.u64(obligation.first_unhealthy_slot.unwrap_or(0));
+    writer.u64(obligation.last_deposit_slot);
+
+    writer.u8(deposits);
+    writer.u8(borrows);
+    for collateral in obligation.deposits.iter() {
+        writer.address(&collateral.deposit_reserve);
+        writer.u256(collateral.deposited_amount);
+        writer.decimal(collateral.market_value);
+    }
+    for liquidity in obligation.borrows.iter() {
+        writer.address(&liquidity.borrow_reserve);
+        writer.decimal(liquidity.borrowed_amount_wads);
+        writer.decimal(liquidity.market_value);
+        writer.decimal(liquidity.cumulative_borrow_rate_wads);
+        writer.u64(liquidity.maturity_slot);
+        writer.u8(match liquidity.rate_mode {
+            RateMode::Variable => 0,
+            RateMode::Stable => 1,
+        });
+        writer.u256(liquidity.stable_borrow_rate.raw());
+        writer.u64(liquidity.last_accrual_slot);
+    }
+    Ok(writer.0)
+}
+
+pub fn unpack_obligation(bytes: &[u8]) -> Result<Obligation, LendingError> {
+    if bytes.len() < OBLIGATION_HEADER_LEN {
+        return Err(LendingError::InstructionUnpackError);
+    }
+    let mut reader = Reader::new(bytes)?;
+
+    let version = reader.u8()?;
+    let lending_market = reader.address()?;
+    let owner = reader.address()?;
+    let deposited_value = reader.decimal()?;
+    let borrowed_value = reader.decimal()?;
+    let allowed_borrow_value = reader.decimal()?;
+    let unhealthy_borrow_value = reader.decimal()?;
+    let last_update = reader.last_update()?;
+    let is_unhealthy = reader.bool()?;
+    let unhealthy_slot = reader.u64()?;
+    let last_deposit_slot = reader.u64()?;
+
+    let deposit_count = reader.u8()? as usize;
+    let borrow_count = reader.u8()? as usize;
+    if bytes.len() != obligation_len(deposit_count, borrow_count) {
+        return Err(LendingError::InstructionUnpackError);
+    }
+
+    let mut deposits = Vec::with_capacity(deposit_count);
+    for _ in 0..deposit_count {
+        deposits.push(Collateral {
+            deposit_reserve: reader.address()?,
+            deposited_amount: reader.u256()?,
+            market_value: reader.decimal()?,
+        });
+    }
+    let mut borrows = Vec::with_capacity(borrow_count);
+    for _ in 0..borrow_count {
+        borrows.push(Liquidity {
+            borrow_reserve: reader.address()?,
+            borrowed_amount_wads: reader.decimal()?,
+            market_value: reader.decimal()?,
+            cumulative_borrow_rate_wads: reader.decimal()?,
+            maturity_slot: reader.u64()?,
+            rate_mode: match reader.u8()? {
+                0 => RateMode::Variable,
+                1 => RateMode::Stable,
+                _ => return Err(LendingError::InstructionUnpackError),
+            },
+            stable_borrow_rate: Rate(reader.u256()?),
+            last_accrual_slot: reader.u64()?,
+        });
+    }
+    reader.finish()?;
+
+    Ok(Obligation {
+        lending_market,
+        owner,
+        deposits,
+        borrows,
+        deposited_value,
+        borrowed_value,
+        allowed_borrow_value,
+        unhealthy_borrow_value,
+        last_update,
+        first_unhealthy_slot: if is_unhealthy { Some(unhealthy_slot) } else { None },
+        last_deposit_slot,
+        version,
+    })
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.0.push(value as u8);
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u256(&mut self, value: U256) {
+        let mut bytes = [0u8; U256_LEN];
+        value.to_big_endian(&mut bytes);
+        self.0.extend_from_slice(&bytes);
+    }
+
+    fn decimal(&mut self, value: Decimal) {
+        self.u256(value.raw());
+    }
+
+    fn address(&mut self, address: &Address) {
+        match address {
+            Address::Account(hash) => {
+                self.u8(0);
+                self.0.extend_from_slice(&hash.value());
+            }
+            Address::Contract(hash) => {
+                self.u8(1);
+                self.0.extend_from_slice(&hash.value());
+            }
+        }
+    }
+
+    fn option_address(&mut self, address: &Option<Address>) {
+        match address {
+            Some(address) => {
+                self.bool(true);
+                self.address(address);
+            }
+            None => {
+                self.bool(false);
+                self.0.extend_from_slice(&[0u8; ADDRESS_LEN]);
+            }
+        }
+    }
+
+    fn last_update(&mut self, last_update: &LastUpdate) {
+        self.u64(last_update.slot);
+        self.bool(last_update.stale);
+    }
+
+    /// Count byte, then every tier slot, unused ones zeroed
+    fn fee_tiers(&mut self, tiers: &[(U256, u8)]) {
+        self.u8(tiers.len() as u8);
+        for slot in 0..MAX_FEE_TIERS {
+            let (min_amount, fee_bps) = tiers.get(slot).copied().unwrap_or_default();
+            self.u256(min_amount);
+            self.u8(fee_bps);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Starts after the version byte, rejecting unknown versions
+    fn new(bytes: &'a [u8]) -> Result<Self, LendingError> {
+        let mut reader = Self { bytes, offset: 0 };
+        if reader.u8()? != SERDE_VERSION {
+            return Err(LendingError::InstructionUnpackError);
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LendingError> {
+        let end = self.offset.checked_add(len).ok_or(LendingError::InstructionUnpackError)?;
+        let slice = self.bytes.get(self.offset..end).ok_or(LendingError::InstructionUnpackError)?;
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn finish(&self) -> Result<(), LendingError> {
+        if self.offset != self.bytes.len() {
+            return Err(LendingError::InstructionUnpackError);
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8, LendingError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, LendingError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(LendingError::InstructionUnpackError),
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64, LendingError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn u256(&mut self) -> Result<U256, LendingError> {
+        Ok(U256::from_big_endian(self.take(U256_LEN)?))
+    }
+
+    fn decimal(&mut self) -> Result<Decimal, LendingError> {
+        Ok(Decimal(self.u256()?))
+    }
+
+    fn hash(&mut self) -> Result<[u8; 32], LendingError> {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(self.take(32)?);
+        Ok(hash)
+    }
+
+    fn address(&mut self) -> Result<Address, LendingError> {
+        match self.u8()? {
+            0 => Ok(Address::Account(AccountHash::new(self.hash()?))),
+            1 => Ok(Address::Contract(ContractPackageHash::new(self.hash()?))),
+            _ => Err(LendingError::InstructionUnpackError),
+        }
+    }
+
+    fn option_address(&mut self) -> Result<Option<Address>, LendingError> {
+        if self.bool()? {
+            Ok(Some(self.address()?))
+        } else {
+            self.take(ADDRESS_LEN)?;
+            Ok(None)
+        }
+    }
+
+    fn last_update(&mut self) -> Result<LastUpdate, LendingError> {
+        Ok(LastUpdate {
+            slot: self.u64()?,
+            stale: self.bool()?,
+        })
+    }
+
+    fn fee_tiers(&mut self) -> Result<Vec<(U256, u8)>, LendingError> {
+        let count = self.u8()? as usize;
+        if count > MAX_FEE_TIERS {
+            return Err(LendingError::InstructionUnpackError);
+        }
+        let mut tiers = Vec::with_capacity(count);
+        for slot in 0..MAX_FEE_TIERS {
+            let tier = (self.u256()?, self.u8()?);
+            if slot < count {
+                tiers.push(tier);
+            }
+        }
+        Ok(tiers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::processor::OBLIGATION_VERSION;
+
+    fn account(byte: u8) -> Address {
+        Address::Account(AccountHash::new([byte; 32]))
+    }
+
+    fn contract(byte: u8) -> Address {
+        Address::Contract(ContractPackageHash::new([byte; 32]))
+    }
+
+    fn sample_reserve() -> Reserve {
+        Reserve {
+            lending_market: contract(1),
+            liquidity: ReserveLiquidity {
+                mint_pubkey: account(2),
+                mint_decimals: 9,
+                supply_pubkey: account(3),
+                fee_receiver: account(4),
+                oracle_pubkey: contract(5),
+                price_decimals: 8,
+                market_price: Decimal::from(1_000_000_000u64),
+                available_amount: U256::from(750_000u64),
+                borrowed_amount_wads: Decimal::from(250_000u64),
+                cumulative_borrow_rate_wads: Decimal::from_percent(105),
+                underlying: Some(account(6)),
+                accumulated_protocol_fees_wads: Decimal::from(12u64),
+                stable_borrowed_amount_wads: Decimal::from(50_000u64),
+                average_stable_borrow_rate: Rate::from_percent(7),
+            },
+            collateral: ReserveCollateral {
+                mint_pubkey: account(7),
+                supply_pubkey: account(8),
+                mint_total_supply: U256::from(900_000u64),
+            },
+            config: ReserveConfig {
+                optimal_utilization_rate: 80,
+                loan_to_value_ratio: 50,
+                liquidation_threshold: 80,
+                liquidation_bonus: 5,
+                min_borrow_rate: 1,
+                optimal_borrow_rate: 10,
+                max_borrow_rate: 30,
+                reserve_factor: 20,
+                linear_accrual_max_slots: 60_000,
+                dynamic_liquidation_bonus: true,
+                max_liquidation_bonus: 15,
+                borrow_enabled: true,
+                collateral_weight_cap: 60,
+                flash_loan_enabled: false,
+                flash_loan_max_utilization: 80,
+                max_borrow_per_tx: U256::from(10_000u64),
+                fee_tiers: vec![(U256::from(50_000u64), 5)],
+                fees: ReserveFees {
+                    borrow_fee_wad: U256::from(1u64),
+                    flash_loan_fee_wad: U256::from(2u64),
+                    host_fee_percentage: 10,
+                    deposit_fee_wad: U256::from(3u64),
+                    withdraw_fee_wad: U256::from(4u64),
+                },
+            },
+            last_update: LastUpdate { slot: 77, stale: true },
+            last_accrual_slot: 70,
+        }
+    }
+
+    fn sample_obligation() -> Obligation {
+        Obligation {
+            lending_market: contract(1),
+            owner: account(9),
+            deposits: vec![Collateral {
+                deposit_reserve: account(10),
+                deposited_amount: U256::from(10_000u64),
+                market_value: Decimal::from(10_000u64),
+            }],
+            borrows: vec![Liquidity {
+                borrow_reserve: account(11),
+                borrowed_amount_wads: Decimal::from(1_000u64),
+                market_value: Decimal::from(1_000u64),
+                cumulative_borrow_rate_wads: Decimal::one(),
+                maturity_slot: 500,
+                rate_mode: RateMode::Stable,
+                stable_borrow_rate: Rate::from_percent(7),
+                last_accrual_slot: 42,
+            }],
+            deposited_value: Decimal::from(10_000u64),
+            borrowed_value: Decimal::from(1_000u64),
+            allowed_borrow_value: Decimal::from(5_000u64),
+            unhealthy_borrow_value: Decimal::from(8_000u64),
+            last_update: LastUpdate { slot: 42, stale: false },
+            first_unhealthy_slot: Some(40),
+            last_deposit_slot: 30,
+            version: OBLIGATION_VERSION,
+        }
+    }
+
+    #[test]
+    fn test_reserve_round_trip() {
+        let reserve = sample_reserve();
+        let packed = pack_reserve(&reserve);
+        assert_eq!(packed.len(), RESERVE_LEN);
+        assert_eq!(packed[0], SERDE_VERSION);
+
+        let unpacked = unpack_reserve(&packed).unwrap();
+        assert_eq!(pack_reserve(&unpacked), packed);
+        assert_eq!(unpacked.liquidity.underlying, Some(account(6)));
+        assert_eq!(unpacked.config.max_borrow_per_tx, U256::from(10_000u64));
+        assert_eq!(unpacked.config.fee_tiers, vec![(U256::from(50_000u64), 5)]);
+        assert!(unpacked.last_update.stale);
+
+        let mut without_underlying = reserve;
+        without_underlying.liquidity.underlying = None;
+        let packed = pack_reserve(&without_underlying);
+        assert_eq!(packed.len(), RESERVE_LEN);
+        assert_eq!(unpack_reserve(&packed).unwrap().liquidity.underlying, None);
+    }
+
+    #[test]
+    fn test_obligation_round_trip() {
+        let obligation = sample_obligation();
+        let packed = pack_obligation(&obligation).unwrap();
+        assert_eq!(packed.len(), obligation_len(1, 1));
+
+        let unpacked = unpack_obligation(&packed).unwrap();
+        assert_eq!(pack_obligation(&unpacked).unwrap(), packed);
+        assert_eq!(unpacked.owner, account(9));
+        assert_eq!(unpacked.first_unhealthy_slot, Some(40));
+        assert_eq!(unpacked.borrows[0].rate_mode, RateMode::Stable);
+        assert_eq!(unpacked.borrows[0].stable_borrow_rate, Rate::from_percent(7));
+    }
+
+    #[test]
+    fn test_unpack_rejects_bad_length_and_version() {
+        let packed = pack_reserve(&sample_reserve());
+        assert!(unpack_reserve(&packed[..RESERVE_LEN - 1]).is_err());
+
+        let mut wrong_version = packed.clone();
+        wrong_version[0] = SERDE_VERSION + 1;
+        assert_eq!(unpack_reserve(&wrong_version).err(), Some(LendingError::InstructionUnpackError));
+
+        let mut packed = pack_obligation(&sample_obligation()).unwrap();
+        packed.push(0);
+        assert_eq!(unpack_obligation(&packed).err(), Some(LendingError::InstructionUnpackError));
+    }
+}